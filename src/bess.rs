@@ -0,0 +1,314 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    cpu::CPU,
+    memory::MemoryBus,
+    model::Model,
+    savestate::{StateError, StateReader},
+};
+
+// Best Effort Save State (BESS), the save state format shared by SameBoy
+// and other emulators: https://github.com/LIJI32/SameBoy/blob/master/BESS.md
+//
+// BESS data goes at the end of a state file, after the emulator's own
+// payload: the memory buffers that CORE points at, then a list of blocks
+// (a 4 byte tag, a u32 length and the contents) ending with END, then the
+// offset of the first block and "BESS". All numbers are little endian.
+const FOOTER_MAGIC: &[u8; 4] = b"BESS";
+const MAJOR_VERSION: u16 = 1;
+const MINOR_VERSION: u16 = 1;
+const CORE_SIZE: usize = 0xD0;
+
+// the part of the cartridge header INFO is made from
+pub const HEADER_START: u16 = 0x0134;
+pub const HEADER_SIZE: usize = 0x1C;
+const TITLE_SIZE: usize = 0x10;
+const GLOBAL_CHECKSUM_OFFSET: usize = 0x1A;
+
+pub fn has_footer(data: &[u8]) -> bool {
+    data.ends_with(FOOTER_MAGIC)
+}
+
+// family (G: DMG, S: SGB, C: CGB), model and revision. Revisions are left
+// blank, as gbars doesn't tell them apart.
+fn model_code(model: Model) -> &'static [u8; 4] {
+    match model {
+        Model::Dmg0 | Model::Dmg => b"GD  ",
+        Model::Mgb => b"GM  ",
+        Model::Sgb => b"SN  ",
+        Model::Sgb2 => b"S2  ",
+        Model::Cgb | Model::CgbDmgMode => b"CC  ",
+    }
+}
+
+// the CGB has 8 WRAM banks and 2 VRAM banks, in DMG mode too
+fn memory_sizes(model: Model) -> (usize, u8) {
+    if model.is_cgb() {
+        (0x8000, 2)
+    } else {
+        (0x2000, 1)
+    }
+}
+
+fn block(data: &mut Vec<u8>, tag: &[u8; 4], contents: &[u8]) {
+    data.extend_from_slice(tag);
+    data.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+    data.extend_from_slice(contents);
+}
+
+// Append the BESS buffers, blocks and footer describing the machine to
+// data, which holds the native state
+pub fn append(data: &mut Vec<u8>, cpu: &CPU, bus: &MemoryBus, model: Model, header: &[u8]) {
+    let (wram_size, vram_banks) = memory_sizes(model);
+
+    let mut vram = Vec::new();
+    for bank in 0..vram_banks {
+        for address in 0x8000..0x9800 {
+            vram.push(bus.ppu().read_vram(bank, address));
+        }
+        for address in 0x9800..0xA000 {
+            vram.push(bus.ppu().read_tile_map(bank, address));
+        }
+    }
+    let oam: Vec<u8> = (0xFE00..0xFEA0).map(|a| bus.ppu().read_oam(a)).collect();
+    let hram: Vec<u8> = (0xFF80..0xFFFF).map(|a| bus.peek(a)).collect();
+    let (bg_palettes, obj_palettes): (&[u8], &[u8]) = if model.is_cgb() {
+        (
+            bus.ppu().bg_palettes().data(),
+            bus.ppu().obj_palettes().data(),
+        )
+    } else {
+        (&[], &[])
+    };
+
+    // in the order CORE lists them
+    let buffers: [&[u8]; 7] = [
+        &bus.wram()[..wram_size],
+        &vram,
+        bus.cartridge().ram().unwrap_or_default(),
+        &oam,
+        &hram,
+        bg_palettes,
+        obj_palettes,
+    ];
+    let mut descriptors = Vec::new();
+    for buffer in buffers {
+        descriptors.push((buffer.len() as u32, data.len() as u32));
+        data.extend_from_slice(buffer);
+    }
+
+    let first_block = data.len() as u32;
+
+    block(
+        data,
+        b"NAME",
+        format!("gbars v{}", env!("CARGO_PKG_VERSION")).as_bytes(),
+    );
+
+    let mut info = header[..TITLE_SIZE].to_vec();
+    info.extend_from_slice(&header[GLOBAL_CHECKSUM_OFFSET..GLOBAL_CHECKSUM_OFFSET + 2]);
+    block(data, b"INFO", &info);
+
+    let registers = cpu.registers();
+    let mut core = Vec::with_capacity(CORE_SIZE);
+    core.extend_from_slice(&MAJOR_VERSION.to_le_bytes());
+    core.extend_from_slice(&MINOR_VERSION.to_le_bytes());
+    core.extend_from_slice(model_code(model));
+    for reg in [
+        cpu.pc(),
+        registers.get_af(),
+        registers.get_bc(),
+        registers.get_de(),
+        registers.get_hl(),
+        cpu.sp(),
+    ] {
+        core.extend_from_slice(&reg.to_le_bytes());
+    }
+    core.push(cpu.ime() as u8);
+    core.push(bus.peek(0xFFFF));
+    // execution state: running or halted (gbars has no stopped state)
+    core.push(cpu.is_halted() as u8);
+    core.push(0);
+    core.extend((0xFF00..0xFF80).map(|a| bus.peek(a)));
+    for (size, offset) in descriptors {
+        core.extend_from_slice(&size.to_le_bytes());
+        core.extend_from_slice(&offset.to_le_bytes());
+    }
+    debug_assert_eq!(core.len(), CORE_SIZE);
+    block(data, b"CORE", &core);
+
+    if model.is_cgb() {
+        let xoam: Vec<u8> = (0xFEA0..0xFF00).map(|a| bus.peek(a)).collect();
+        block(data, b"XOAM", &xoam);
+    }
+
+    let writes = bus.cartridge().mbc_register_writes();
+    if !writes.is_empty() {
+        let mut mbc = Vec::new();
+        for (address, value) in writes {
+            mbc.extend_from_slice(&address.to_le_bytes());
+            mbc.push(value);
+        }
+        block(data, b"MBC ", &mbc);
+    }
+
+    if let Some(rtc_registers) = bus.cartridge().rtc_registers() {
+        // the current and latched registers as u32s, then the time of
+        // saving. gbars only has the one set of registers.
+        let mut rtc = Vec::new();
+        for _ in 0..2 {
+            for reg in rtc_registers {
+                rtc.extend_from_slice(&(reg as u32).to_le_bytes());
+            }
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        rtc.extend_from_slice(&now.to_le_bytes());
+        block(data, b"RTC ", &rtc);
+    }
+
+    block(data, b"END ", &[]);
+
+    data.extend_from_slice(&first_block.to_le_bytes());
+    data.extend_from_slice(FOOTER_MAGIC);
+}
+
+fn copy_prefix(dest: &mut [u8], src: &[u8]) {
+    let len = dest.len().min(src.len());
+    dest[..len].copy_from_slice(&src[..len]);
+}
+
+// Load the BESS blocks at the end of data, from gbars or any other
+// emulator. Anything BESS doesn't cover (the position within the current
+// line, timer and DMA progress) starts afresh.
+pub fn load(
+    data: &[u8],
+    cpu: &mut CPU,
+    bus: &mut MemoryBus,
+    model: Model,
+    header: &[u8],
+) -> Result<(), StateError> {
+    if !has_footer(data) {
+        return Err(StateError::BadMagic);
+    }
+    let footer = data.len().checked_sub(8).ok_or(StateError::Truncated)?;
+    let first_block = StateReader::new(&data[footer..]).read_u32()? as usize;
+    let mut r = StateReader::new(data.get(first_block..footer).ok_or(StateError::Truncated)?);
+
+    let (mut core, mut xoam, mut mbc, mut rtc) = (None, None, None, None);
+    loop {
+        let tag: [u8; 4] = r.read_bytes(4)?.try_into().unwrap();
+        let len = r.read_u32()? as usize;
+        let contents = r.read_bytes(len)?;
+
+        match &tag {
+            b"END " => break,
+            b"INFO" => {
+                let checksum = &header[GLOBAL_CHECKSUM_OFFSET..GLOBAL_CHECKSUM_OFFSET + 2];
+                if contents.get(TITLE_SIZE..TITLE_SIZE + 2) != Some(checksum) {
+                    return Err(StateError::WrongRom);
+                }
+            }
+            b"CORE" => core = Some(contents),
+            b"XOAM" => xoam = Some(contents),
+            b"MBC " => mbc = Some(contents),
+            b"RTC " => rtc = Some(contents),
+            // NAME, and blocks for hardware gbars doesn't have
+            _ => {}
+        }
+    }
+
+    let mut core = StateReader::new(core.ok_or(StateError::MissingSection(*b"CORE"))?);
+    let major_version = core.read_u16()?;
+    if major_version != MAJOR_VERSION {
+        return Err(StateError::UnsupportedVersion(major_version as u32));
+    }
+    // minor versions only add to the end of blocks
+    core.read_u16()?;
+    if core.read_bytes(4)?[0] != model_code(model)[0] {
+        return Err(StateError::Invalid("the state is for a different model"));
+    }
+
+    let pc = core.read_u16()?;
+    let af = core.read_u16()?;
+    let bc = core.read_u16()?;
+    let de = core.read_u16()?;
+    let hl = core.read_u16()?;
+    let sp = core.read_u16()?;
+    let ime = core.read_bool()?;
+    let ie = core.read_u8()?;
+    let halted = core.read_u8()? == 1;
+    core.read_u8()?;
+    let io: [u8; 0x80] = core.read_bytes(0x80)?.try_into().unwrap();
+
+    let mut buffers: [&[u8]; 7] = [&[]; 7];
+    for buffer in buffers.iter_mut() {
+        let size = core.read_u32()? as usize;
+        let offset = core.read_u32()? as usize;
+        *buffer = data
+            .get(offset..offset + size)
+            .ok_or(StateError::Truncated)?;
+    }
+    let [wram, vram, cartridge_ram, oam, hram, bg_palettes, obj_palettes] = buffers;
+
+    // banking first, so RAM ends up where it belongs. Only mapper register
+    // writes are replayed, not writes to cartridge RAM.
+    if let Some(mbc) = mbc {
+        for write in mbc.chunks_exact(3) {
+            let address = u16::from_le_bytes([write[0], write[1]]);
+            if address < 0x8000 {
+                bus.cartridge_mut().write_byte(address, write[2]);
+            }
+        }
+    }
+    if let Some(rtc) = rtc.filter(|rtc| rtc.len() >= 20) {
+        bus.cartridge_mut()
+            .set_rtc_registers([rtc[0], rtc[4], rtc[8], rtc[12], rtc[16]]);
+    }
+    if let Some(ram) = bus.cartridge_mut().ram_mut() {
+        copy_prefix(ram, cartridge_ram);
+    }
+
+    let (wram_size, vram_banks) = memory_sizes(model);
+    copy_prefix(&mut bus.wram_mut()[..wram_size], wram);
+
+    let ppu = bus.ppu_mut();
+    for (i, &value) in vram.iter().take(vram_banks as usize * 0x2000).enumerate() {
+        let bank = (i / 0x2000) as u8;
+        let address = 0x8000 + (i % 0x2000) as u16;
+        if address < 0x9800 {
+            ppu.write_vram(bank, address, value);
+        } else {
+            ppu.write_tile_map(bank, address, value);
+        }
+    }
+    for (address, &value) in (0xFE00..0xFEA0).zip(oam) {
+        ppu.write_oam(address, value);
+    }
+    copy_prefix(ppu.bg_palettes_mut().data_mut(), bg_palettes);
+    copy_prefix(ppu.obj_palettes_mut().data_mut(), obj_palettes);
+
+    for (address, &value) in (0xFF80..0xFFFF).zip(hram) {
+        bus.poke(address, value);
+    }
+    if let Some(xoam) = xoam {
+        for (address, &value) in (0xFEA0..0xFF00).zip(xoam) {
+            bus.poke(address, value);
+        }
+    }
+    bus.poke(0xFFFF, ie);
+    bus.restore_io_registers(&io);
+
+    let registers = cpu.registers_mut();
+    registers.set_af(af);
+    registers.set_bc(bc);
+    registers.set_de(de);
+    registers.set_hl(hl);
+    cpu.set_pc(pc);
+    cpu.set_sp(sp);
+    cpu.set_ime(ime);
+    cpu.set_halted(halted);
+
+    Ok(())
+}
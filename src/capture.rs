@@ -0,0 +1,487 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::ppu::Rgb;
+
+// the Game Boy's frame rate, 4194304 Hz / 70224 dots, as a fraction
+const FPS_NUMERATOR: u64 = 262144;
+const FPS_DENOMINATOR: u64 = 4389;
+
+// LZW codes in GIFs are at most 12 bits
+const MAX_LZW_CODE: u16 = 4095;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureFormat {
+    // An animated GIF. GIF delays are in 1/100 s and most viewers slow
+    // down anything under 2/100 s, so every other frame is kept.
+    Gif,
+    // Raw 4:2:0 YUV video in a YUV4MPEG2 stream, with every frame. Audio
+    // goes into a WAV file next to it.
+    Y4m,
+}
+
+impl CaptureFormat {
+    // from a .gif or .y4m file name
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "gif" => Some(CaptureFormat::Gif),
+            "y4m" => Some(CaptureFormat::Y4m),
+            _ => None,
+        }
+    }
+}
+
+// Writes frames (and audio, for Y4M) as they're made. finish() has to be
+// called to complete the files.
+pub struct Capture {
+    format: CaptureFormat,
+    out: Box<dyn Write + Send>,
+    width: usize,
+    height: usize,
+    frames: u64,
+
+    // GIF frames are written once the next one is known, so unchanged
+    // frames can be merged into one longer frame
+    pending: Option<(Vec<Rgb>, u64)>,
+    // frames and hundredths of a second written to the GIF so far
+    gif_frames: u64,
+    gif_time: u64,
+
+    wav_path: Option<PathBuf>,
+    audio: Option<(u32, Vec<i16>)>,
+}
+
+impl Capture {
+    // Capture to a file, in the format its extension names
+    pub fn create(path: &Path, width: usize, height: usize) -> io::Result<Self> {
+        let format = CaptureFormat::from_path(path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "captures have to be .gif or .y4m files",
+            )
+        })?;
+        let out = BufWriter::new(File::create(path)?);
+
+        let mut capture = Self::new(format, Box::new(out), width, height)?;
+        if format == CaptureFormat::Y4m {
+            capture.wav_path = Some(path.with_extension("wav"));
+        }
+
+        Ok(capture)
+    }
+
+    pub fn new(
+        format: CaptureFormat,
+        mut out: Box<dyn Write + Send>,
+        width: usize,
+        height: usize,
+    ) -> io::Result<Self> {
+        match format {
+            CaptureFormat::Gif => {
+                out.write_all(b"GIF89a")?;
+                out.write_all(&(width as u16).to_le_bytes())?;
+                out.write_all(&(height as u16).to_le_bytes())?;
+                // no global colour table, each frame has its own
+                out.write_all(&[0, 0, 0])?;
+                // loop forever
+                out.write_all(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00")?;
+            }
+            CaptureFormat::Y4m => {
+                writeln!(
+                    out,
+                    "YUV4MPEG2 W{width} H{height} F{FPS_NUMERATOR}:{FPS_DENOMINATOR} Ip A1:1 C420jpeg"
+                )?;
+            }
+        }
+
+        Ok(Self {
+            format,
+            out,
+            width,
+            height,
+            frames: 0,
+            pending: None,
+            gif_frames: 0,
+            gif_time: 0,
+            wav_path: None,
+            audio: None,
+        })
+    }
+
+    pub fn format(&self) -> CaptureFormat {
+        self.format
+    }
+
+    // frames given to frame() so far
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn frame(&mut self, pixels: &[Rgb]) -> io::Result<()> {
+        assert_eq!(pixels.len(), self.width * self.height);
+        self.frames += 1;
+
+        match self.format {
+            CaptureFormat::Gif => {
+                if self.frames.is_multiple_of(2) {
+                    return Ok(());
+                }
+
+                match self.pending.as_mut() {
+                    Some((pending, count)) if pending.as_slice() == pixels => *count += 2,
+                    _ => {
+                        self.write_pending_gif_frame()?;
+                        self.pending = Some((pixels.to_vec(), 2));
+                    }
+                }
+                Ok(())
+            }
+            CaptureFormat::Y4m => {
+                self.out.write_all(b"FRAME\n")?;
+                self.out.write_all(&yuv420(pixels, self.width, self.height))
+            }
+        }
+    }
+
+    // Interleaved stereo samples, for the WAV file next to a Y4M capture.
+    // Ignored for GIFs.
+    pub fn audio(&mut self, sample_rate: u32, samples: &[i16]) {
+        if self.wav_path.is_some() {
+            self.audio
+                .get_or_insert_with(|| (sample_rate, Vec::new()))
+                .1
+                .extend_from_slice(samples);
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.format == CaptureFormat::Gif {
+            self.write_pending_gif_frame()?;
+            self.out.write_all(&[0x3B])?;
+        }
+        self.out.flush()?;
+
+        if let (Some(path), Some((sample_rate, samples))) = (&self.wav_path, &self.audio) {
+            std::fs::write(path, wav(*sample_rate, samples))?;
+        }
+
+        Ok(())
+    }
+
+    fn write_pending_gif_frame(&mut self) -> io::Result<()> {
+        let Some((pixels, count)) = self.pending.take() else {
+            return Ok(());
+        };
+
+        // round each frame's end time rather than its length, so the
+        // delays don't drift
+        self.gif_frames += count;
+        let end_time = self.gif_frames * FPS_DENOMINATOR * 100 / FPS_NUMERATOR;
+        let delay = end_time.saturating_sub(self.gif_time).max(2);
+        self.gif_time += delay;
+
+        let (palette, indices) = gif_palette(&pixels);
+        // the colour table holds 2^(n + 1) colours
+        let table_bits = (palette.len().max(2).next_power_of_two().trailing_zeros()) as u8;
+
+        // graphic control extension, for the delay
+        self.out.write_all(&[0x21, 0xF9, 4, 0])?;
+        self.out.write_all(&(delay as u16).to_le_bytes())?;
+        self.out.write_all(&[0, 0])?;
+
+        // image descriptor with a local colour table
+        self.out.write_all(&[0x2C, 0, 0, 0, 0])?;
+        self.out.write_all(&(self.width as u16).to_le_bytes())?;
+        self.out.write_all(&(self.height as u16).to_le_bytes())?;
+        self.out.write_all(&[0x80 | (table_bits - 1)])?;
+        for i in 0..1 << table_bits {
+            let colour = palette.get(i).copied().unwrap_or(0);
+            self.out.write_all(&colour.to_be_bytes()[1..])?;
+        }
+
+        let min_code_size = table_bits.max(2);
+        self.out.write_all(&[min_code_size])?;
+        for block in lzw_encode(&indices, min_code_size).chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0])?;
+
+        Ok(())
+    }
+}
+
+// BT.601 limited range YUV, with each 2x2 block sharing its averaged
+// chroma
+fn yuv420(pixels: &[Rgb], width: usize, height: usize) -> Vec<u8> {
+    let rgb = |pixel: Rgb| {
+        let [_, r, g, b] = pixel.to_be_bytes();
+        (r as i32, g as i32, b as i32)
+    };
+
+    let mut y_plane = Vec::with_capacity(width * height);
+    for &pixel in pixels {
+        let (r, g, b) = rgb(pixel);
+        y_plane.push((16 + ((66 * r + 129 * g + 25 * b + 128) >> 8)) as u8);
+    }
+
+    let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+    let mut u_plane = Vec::with_capacity(chroma_width * chroma_height);
+    let mut v_plane = Vec::with_capacity(chroma_width * chroma_height);
+    for cy in 0..chroma_height {
+        for cx in 0..chroma_width {
+            let (mut r, mut g, mut b, mut n) = (0, 0, 0, 0);
+            for y in cy * 2..(cy * 2 + 2).min(height) {
+                for x in cx * 2..(cx * 2 + 2).min(width) {
+                    let (pr, pg, pb) = rgb(pixels[y * width + x]);
+                    r += pr;
+                    g += pg;
+                    b += pb;
+                    n += 1;
+                }
+            }
+            let (r, g, b) = (r / n, g / n, b / n);
+
+            u_plane.push((128 + ((-38 * r - 74 * g + 112 * b + 128) >> 8)) as u8);
+            v_plane.push((128 + ((112 * r - 94 * g - 18 * b + 128) >> 8)) as u8);
+        }
+    }
+
+    y_plane.extend(u_plane);
+    y_plane.extend(v_plane);
+    y_plane
+}
+
+// The frame's colours and each pixel's index into them. Frames with more
+// than 256 colours (only possible with CGB palette tricks) fall back to a
+// fixed palette with 3 bits of red and green and 2 of blue.
+fn gif_palette(pixels: &[Rgb]) -> (Vec<Rgb>, Vec<u8>) {
+    let mut palette = Vec::new();
+    let mut lookup = HashMap::new();
+    let mut indices = Vec::with_capacity(pixels.len());
+
+    for &pixel in pixels {
+        let index = *lookup.entry(pixel).or_insert_with(|| {
+            palette.push(pixel);
+            palette.len() - 1
+        });
+        if index > 0xFF {
+            break;
+        }
+        indices.push(index as u8);
+    }
+
+    if palette.len() <= 0x100 {
+        return (palette, indices);
+    }
+
+    let palette = (0..=0xFF)
+        .map(|i: u32| {
+            let r = (i >> 5) * 0xFF / 7;
+            let g = (i >> 2 & 7) * 0xFF / 7;
+            let b = (i & 3) * 0xFF / 3;
+            r << 16 | g << 8 | b
+        })
+        .collect();
+    let indices = pixels
+        .iter()
+        .map(|&p| ((p >> 16 & 0xE0) | (p >> 11 & 0x1C) | (p >> 6 & 0x03)) as u8)
+        .collect();
+
+    (palette, indices)
+}
+
+// variable length codes, packed least significant bit first
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u8) {
+        self.bits |= (code as u32) << self.count;
+        self.count += size;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+// codes grow once the table has outgrown them
+fn emit(out: &mut BitWriter, code: u16, next_code: u16, code_size: &mut u8) {
+    out.write(code, *code_size);
+    if next_code >= 1 << *code_size && *code_size < 12 {
+        *code_size += 1;
+    }
+}
+
+// GIF's variant of LZW, following giflib on when codes grow and when the
+// table is cleared
+fn lzw_encode(indices: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+
+    let mut out = BitWriter {
+        out: Vec::new(),
+        bits: 0,
+        count: 0,
+    };
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next_code = end + 1;
+    let mut code_size = min_code_size + 1;
+
+    out.write(clear, code_size);
+
+    let Some((&first, rest)) = indices.split_first() else {
+        out.write(end, code_size);
+        return out.finish();
+    };
+    let mut current = first as u16;
+
+    for &index in rest {
+        if let Some(&code) = table.get(&(current, index)) {
+            current = code;
+            continue;
+        }
+
+        emit(&mut out, current, next_code, &mut code_size);
+        if next_code >= MAX_LZW_CODE {
+            emit(&mut out, clear, next_code, &mut code_size);
+            table.clear();
+            next_code = end + 1;
+            code_size = min_code_size + 1;
+        } else {
+            table.insert((current, index), next_code);
+            next_code += 1;
+        }
+        current = index as u16;
+    }
+
+    emit(&mut out, current, next_code, &mut code_size);
+    emit(&mut out, end, next_code, &mut code_size);
+
+    out.finish()
+}
+
+// 16 bit stereo PCM
+fn wav(sample_rate: u32, samples: &[i16]) -> Vec<u8> {
+    let data_len = samples.len() as u32 * 2;
+    let mut out = Vec::with_capacity(44 + data_len as usize);
+
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_len).to_le_bytes());
+    out.extend_from_slice(b"WAVEfmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, 2 channels
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(sample_rate * 4).to_le_bytes());
+    out.extend_from_slice(&4u16.to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        out.extend_from_slice(&sample.to_le_bytes());
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lzw_encode, yuv420};
+
+    // a plain GIF LZW decoder to check the encoder against
+    fn lzw_decode(data: &[u8], min_code_size: u8) -> Vec<u8> {
+        let clear = 1usize << min_code_size;
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut code_size = min_code_size + 1;
+        let mut previous: Option<usize> = None;
+        let mut out = Vec::new();
+
+        let (mut bits, mut count, mut bytes) = (0u32, 0u8, data.iter());
+        loop {
+            while count < code_size {
+                bits |= (*bytes.next().unwrap() as u32) << count;
+                count += 8;
+            }
+            let code = (bits & ((1 << code_size) - 1)) as usize;
+            bits >>= code_size;
+            count -= code_size;
+
+            if code == clear {
+                table = (0..clear).map(|i| vec![i as u8]).collect();
+                table.extend([vec![], vec![]]);
+                code_size = min_code_size + 1;
+                previous = None;
+                continue;
+            }
+            if code == clear + 1 {
+                return out;
+            }
+
+            let entry = match previous {
+                None => table[code].clone(),
+                Some(previous) => {
+                    let entry = if code < table.len() {
+                        table[code].clone()
+                    } else {
+                        let mut entry = table[previous].clone();
+                        entry.push(entry[0]);
+                        entry
+                    };
+                    if table.len() < 4096 {
+                        let mut new = table[previous].clone();
+                        new.push(entry[0]);
+                        table.push(new);
+                    }
+                    entry
+                }
+            };
+            out.extend_from_slice(&entry);
+            previous = Some(code);
+
+            if table.len() == 1 << code_size && code_size < 12 {
+                code_size += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_lzw_round_trip() {
+        // long enough to fill the table a few times
+        let mut seed = 1u32;
+        let data: Vec<u8> = (0..100_000)
+            .map(|i| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                if i % 1000 < 500 {
+                    (seed >> 16) as u8 & 0x0F
+                } else {
+                    (i / 7) as u8 & 0x03
+                }
+            })
+            .collect();
+
+        assert_eq!(lzw_decode(&lzw_encode(&data, 4), 4), data);
+        assert_eq!(lzw_decode(&lzw_encode(&[3], 2), 2), [3]);
+        assert!(lzw_decode(&lzw_encode(&[], 2), 2).is_empty());
+
+        let yuv = yuv420(&[0xFFFFFF, 0, 0, 0xFFFFFF], 2, 2);
+        assert_eq!(yuv, [235, 16, 16, 235, 128, 128]);
+    }
+}
@@ -9,6 +9,15 @@ impl BasicCartridge {
     pub fn new() -> Self {
         Self { data: [0; 0x8000] }
     }
+
+    // a 32KiB ROM with no mapper
+    pub fn from_rom(rom: &[u8]) -> Self {
+        let mut cartridge = Self::new();
+        let len = rom.len().min(cartridge.data.len());
+        cartridge.data[..len].copy_from_slice(&rom[..len]);
+
+        cartridge
+    }
 }
 
 impl Cartridge for BasicCartridge {
@@ -1,6 +1,7 @@
 use crate::{
     cartridge::{Cartridge, RAM_BANK_SIZE},
     memory::MemoryRegion,
+    savestate::{StateError, StateReader, StateWriter},
 };
 
 #[derive(Debug, Default)]
@@ -25,25 +26,96 @@ pub struct MBC1Cartridge {
     registers: MBC1Registers,
     rom_banks: usize,
     ram_banks: usize,
+    has_battery: bool,
 }
 
 impl MBC1Cartridge {
-    pub fn new(rom: Vec<u8>, ram_banks: usize, rom_banks: usize) -> Self {
+    pub fn new(rom: Vec<u8>, ram_banks: usize, rom_banks: usize, has_battery: bool) -> Self {
         Self {
             rom,
             ram: vec![0; ram_banks * RAM_BANK_SIZE],
             registers: Default::default(),
             rom_banks,
             ram_banks,
+            has_battery,
         }
     }
 
     pub fn new_and_empty() -> Self {
         todo!()
     }
+
+    // 8KB banks, picked by the secondary bank register in mode 1
+    fn ram_address(&self, address: u16) -> usize {
+        let mut ram_addr = address as usize & 0x1FFF;
+
+        if self.registers.banking_mode_select > 0 {
+            ram_addr |= (self.registers.secondary_bank as usize) << 13;
+        }
+
+        ram_addr
+    }
 }
 
 impl Cartridge for MBC1Cartridge {
+    fn battery_ram(&self) -> Option<&[u8]> {
+        (self.has_battery && !self.ram.is_empty()).then_some(&self.ram[..])
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        if self.has_battery {
+            let len = self.ram.len().min(data.len());
+            self.ram[..len].copy_from_slice(&data[..len]);
+        }
+    }
+
+    fn ram(&self) -> Option<&[u8]> {
+        (!self.ram.is_empty()).then_some(&self.ram[..])
+    }
+
+    fn ram_mut(&mut self) -> Option<&mut [u8]> {
+        (!self.ram.is_empty()).then_some(&mut self.ram[..])
+    }
+
+    fn mbc_register_writes(&self) -> Vec<(u16, u8)> {
+        vec![
+            (
+                0x0000,
+                if self.registers.ram_enable {
+                    0x0A
+                } else {
+                    0x00
+                },
+            ),
+            (0x2000, self.registers.rom_bank_number),
+            (0x4000, self.registers.secondary_bank),
+            (0x6000, self.registers.banking_mode_select),
+        ]
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.registers.ram_enable);
+        w.write_u8(self.registers.rom_bank_number);
+        w.write_u8(self.registers.secondary_bank);
+        w.write_u8(self.registers.banking_mode_select);
+        w.write_blob(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.registers.ram_enable = r.read_bool()?;
+        self.registers.rom_bank_number = r.read_u8()?;
+        self.registers.secondary_bank = r.read_u8()?;
+        self.registers.banking_mode_select = r.read_u8()?;
+
+        let ram = r.read_blob()?;
+        if ram.len() != self.ram.len() {
+            return Err(StateError::Invalid("cartridge RAM size"));
+        }
+        self.ram.copy_from_slice(ram);
+
+        Ok(())
+    }
+
     fn read_byte(&self, address: u16) -> u8 {
         match MemoryRegion::from_addr(address, false) {
             MemoryRegion::GameROMBank0 | MemoryRegion::GameROMBankN => {
@@ -61,13 +133,10 @@ impl Cartridge for MBC1Cartridge {
             }
             MemoryRegion::CartridgeRAM => {
                 if self.registers.ram_enable {
-                    let mut ram_addr = address & 0x1FFF;
-
-                    if self.registers.banking_mode_select > 0 {
-                        ram_addr |= (self.registers.secondary_bank as u16) << 13;
-                    }
-
-                    self.ram[ram_addr as usize]
+                    self.ram
+                        .get(self.ram_address(address))
+                        .copied()
+                        .unwrap_or(0xFF)
                 } else {
                     0xFF
                 }
@@ -105,6 +174,15 @@ impl Cartridge for MBC1Cartridge {
                 self.registers.banking_mode_select = val & 0b1;
             }
 
+            0xA000..=0xBFFF => {
+                if self.registers.ram_enable {
+                    let ram_addr = self.ram_address(address);
+                    if let Some(byte) = self.ram.get_mut(ram_addr) {
+                        *byte = val;
+                    }
+                }
+            }
+
             _ => panic!(
                 "Writing to bad address on MBC1 chip! Address = 0x{:x}",
                 address
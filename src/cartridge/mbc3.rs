@@ -2,10 +2,13 @@ use log::debug;
 
 use crate::{
     cartridge::{Cartridge, ROM_BANK_SIZE},
-    gameboy::DUMP_INFO_TICK,
     memory::MemoryRegion,
+    savestate::{StateError, StateReader, StateWriter},
 };
 
+const RTC_SECONDS: u8 = 0x08;
+const RTC_DAYS_HIGH: u8 = 0x0C;
+
 #[derive(Debug, Default)]
 pub struct RTCRegister {
     seconds: u8,
@@ -25,6 +28,8 @@ pub struct MBC3Registers {
     clock_latched: bool,
     rtc: RTCRegister,
     ram_over_rtc: bool,
+    // the RTC register mapped in when ram_over_rtc is off, 0x08 - 0x0C
+    rtc_register: u8,
 }
 
 #[derive(Debug)]
@@ -33,11 +38,13 @@ pub struct MBC3Cartridge {
     ram: Option<Vec<u8>>,
     registers: MBC3Registers,
     has_battery: bool,
+    // the clock is only on MBC3+TIMER cartridges
+    has_timer: bool,
     rom_banks: usize,
 }
 
 impl MBC3Cartridge {
-    pub fn new(rom: Vec<u8>, ram_banks: Option<usize>, has_battery: bool) -> Self {
+    pub fn new(rom: Vec<u8>, ram_banks: Option<usize>, has_battery: bool, has_timer: bool) -> Self {
         Self {
             rom_banks: rom.len() / ROM_BANK_SIZE,
             rom,
@@ -46,8 +53,12 @@ impl MBC3Cartridge {
             } else {
                 None
             },
-            registers: Default::default(),
+            registers: MBC3Registers {
+                rtc_register: RTC_SECONDS,
+                ..Default::default()
+            },
             has_battery,
+            has_timer,
         }
     }
 
@@ -57,6 +68,123 @@ impl MBC3Cartridge {
 }
 
 impl Cartridge for MBC3Cartridge {
+    fn battery_ram(&self) -> Option<&[u8]> {
+        if self.has_battery {
+            self.ram.as_deref()
+        } else {
+            None
+        }
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        if let Some(ram) = self.ram.as_mut().filter(|_| self.has_battery) {
+            let len = ram.len().min(data.len());
+            ram[..len].copy_from_slice(&data[..len]);
+        }
+    }
+
+    fn ram(&self) -> Option<&[u8]> {
+        self.ram.as_deref()
+    }
+
+    fn ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.ram.as_deref_mut()
+    }
+
+    fn mbc_register_writes(&self) -> Vec<(u16, u8)> {
+        let registers = &self.registers;
+
+        vec![
+            (
+                0x0000,
+                if registers.ram_timer_enable {
+                    0x0A
+                } else {
+                    0x00
+                },
+            ),
+            (0x2000, registers.rom_bank_number),
+            (
+                0x4000,
+                if registers.ram_over_rtc || !self.has_timer {
+                    registers.ram_bank_number
+                } else {
+                    registers.rtc_register
+                },
+            ),
+        ]
+    }
+
+    fn rtc_registers(&self) -> Option<[u8; 5]> {
+        if !self.has_timer {
+            return None;
+        }
+        let rtc = &self.registers.rtc;
+
+        Some([
+            rtc.seconds,
+            rtc.minutes,
+            rtc.hours,
+            rtc.days as u8,
+            (rtc.days >> 8) as u8 & 1 | (rtc.halt as u8) << 6 | (rtc.day_carry as u8) << 7,
+        ])
+    }
+
+    fn set_rtc_registers(&mut self, registers: [u8; 5]) {
+        let rtc = &mut self.registers.rtc;
+        rtc.seconds = registers[0];
+        rtc.minutes = registers[1];
+        rtc.hours = registers[2];
+        rtc.days = registers[3] as u16 | (registers[4] as u16 & 1) << 8;
+        rtc.halt = registers[4] & 0x40 != 0;
+        rtc.day_carry = registers[4] & 0x80 != 0;
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        let registers = &self.registers;
+        w.write_bool(registers.ram_timer_enable);
+        w.write_u8(registers.rom_bank_number);
+        w.write_u8(registers.ram_bank_number);
+        w.write_u8(registers.latch_clock);
+        w.write_bool(registers.clock_latched);
+        w.write_u8(registers.rtc.seconds);
+        w.write_u8(registers.rtc.minutes);
+        w.write_u8(registers.rtc.hours);
+        w.write_u16(registers.rtc.days);
+        w.write_bool(registers.rtc.halt);
+        w.write_bool(registers.rtc.day_carry);
+        w.write_bool(registers.ram_over_rtc);
+        w.write_blob(self.ram.as_deref().unwrap_or_default());
+        w.write_u8(registers.rtc_register);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let registers = &mut self.registers;
+        registers.ram_timer_enable = r.read_bool()?;
+        registers.rom_bank_number = r.read_u8()?;
+        registers.ram_bank_number = r.read_u8()?;
+        registers.latch_clock = r.read_u8()?;
+        registers.clock_latched = r.read_bool()?;
+        registers.rtc.seconds = r.read_u8()?;
+        registers.rtc.minutes = r.read_u8()?;
+        registers.rtc.hours = r.read_u8()?;
+        registers.rtc.days = r.read_u16()?;
+        registers.rtc.halt = r.read_bool()?;
+        registers.rtc.day_carry = r.read_bool()?;
+        registers.ram_over_rtc = r.read_bool()?;
+
+        let ram = r.read_blob()?;
+        match self.ram.as_mut() {
+            Some(own) if own.len() == ram.len() => own.copy_from_slice(ram),
+            None if ram.is_empty() => {}
+            _ => return Err(StateError::Invalid("cartridge RAM size")),
+        }
+
+        self.registers.rtc_register = r.read_u8()?;
+
+        Ok(())
+    }
+
     fn read_byte(&self, address: u16) -> u8 {
         match MemoryRegion::from_addr(address, false) {
             MemoryRegion::GameROMBank0 => {
@@ -121,10 +249,6 @@ impl Cartridge for MBC3Cartridge {
                     "RAM ENABLE write. Write addr=0x{:x}, val=0x{:x}",
                     address, val
                 );
-
-                // unsafe {
-                //     DUMP_INFO_TICK = true;
-                // }
             }
             0x2000..=0x3FFF => {
                 // rom bank number
@@ -154,9 +278,10 @@ impl Cartridge for MBC3Cartridge {
                         "RAM BANK write. Write addr=0x{:x}, val=0x{:x}",
                         address, val
                     );
-                } else {
+                } else if self.has_timer && (RTC_SECONDS..=RTC_DAYS_HIGH).contains(&val) {
                     debug!("RTC write. Write addr=0x{:x}, val=0x{:x}", address, val);
                     self.registers.ram_over_rtc = false;
+                    self.registers.rtc_register = val;
                 }
             }
 
@@ -206,3 +331,35 @@ impl Cartridge for MBC3Cartridge {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::Cartridge;
+
+    use super::MBC3Cartridge;
+
+    #[test]
+    fn test_rtc_register_writes() {
+        let mut cartridge = MBC3Cartridge::new(vec![0; 0x8000], Some(1), true, true);
+        assert_eq!(cartridge.mbc_register_writes()[2], (0x4000, 0x08));
+
+        cartridge.write_byte(0x4000, 0x0B);
+        assert_eq!(cartridge.mbc_register_writes()[2], (0x4000, 0x0B));
+
+        cartridge.write_byte(0x4000, 0x02);
+        assert_eq!(cartridge.mbc_register_writes()[2], (0x4000, 0x02));
+        assert!(cartridge.rtc_registers().is_some());
+    }
+
+    #[test]
+    fn test_no_timer() {
+        let mut cartridge = MBC3Cartridge::new(vec![0; 0x8000], Some(1), true, false);
+        assert_eq!(cartridge.rtc_registers(), None);
+        assert_eq!(cartridge.mbc_register_writes()[2], (0x4000, 0x00));
+
+        // there's no clock to select
+        cartridge.write_byte(0x4000, 0x01);
+        cartridge.write_byte(0x4000, 0x0B);
+        assert_eq!(cartridge.mbc_register_writes()[2], (0x4000, 0x01));
+    }
+}
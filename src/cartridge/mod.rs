@@ -1,6 +1,12 @@
-use std::{fmt::Debug, path::Path};
+use std::{
+    fmt::{Debug, Display},
+    path::Path,
+};
 
-use crate::cartridge::{mbc1::MBC1Cartridge, mbc3::MBC3Cartridge};
+use crate::{
+    cartridge::{basic::BasicCartridge, mbc1::MBC1Cartridge, mbc3::MBC3Cartridge},
+    savestate::{StateError, StateReader, StateWriter},
+};
 
 pub mod basic;
 pub mod mbc1;
@@ -10,7 +16,7 @@ const RAM_BANK_SIZE: usize = 0x2000;
 const ROM_BANK_SIZE: usize = 0x4000;
 
 #[derive(Debug)]
-enum CartridgeType {
+pub enum CartridgeType {
     ROMOnly,
     MBC1,
     MBC1Ram,
@@ -81,109 +87,311 @@ impl CartridgeType {
             | CartridgeType::MBC5RamBat => true,
         }
     }
+
+    fn has_timer(&self) -> bool {
+        matches!(
+            self,
+            CartridgeType::MBC3TimerBat | CartridgeType::MBC3RamTimerBat
+        )
+    }
 }
 
-impl From<u8> for CartridgeType {
-    fn from(value: u8) -> Self {
+impl CartridgeType {
+    pub fn from_byte(value: u8) -> Option<Self> {
         match value {
-            0x00 => Self::ROMOnly,
-            0x01 => Self::MBC1,
-            0x02 => Self::MBC1Ram,
-            0x03 => Self::MBC1RamBat,
-            0x05 => Self::MBC2,
-            0x06 => Self::MBC2Bat,
-            0x08 => Self::RomRam,
-            0x09 => Self::RomRamBat,
-            0x0F => Self::MBC3TimerBat,
-            0x10 => Self::MBC3RamTimerBat,
-            0x11 => Self::MBC3,
-            0x12 => Self::MBC3Ram,
-            0x13 => Self::MBC3RamBat,
-            0x15 => Self::MBC4,
-            0x16 => Self::MBC4Ram,
-            0x17 => Self::MBC4RamBat,
-            0x19 => Self::MBC5,
-            0x1A => Self::MBC5Ram,
-            0x1B => Self::MBC5RamBat,
-            _ => panic!("Unknown Cartridge Type value: 0x{:x}", value),
+            0x00 => Some(Self::ROMOnly),
+            0x01 => Some(Self::MBC1),
+            0x02 => Some(Self::MBC1Ram),
+            0x03 => Some(Self::MBC1RamBat),
+            0x05 => Some(Self::MBC2),
+            0x06 => Some(Self::MBC2Bat),
+            0x08 => Some(Self::RomRam),
+            0x09 => Some(Self::RomRamBat),
+            0x0F => Some(Self::MBC3TimerBat),
+            0x10 => Some(Self::MBC3RamTimerBat),
+            0x11 => Some(Self::MBC3),
+            0x12 => Some(Self::MBC3Ram),
+            0x13 => Some(Self::MBC3RamBat),
+            0x15 => Some(Self::MBC4),
+            0x16 => Some(Self::MBC4Ram),
+            0x17 => Some(Self::MBC4RamBat),
+            0x19 => Some(Self::MBC5),
+            0x1A => Some(Self::MBC5Ram),
+            0x1B => Some(Self::MBC5RamBat),
+            _ => None,
         }
     }
 }
 
+impl From<u8> for CartridgeType {
+    fn from(value: u8) -> Self {
+        Self::from_byte(value)
+            .unwrap_or_else(|| panic!("Unknown Cartridge Type value: 0x{:x}", value))
+    }
+}
+
 enum CartridgeHeaderConstants {
+    Title,
+    CGBFlag,
+    SGBFlag,
     RAMSize,
     ROMSize,
     CartridgeType,
+    Destination,
+    Version,
+    HeaderChecksum,
+    GlobalChecksum,
 }
 
 impl CartridgeHeaderConstants {
     fn get_address(&self) -> usize {
         match self {
+            CartridgeHeaderConstants::Title => 0x134,
+            CartridgeHeaderConstants::CGBFlag => 0x143,
+            CartridgeHeaderConstants::SGBFlag => 0x146,
             CartridgeHeaderConstants::RAMSize => 0x149,
             CartridgeHeaderConstants::ROMSize => 0x148,
             CartridgeHeaderConstants::CartridgeType => 0x147,
+            CartridgeHeaderConstants::Destination => 0x14A,
+            CartridgeHeaderConstants::Version => 0x14C,
+            CartridgeHeaderConstants::HeaderChecksum => 0x14D,
+            CartridgeHeaderConstants::GlobalChecksum => 0x14E,
         }
     }
 }
 
-fn get_ram_banks(ram_constant: u8) -> usize {
-    match ram_constant {
-        0 => 0,
-        2 => 1,
-        3 => 4,
-        4 => 16,
-        5 => 8,
-        _ => panic!("Unknown Cartridge RAM size value: 0x{:x}", ram_constant),
+const HEADER_END: usize = 0x150;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    // the ROM is shorter than the header, given its length
+    TooShort(usize),
+    UnknownRomSize(u8),
+    UnknownRamSize(u8),
+}
+
+impl Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeaderError::TooShort(len) => write!(
+                f,
+                "ROM is 0x{len:x} bytes, too short to hold a cartridge header"
+            ),
+            HeaderError::UnknownRomSize(value) => write!(f, "unknown ROM size value 0x{value:02x}"),
+            HeaderError::UnknownRamSize(value) => write!(f, "unknown RAM size value 0x{value:02x}"),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+// The cartridge header at 0x0100 - 0x014F, as far as we understand it
+#[derive(Debug)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb_flag: u8,
+    pub sgb_flag: u8,
+    pub cartridge_type: Option<CartridgeType>,
+    pub cartridge_type_byte: u8,
+    pub rom_banks: usize,
+    pub ram_banks: usize,
+    pub destination: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    pub fn from_rom(rom: &[u8]) -> Result<Self, HeaderError> {
+        if rom.len() < HEADER_END {
+            return Err(HeaderError::TooShort(rom.len()));
+        }
+
+        let byte = |constant: CartridgeHeaderConstants| rom[constant.get_address()];
+
+        let title_start = CartridgeHeaderConstants::Title.get_address();
+        let title = rom[title_start..title_start + 16]
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as char)
+            .collect();
+
+        let global_checksum = CartridgeHeaderConstants::GlobalChecksum.get_address();
+        let rom_size = byte(CartridgeHeaderConstants::ROMSize);
+        let ram_size = byte(CartridgeHeaderConstants::RAMSize);
+
+        Ok(Self {
+            title,
+            cgb_flag: byte(CartridgeHeaderConstants::CGBFlag),
+            sgb_flag: byte(CartridgeHeaderConstants::SGBFlag),
+            cartridge_type: CartridgeType::from_byte(byte(CartridgeHeaderConstants::CartridgeType)),
+            cartridge_type_byte: byte(CartridgeHeaderConstants::CartridgeType),
+            rom_banks: get_rom_banks(rom_size).ok_or(HeaderError::UnknownRomSize(rom_size))?,
+            ram_banks: get_ram_banks(ram_size).ok_or(HeaderError::UnknownRamSize(ram_size))?,
+            destination: byte(CartridgeHeaderConstants::Destination),
+            version: byte(CartridgeHeaderConstants::Version),
+            header_checksum: byte(CartridgeHeaderConstants::HeaderChecksum),
+            global_checksum: u16::from_be_bytes([rom[global_checksum], rom[global_checksum + 1]]),
+        })
+    }
+
+    // the checksum of 0x0134 - 0x014C the boot ROM checks against
+    // header_checksum
+    pub fn computed_header_checksum(rom: &[u8]) -> u8 {
+        rom[0x134..=0x14C]
+            .iter()
+            .fold(0u8, |acc, b| acc.wrapping_sub(*b).wrapping_sub(1))
     }
 }
 
-fn get_rom_banks(ram_constant: u8) -> usize {
+fn get_ram_banks(ram_constant: u8) -> Option<usize> {
     match ram_constant {
-        0 => 2,
-        1 => 4,
-        2 => 8,
-        3 => 16,
-        4 => 32,
-        5 => 64,
-        6 => 128,
-        7 => 256,
-        8 => 512,
-        _ => panic!("Unknown Cartridge RAM size value: 0x{:x}", ram_constant),
+        0 => Some(0),
+        2 => Some(1),
+        3 => Some(4),
+        4 => Some(16),
+        5 => Some(8),
+        _ => None,
+    }
+}
+
+fn get_rom_banks(rom_constant: u8) -> Option<usize> {
+    match rom_constant {
+        0..=8 => Some(2 << rom_constant),
+        _ => None,
     }
 }
 
 pub fn create_cartridge(path: &Path) -> Box<dyn Cartridge> {
-    let cart = std::fs::read(path).unwrap();
+    create_cartridge_from_rom(std::fs::read(path).unwrap())
+}
 
+pub fn create_cartridge_from_rom(cart: Vec<u8>) -> Box<dyn Cartridge> {
     let cart_type =
         CartridgeType::from(cart[CartridgeHeaderConstants::CartridgeType.get_address()]);
-    let ram_banks = get_ram_banks(cart[CartridgeHeaderConstants::RAMSize.get_address()]);
-    let rom_banks = get_ram_banks(cart[CartridgeHeaderConstants::ROMSize.get_address()]);
+    let ram_size = cart[CartridgeHeaderConstants::RAMSize.get_address()];
+    let ram_banks = get_ram_banks(ram_size)
+        .unwrap_or_else(|| panic!("Unknown Cartridge RAM size value: 0x{:x}", ram_size));
+    let rom_size = cart[CartridgeHeaderConstants::ROMSize.get_address()];
+    let rom_banks = get_rom_banks(rom_size)
+        .unwrap_or_else(|| panic!("Unknown Cartridge ROM size value: 0x{:x}", rom_size));
     let battery = cart_type.has_battery();
     let has_ram = cart_type.has_ram();
 
     match cart_type {
-        CartridgeType::MBC1 | CartridgeType::MBC1Ram => {
-            Box::new(MBC1Cartridge::new(cart, ram_banks, rom_banks))
+        CartridgeType::ROMOnly => Box::new(BasicCartridge::from_rom(&cart)),
+        CartridgeType::MBC1 | CartridgeType::MBC1Ram | CartridgeType::MBC1RamBat => {
+            Box::new(MBC1Cartridge::new(cart, ram_banks, rom_banks, battery))
         }
         CartridgeType::MBC3
         | CartridgeType::MBC3Ram
         | CartridgeType::MBC3RamBat
+        | CartridgeType::MBC3TimerBat
         | CartridgeType::MBC3RamTimerBat => Box::new(MBC3Cartridge::new(
             cart,
             if has_ram { Some(ram_banks) } else { None },
             battery,
+            cart_type.has_timer(),
         )),
         _ => panic!("Unimplemented cartridge selected! {:?}", cart_type),
     }
 }
 
-pub trait Cartridge: Debug {
+pub trait Cartridge: Debug + Send {
     fn read_byte(&self, address: u16) -> u8;
     fn write_byte(&mut self, address: u16, val: u8);
 
+    // battery backed RAM to keep between runs, if the cartridge has any
+    fn battery_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn load_battery_ram(&mut self, _data: &[u8]) {}
+
+    // all cartridge RAM, battery backed or not
+    fn ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    // writes that put a fresh cartridge's mapper into the current banking
+    // state, for save state formats that store MBC state that way (BESS)
+    fn mbc_register_writes(&self) -> Vec<(u16, u8)> {
+        Vec::new()
+    }
+
+    // the real time clock registers, if there is a clock: seconds,
+    // minutes, hours, the low byte of the day counter, then the day
+    // counter's high bit with the halt (bit 6) and day carry (bit 7) flags
+    fn rtc_registers(&self) -> Option<[u8; 5]> {
+        None
+    }
+
+    fn set_rtc_registers(&mut self, _registers: [u8; 5]) {}
+
+    // mapper registers and RAM for save states. The ROM is not included
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+
     // let the cartridge have a tick every M cycle.
     // Not necessary in most cases, but useful for
     // when cartridges have onboard clocks
     fn tick(&mut self) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{create_cartridge_from_rom, CartridgeHeader, HeaderError};
+
+    #[test]
+    fn test_header_from_rom() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x138].copy_from_slice(b"GBAR");
+        rom[0x147] = 0x03;
+        rom[0x148] = 0x05;
+        rom[0x149] = 0x03;
+
+        let header = CartridgeHeader::from_rom(&rom).unwrap();
+        assert_eq!(header.title, "GBAR");
+        assert_eq!((header.rom_banks, header.ram_banks), (64, 4));
+
+        rom[0x148] = 0x42;
+        assert_eq!(
+            CartridgeHeader::from_rom(&rom).unwrap_err(),
+            HeaderError::UnknownRomSize(0x42)
+        );
+        assert_eq!(
+            CartridgeHeader::from_rom(&rom[..0x100]).unwrap_err(),
+            HeaderError::TooShort(0x100)
+        );
+    }
+
+    #[test]
+    fn test_mbc1_battery_ram() {
+        // MBC1+RAM+BATTERY with one 8 KiB bank
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+
+        let mut cart = create_cartridge_from_rom(rom.clone());
+        cart.write_byte(0x0000, 0x0A);
+        cart.write_byte(0xA123, 0x42);
+        let saved = cart.battery_ram().unwrap().to_vec();
+        assert_eq!(saved.len(), 0x2000);
+
+        let mut loaded = create_cartridge_from_rom(rom.clone());
+        loaded.load_battery_ram(&saved);
+        loaded.write_byte(0x0000, 0x0A);
+        assert_eq!(loaded.read_byte(0xA123), 0x42);
+
+        // the same without the battery keeps nothing
+        rom[0x147] = 0x02;
+        assert!(create_cartridge_from_rom(rom).battery_ram().is_none());
+    }
+}
@@ -2,8 +2,6 @@ use std::ops::{Shl, Shr};
 
 use log::{debug, info};
 
-use crate::gameboy::DUMP_INFO_TICK;
-use crate::hardware_registers::RegisterAddresses;
 use crate::instructions::{
     AdcTargetType, AddByteTarget, AddTargetType, AndTargetType, ArithmeticByteTarget,
     ArithmeticTargetType, ArithmeticWordTarget, BitPosition, BitRegister, Instruction, JpAddrLoc,
@@ -12,7 +10,9 @@ use crate::instructions::{
     XORTargetType,
 };
 use crate::memory::MemoryBus;
-use crate::registers::Registers;
+use crate::model::Model;
+use crate::registers::{FlagsRegister, Registers};
+use crate::savestate::{StateError, StateReader, StateWriter};
 
 #[derive(Debug, Clone)]
 pub struct CPU {
@@ -24,6 +24,13 @@ pub struct CPU {
     instruction_counter: i64,
     wait_ticks: usize,
     wait_instr: Option<Instruction>,
+    // opcode and CB prefix of wait_instr, for save states
+    wait_opcode: (u8, bool),
+
+    // interrupt master enable, and EI's request to set it after the next
+    // instruction
+    ime: bool,
+    ime_pending: bool,
 }
 
 impl CPU {
@@ -37,6 +44,9 @@ impl CPU {
             instruction_counter: 0,
             wait_ticks: 0,
             wait_instr: None,
+            wait_opcode: (0, false),
+            ime: false,
+            ime_pending: false,
         }
     }
     pub fn new_and_empty() -> Self {
@@ -49,11 +59,117 @@ impl CPU {
             instruction_counter: 0,
             wait_ticks: 0,
             wait_instr: None,
+            wait_opcode: (0, false),
+            ime: false,
+            ime_pending: false,
         }
     }
     pub fn reset(&mut self) {
         self.sp = 0xFFFE;
         self.pc = 0;
+        self.ime = false;
+        self.ime_pending = false;
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    // jump to pc, dropping any instruction still in flight
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+        self.wait_ticks = 0;
+        self.wait_instr = None;
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, sp: u16) {
+        self.sp = sp;
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    pub fn set_ime(&mut self, ime: bool) {
+        self.ime = ime;
+        self.ime_pending = false;
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.is_halted
+    }
+
+    pub fn set_halted(&mut self, halted: bool) {
+        self.is_halted = halted;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        let r = &self.registers;
+        for reg in [r.a, u8::from(r.f), r.b, r.c, r.d, r.e, r.h, r.l] {
+            w.write_u8(reg);
+        }
+        w.write_u16(self.pc);
+        w.write_u16(self.sp);
+        w.write_bool(self.is_halted);
+        w.write_u64(self.instruction_counter as u64);
+        w.write_u32(self.wait_ticks as u32);
+        w.write_bool(self.wait_instr.is_some());
+        w.write_u8(self.wait_opcode.0);
+        w.write_bool(self.wait_opcode.1);
+        w.write_bool(self.ime);
+        w.write_bool(self.ime_pending);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.registers.a = r.read_u8()?;
+        self.registers.f = FlagsRegister::from(r.read_u8()?);
+        self.registers.b = r.read_u8()?;
+        self.registers.c = r.read_u8()?;
+        self.registers.d = r.read_u8()?;
+        self.registers.e = r.read_u8()?;
+        self.registers.h = r.read_u8()?;
+        self.registers.l = r.read_u8()?;
+        self.pc = r.read_u16()?;
+        self.sp = r.read_u16()?;
+        self.is_halted = r.read_bool()?;
+        self.instruction_counter = r.read_u64()? as i64;
+        self.wait_ticks = r.read_u32()? as usize;
+
+        let waiting = r.read_bool()?;
+        self.wait_opcode = (r.read_u8()?, r.read_bool()?);
+        self.wait_instr = if waiting {
+            Some(
+                Instruction::from_byte(self.wait_opcode.0, self.wait_opcode.1)
+                    .ok_or(StateError::Invalid("unknown pending instruction"))?,
+            )
+        } else {
+            None
+        };
+
+        self.ime = r.read_bool()?;
+        self.ime_pending = r.read_bool()?;
+
+        Ok(())
+    }
+
+    // Load the register state the boot ROM of the given model hands over
+    // to the cartridge with
+    pub fn skip_boot(&mut self, model: Model, header_checksum: u8) {
+        self.registers = model.post_boot_registers(header_checksum);
+        self.sp = 0xFFFE;
+        self.pc = 0x0100;
     }
 
     pub fn step(&mut self, bus: &mut MemoryBus) -> bool {
@@ -66,6 +182,20 @@ impl CPU {
             self.wait_ticks = 0;
             self.execute_outer(instr, bus);
         } else {
+            if self.handle_interrupt(bus) {
+                return true;
+            }
+
+            if self.ime_pending {
+                self.ime_pending = false;
+                self.ime = true;
+            }
+
+            // nothing is fetched or run until an interrupt wakes the CPU
+            if self.is_halted {
+                return true;
+            }
+
             let mut instruction_byte = bus.read_byte(self.pc);
 
             let prefix = instruction_byte == 0xCB;
@@ -73,7 +203,7 @@ impl CPU {
                 instruction_byte = bus.read_byte(self.pc + 1);
             }
 
-            if self.debug_view || unsafe { DUMP_INFO_TICK } {
+            if self.debug_view {
                 debug!(
                     "\n\n{}: Parsing instruction: 0x{}{:x}. pc=0x{:x} (boot mode active: {})\n{:?}\nsp=0x{:x}",
                     self.instruction_counter,
@@ -92,6 +222,7 @@ impl CPU {
                 if wait_ticks > 1 {
                     self.wait_ticks = wait_ticks - 1;
                     self.wait_instr = Some(new_instr);
+                    self.wait_opcode = (instruction_byte, prefix);
                 } else {
                     self.wait_ticks = 0;
                     self.execute_outer(new_instr, bus);
@@ -118,27 +249,39 @@ impl CPU {
         true
     }
 
+    // Wake from HALT if an interrupt is pending, and jump to its handler if
+    // interrupts are enabled. Returns true if the handler was entered, which
+    // takes the place of an instruction.
+    fn handle_interrupt(&mut self, bus: &mut MemoryBus) -> bool {
+        let Some(interrupt) = bus.pending_interrupt() else {
+            return false;
+        };
+
+        self.is_halted = false;
+
+        if !self.ime {
+            return false;
+        }
+
+        self.ime = false;
+        bus.acknowledge_interrupt(interrupt);
+        self.push(self.pc, bus);
+        self.pc = interrupt.vector();
+
+        // 5 M-cycles, this step being the first
+        self.wait_ticks = 5;
+
+        true
+    }
+
     fn execute_outer(&mut self, instruction: Instruction, bus: &mut MemoryBus) {
+        let pc = self.pc;
         self.pc = self.execute(instruction.clone(), bus);
-        if unsafe { DUMP_INFO_TICK } {
-            info!(
-                "\n\n{}:  pc=0x{:x} (boot mode active: {})\n{:?}\nsp=0x{:x}. Instruction={:?}",
-                self.instruction_counter,
-                self.pc,
-                bus.boot_mode_active(),
-                self.registers,
-                self.sp,
-                instruction
-            );
-        }
+        bus.hooks_mut().instruction(pc, &instruction);
         self.instruction_counter += 1;
     }
 
     fn execute(&mut self, instruction: Instruction, bus: &mut MemoryBus) -> u16 {
-        if self.is_halted {
-            return self.pc;
-        }
-
         match instruction {
             Instruction::ADC(target) => self.adc_instr(target, bus),
             Instruction::ADD(add_type) => self.add_instr(add_type, bus),
@@ -764,11 +907,12 @@ impl CPU {
             }
             Instruction::SWAP(target) => self.swap(target, bus),
             Instruction::XOR(target) => self.xor_instr(target, bus),
-            Instruction::STOP => self.stop(),
+            Instruction::STOP => self.stop(bus),
             Instruction::RETI => self.reti(bus),
             Instruction::SLA(target) => self.sla(target, bus),
             Instruction::DAA => self.daa(bus),
-            Instruction::EI => self.ei(bus),
+            Instruction::EI => self.ei(),
+            Instruction::DI => self.di(),
             Instruction::SRA(target) => self.sra(target, bus),
             Instruction::SRL(target) => self.sra(target, bus),
         }
@@ -778,9 +922,15 @@ impl CPU {
         todo!()
     }
 
-    fn ei(&mut self, bus: &mut MemoryBus) -> u16 {
-        bus.write_byte(RegisterAddresses::IE.address(), 1);
-        1
+    fn ei(&mut self) -> u16 {
+        self.ime_pending = true;
+        self.pc.wrapping_add(1)
+    }
+
+    fn di(&mut self) -> u16 {
+        self.ime = false;
+        self.ime_pending = false;
+        self.pc.wrapping_add(1)
     }
 
     fn sra(&mut self, target: ArithmeticByteTarget, bus: &mut MemoryBus) -> u16 {
@@ -857,13 +1007,16 @@ impl CPU {
 
     // Unconditional return from a function. Also enables interrupts by setting IME=1
     fn reti(&mut self, bus: &mut MemoryBus) -> u16 {
-        bus.write_byte(RegisterAddresses::IE.address(), 1);
+        self.ime = true;
 
         self.pop(bus)
     }
 
-    fn stop(&mut self) -> u16 {
-        self.is_halted = true;
+    fn stop(&mut self, bus: &mut MemoryBus) -> u16 {
+        // on CGB, STOP with a speed switch armed just switches speed
+        if !bus.try_speed_switch() {
+            self.is_halted = true;
+        }
 
         self.pc.wrapping_add(1)
     }
@@ -1471,11 +1624,11 @@ impl CPU {
 
 #[cfg(test)]
 mod tests {
-    use std::{cell::RefCell, rc::Rc};
+    use std::sync::{Arc, Mutex};
 
     use test_case::test_matrix;
 
-    use crate::{memory::MemoryBus, ppu::PPU};
+    use crate::{hardware_registers::Interrupt, memory::MemoryBus};
 
     use super::{Instruction, CPU};
 
@@ -1495,9 +1648,73 @@ mod tests {
         let instruction = Instruction::from_byte(opcode, prefixed).unwrap();
 
         let mut cpu = CPU::new_and_empty();
-        let ppu = Rc::new(RefCell::new(PPU::new()));
-        let mut bus = MemoryBus::new_and_empty(None, ppu);
+        let mut bus = MemoryBus::new_and_empty(None);
 
         cpu.execute(instruction, &mut bus);
     }
+
+    #[test]
+    fn test_interrupt_dispatch_after_ei() {
+        let mut cpu = CPU::new_and_empty();
+        let mut bus = MemoryBus::new_and_empty(None);
+
+        let taken = Arc::new(Mutex::new(Vec::new()));
+        let hook_taken = taken.clone();
+        bus.hooks_mut()
+            .on_interrupt(move |interrupt| hook_taken.lock().unwrap().push(interrupt));
+
+        // EI; NOP
+        cpu.reset();
+        cpu.pc = 0xC000;
+        bus.write_byte(0xC000, 0xFB);
+        bus.write_byte(0xC001, 0x00);
+
+        bus.write_byte(0xFFFF, 0b101);
+        bus.request_interrupt(Interrupt::Timer);
+        bus.request_interrupt(Interrupt::VBlank);
+
+        // the interrupt is only taken after the instruction following EI
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.pc, 0xC002);
+        assert!(taken.lock().unwrap().is_empty());
+
+        cpu.step(&mut bus);
+        assert_eq!(cpu.pc, 0x40);
+        assert_eq!(bus.read_byte(0xFF0F) & 0x1F, Interrupt::Timer.bit());
+        assert_eq!(*taken.lock().unwrap(), vec![Interrupt::VBlank]);
+    }
+
+    #[test]
+    fn test_interrupt_masking_and_di() {
+        let mut cpu = CPU::new_and_empty();
+        let mut bus = MemoryBus::new_and_empty(None);
+
+        // EI; DI; EI; NOP
+        cpu.reset();
+        cpu.pc = 0xC000;
+        for (i, byte) in [0xFB, 0xF3, 0xFB, 0x00].into_iter().enumerate() {
+            bus.write_byte(0xC000 + i as u16, byte);
+        }
+
+        bus.write_byte(0xFFFF, Interrupt::Serial.bit() | Interrupt::Joypad.bit());
+        for interrupt in [Interrupt::LCD, Interrupt::Joypad, Interrupt::Serial] {
+            bus.request_interrupt(interrupt);
+        }
+
+        // DI straight after EI cancels it
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.pc, 0xC003);
+
+        // LCD is requested first and has priority, but isn't enabled
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.pc, Interrupt::Serial.vector());
+        assert_eq!(
+            bus.read_byte(0xFF0F) & 0x1F,
+            Interrupt::LCD.bit() | Interrupt::Joypad.bit()
+        );
+    }
 }
@@ -1,8 +1,5 @@
-use crate::{
-    memory::MemoryBus,
-    ppu::{DrawColor, PPU},
-};
-use minifb::{Key, Window, WindowOptions};
+use crate::{joypad::Buttons, ppu::Rgb};
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
 
 pub const BACKGROUND_WIDTH_PIXELS: usize = 256;
 pub const BACKGROUND_HEIGHT_PIXELS: usize = 256;
@@ -10,25 +7,49 @@ pub const BACKGROUND_TILES_PIXELS_WIDTH: usize = 8;
 pub const BACKGROUND_TILES_PIXELS_HEIGHT: usize = 8;
 pub const BACKGROUND_TILES_WIDTH_N: usize = 32;
 pub const BACKGROUND_TILES_HEIGHT_N: usize = 32;
-pub const SCREEN_WIDTH_PIXELS: usize = 144;
-pub const SCREEN_HEIGHT_PIXELS: usize = 160;
+pub const SCREEN_WIDTH_PIXELS: usize = 160;
+pub const SCREEN_HEIGHT_PIXELS: usize = 144;
 pub const MAX_DISPLAY_SPRITES: usize = 40;
 pub const MAX_DISPLAY_SPRITES_PER_SCAN_LINE: usize = 10;
 
-pub const WINDOW_PX_WIDTH: usize = 144;
-pub const WINDOW_PX_HEIHGT: usize = 160;
+pub const WINDOW_PX_WIDTH: usize = 160;
+pub const WINDOW_PX_HEIHGT: usize = 144;
+
+// emulator hotkeys: presses are true for the first render after the key
+// went down, the rest while the key is held
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Controls {
+    pub pause: bool,
+    pub frame_advance: bool,
+    pub speed_up: bool,
+    pub slow_down: bool,
+    pub fast_forward: bool,
+    pub rewind: bool,
+    pub screenshot: bool,
+    pub capture: bool,
+}
 
 pub struct GbDisplay {
     window: Window,
 }
 
 impl GbDisplay {
-    pub fn start() -> Result<Self, ()> {
+    pub fn start(width: usize, height: usize, scale: usize) -> Result<Self, ()> {
+        let scale = match scale {
+            0 | 1 => Scale::X1,
+            2 | 3 => Scale::X2,
+            4..=7 => Scale::X4,
+            _ => Scale::X8,
+        };
+
         let mut window = Window::new(
             "Test - ESC to exit",
-            WINDOW_PX_WIDTH,
-            WINDOW_PX_HEIHGT,
-            WindowOptions::default(),
+            width,
+            height,
+            WindowOptions {
+                scale,
+                ..WindowOptions::default()
+            },
         )
         .unwrap_or_else(|e| {
             panic!("{}", e);
@@ -38,31 +59,58 @@ impl GbDisplay {
         Ok(Self { window })
     }
 
-    pub fn render(&mut self, bus: &MemoryBus, ppu: &PPU) -> bool {
-        let buffer = ppu.get_screen_buffer();
-        let mut draw_buffer: Vec<u32> =
-            Vec::with_capacity(SCREEN_WIDTH_PIXELS * SCREEN_HEIGHT_PIXELS);
-
-        if bus.registers.LCDC.lcd_display_enable {
-            for i in 0..buffer.len() {
-                for j in 0..buffer[0].len() {
-                    draw_buffer.push(match buffer[i][j] {
-                        DrawColor::BLACK => 0,
-                        DrawColor::DARKGREY => 0x525252,
-                        DrawColor::LIGHTGREY => 0xb0b0b0,
-                        DrawColor::WHITE => 0xffffff,
-                    });
-                }
-            }
-        } else {
-            draw_buffer = vec![0; SCREEN_WIDTH_PIXELS * SCREEN_HEIGHT_PIXELS];
-        }
-
+    pub fn render(&mut self, frame: &[Rgb], width: usize, height: usize) -> bool {
         self.window
-            .update_with_buffer(&draw_buffer, SCREEN_WIDTH_PIXELS, SCREEN_HEIGHT_PIXELS)
+            .update_with_buffer(frame, width, height)
             .unwrap();
         self.window.is_open() && !self.window.is_key_down(Key::Escape)
     }
+
+    // arrow keys for the d-pad, Z/X for A/B, Enter for start and
+    // Backspace for select
+    pub fn buttons(&self) -> Buttons {
+        Buttons {
+            right: self.window.is_key_down(Key::Right),
+            left: self.window.is_key_down(Key::Left),
+            up: self.window.is_key_down(Key::Up),
+            down: self.window.is_key_down(Key::Down),
+            a: self.window.is_key_down(Key::Z),
+            b: self.window.is_key_down(Key::X),
+            select: self.window.is_key_down(Key::Backspace),
+            start: self.window.is_key_down(Key::Enter),
+        }
+    }
+
+    // a second player's keys, for two linked machines in one window: I/J/K/L
+    // for the d-pad, ./, for A/B, right shift for start and / for select
+    pub fn second_player_buttons(&self) -> Buttons {
+        Buttons {
+            right: self.window.is_key_down(Key::L),
+            left: self.window.is_key_down(Key::J),
+            up: self.window.is_key_down(Key::I),
+            down: self.window.is_key_down(Key::K),
+            a: self.window.is_key_down(Key::Period),
+            b: self.window.is_key_down(Key::Comma),
+            select: self.window.is_key_down(Key::Slash),
+            start: self.window.is_key_down(Key::RightShift),
+        }
+    }
+
+    // P to pause, N to advance a frame (pausing), -/= to step the speed
+    // down and up, Tab and R held to fast forward and rewind, F12 for a
+    // screenshot and F10 to start and stop capturing video
+    pub fn controls(&self) -> Controls {
+        Controls {
+            pause: self.window.is_key_pressed(Key::P, KeyRepeat::No),
+            frame_advance: self.window.is_key_pressed(Key::N, KeyRepeat::Yes),
+            speed_up: self.window.is_key_pressed(Key::Equal, KeyRepeat::No),
+            slow_down: self.window.is_key_pressed(Key::Minus, KeyRepeat::No),
+            fast_forward: self.window.is_key_down(Key::Tab),
+            rewind: self.window.is_key_down(Key::R),
+            screenshot: self.window.is_key_pressed(Key::F12, KeyRepeat::No),
+            capture: self.window.is_key_pressed(Key::F10, KeyRepeat::No),
+        }
+    }
 }
 // pub struct GbDisplay {
 //     rl: RaylibHandle,
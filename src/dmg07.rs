@@ -0,0 +1,424 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{gameboy::Gameboy, serial::SerialDevice};
+
+// DMG-07 Four Player Adapter. It drives the clock for every Game Boy
+// plugged into it, which all wait on the external clock.
+//
+// It starts in the ping phase, sending each player FE and then three
+// STAT bytes: the players that answered the last ping in the high nibble
+// (bit 4 for player 1) and the player's own number in the low bits. A
+// Game Boy answers 88 88, then the RATE and SIZE it wants. Once player 1
+// answers a whole ping with AA, the adapter sends CC four times and
+// moves to the transmission phase. There it sends rounds of 4 * SIZE
+// bytes: each player's SIZE byte packet from the previous round, player 1
+// first, while taking each player's next packet from its first SIZE
+// replies. A round where every player sends only FF goes back to ping.
+//
+// The real adapter's timings aren't known exactly, so bytes come at a
+// steady rate: every PING_BYTE_CLOCKS while pinging, and every
+// TRANSMISSION_BYTE_CLOCKS plus RATE_STEP_CLOCKS for each step of RATE's
+// low nibble while transmitting.
+const PING_BYTE_CLOCKS: usize = 16384;
+const TRANSMISSION_BYTE_CLOCKS: usize = 4096;
+const RATE_STEP_CLOCKS: usize = 1024;
+
+pub const MAX_PLAYERS: usize = 4;
+
+const PING: u8 = 0xFE;
+const ACK: u8 = 0x88;
+const START: u8 = 0xAA;
+const STARTING: u8 = 0xCC;
+const RESTART: u8 = 0xFF;
+const PING_PACKET_LEN: usize = 4;
+const MAX_SIZE: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Ping,
+    Starting,
+    Transmission,
+}
+
+#[derive(Debug)]
+struct Hub {
+    players: usize,
+    phase: Phase,
+    // master clock ticks since the last byte
+    clocks: usize,
+    // position in the current packet, and whether that byte went out
+    index: usize,
+    in_flight: bool,
+    // bytes clocked to each player that it hasn't taken yet, and what it
+    // sent back when it did
+    pending: [Option<u8>; MAX_PLAYERS],
+    replies: [Option<u8>; MAX_PLAYERS],
+    // each player's replies so far this packet
+    packet_replies: [Vec<u8>; MAX_PLAYERS],
+    // players that answered the last ping, bit 0 for player 1
+    connected: u8,
+    rate: u8,
+    size: usize,
+    // what's being sent this round of the transmission phase
+    outgoing: Vec<u8>,
+}
+
+impl Hub {
+    fn new(players: usize) -> Self {
+        Self {
+            players,
+            phase: Phase::Ping,
+            clocks: 0,
+            index: 0,
+            in_flight: false,
+            pending: [None; MAX_PLAYERS],
+            replies: [None; MAX_PLAYERS],
+            packet_replies: Default::default(),
+            connected: 0,
+            rate: 0,
+            size: 1,
+            outgoing: Vec::new(),
+        }
+    }
+
+    fn byte_clocks(&self) -> usize {
+        match self.phase {
+            Phase::Ping | Phase::Starting => PING_BYTE_CLOCKS,
+            Phase::Transmission => {
+                TRANSMISSION_BYTE_CLOCKS + (self.rate & 0x0F) as usize * RATE_STEP_CLOCKS
+            }
+        }
+    }
+
+    fn packet_len(&self) -> usize {
+        match self.phase {
+            Phase::Ping | Phase::Starting => PING_PACKET_LEN,
+            Phase::Transmission => self.size * MAX_PLAYERS,
+        }
+    }
+
+    // advance by one master clock tick
+    fn tick(&mut self) {
+        self.clocks += 1;
+        if self.clocks < self.byte_clocks() {
+            return;
+        }
+        self.clocks = 0;
+
+        if self.in_flight {
+            self.finish_byte();
+        }
+
+        for player in 0..self.players {
+            self.pending[player] = Some(self.next_byte(player));
+        }
+        self.in_flight = true;
+    }
+
+    fn next_byte(&self, player: usize) -> u8 {
+        match self.phase {
+            Phase::Ping if self.index == 0 => PING,
+            Phase::Ping => self.connected << 4 | (player as u8 + 1),
+            Phase::Starting => STARTING,
+            Phase::Transmission => self.outgoing[self.index],
+        }
+    }
+
+    // A player that wasn't waiting on the clock missed the byte, and
+    // shifted in nothing but 1s
+    fn finish_byte(&mut self) {
+        for player in 0..self.players {
+            let missed = self.pending[player].take().is_some();
+            let reply = self.replies[player].take().filter(|_| !missed);
+            self.packet_replies[player].push(reply.unwrap_or(0xFF));
+        }
+
+        self.index += 1;
+        if self.index == self.packet_len() {
+            self.finish_packet();
+            self.index = 0;
+            self.packet_replies.iter_mut().for_each(Vec::clear);
+        }
+    }
+
+    fn finish_packet(&mut self) {
+        match self.phase {
+            Phase::Ping => {
+                self.connected = 0;
+                for (player, replies) in self.packet_replies[..self.players].iter().enumerate() {
+                    if replies[..2] == [ACK, ACK] {
+                        self.connected |= 1 << player;
+                    }
+                }
+
+                let first = &self.packet_replies[0];
+                if first[..2] == [ACK, ACK] {
+                    self.rate = first[2];
+                    self.size = first[3].clamp(1, MAX_SIZE) as usize;
+                } else if first.iter().all(|&reply| reply == START) {
+                    self.connected |= 1;
+                    self.phase = Phase::Starting;
+                }
+            }
+            Phase::Starting => {
+                self.phase = Phase::Transmission;
+                self.outgoing = vec![0; self.packet_len()];
+            }
+            Phase::Transmission => {
+                let packets: Vec<&[u8]> = self.packet_replies[..self.players]
+                    .iter()
+                    .enumerate()
+                    .filter(|(player, _)| self.connected & (1 << player) != 0)
+                    .map(|(_, replies)| &replies[..self.size])
+                    .collect();
+
+                if packets
+                    .iter()
+                    .all(|packet| packet.iter().all(|&b| b == RESTART))
+                {
+                    self.phase = Phase::Ping;
+                    return;
+                }
+
+                for (player, chunk) in self.outgoing.chunks_mut(self.size).enumerate() {
+                    if self.connected & (1 << player) != 0 {
+                        chunk.copy_from_slice(&self.packet_replies[player][..self.size]);
+                    } else {
+                        chunk.fill(0);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// One of the adapter's four sockets
+#[derive(Debug)]
+pub struct AdapterPort {
+    hub: Arc<Mutex<Hub>>,
+    player: usize,
+}
+
+impl SerialDevice for AdapterPort {
+    // the adapter only listens to its own clock
+    fn transfer(&mut self, _byte: u8) -> Option<u8> {
+        Some(0xFF)
+    }
+
+    fn poll_external(&mut self, byte: u8) -> Option<u8> {
+        let mut hub = self.hub.lock().unwrap();
+
+        let incoming = hub.pending[self.player].take()?;
+        hub.replies[self.player] = Some(byte);
+
+        Some(incoming)
+    }
+}
+
+fn adapter(players: usize) -> (Arc<Mutex<Hub>>, Vec<AdapterPort>) {
+    let hub = Arc::new(Mutex::new(Hub::new(players)));
+    let ports = (0..players)
+        .map(|player| AdapterPort {
+            hub: hub.clone(),
+            player,
+        })
+        .collect();
+
+    (hub, ports)
+}
+
+// Up to four Game Boys plugged into a DMG-07, stepped a master clock tick
+// at a time along with the adapter. Player 1 is players[0].
+pub struct FourPlayerAdapter {
+    pub players: Vec<Box<Gameboy>>,
+    hub: Arc<Mutex<Hub>>,
+}
+
+impl FourPlayerAdapter {
+    // plug each Game Boy in, replacing any serial devices
+    pub fn new(mut players: Vec<Box<Gameboy>>) -> Self {
+        assert!(
+            (1..=MAX_PLAYERS).contains(&players.len()),
+            "the DMG-07 takes 1 to {} players",
+            MAX_PLAYERS
+        );
+
+        let (hub, ports) = adapter(players.len());
+        for (gb, port) in players.iter_mut().zip(ports) {
+            gb.set_serial_device(Box::new(port));
+        }
+
+        Self { players, hub }
+    }
+
+    // Advance every player and the adapter by one master clock tick.
+    // Returns whether player 1 finished a frame.
+    pub fn step(&mut self) -> bool {
+        let mut frame_finished = false;
+        for (player, gb) in self.players.iter_mut().enumerate() {
+            let finished = gb.is_running() && gb.step();
+            frame_finished |= player == 0 && finished;
+        }
+        self.hub.lock().unwrap().tick();
+
+        frame_finished
+    }
+
+    // Emulate up to player 1's next V-Blank. Returns false if any CPU has
+    // stopped.
+    pub fn run_frame(&mut self) -> bool {
+        while self.is_running() {
+            if self.step() {
+                break;
+            }
+        }
+
+        self.is_running()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.players.iter().all(|gb| gb.is_running())
+    }
+
+    // players that answered the adapter's last ping, bit 0 for player 1
+    pub fn connected(&self) -> u8 {
+        self.hub.lock().unwrap().connected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        model::Model,
+        serial::SerialDevice,
+        testing::{test_gameboy, write_test_rom},
+    };
+
+    use super::{adapter, FourPlayerAdapter};
+
+    // the table of a player's replies, at 0x0170
+    const TABLE: usize = 0x20;
+    const BYTES: usize = 24;
+
+    // what each player puts in SB for byte n: ping answers asking for
+    // one byte packets, player 1 starting the game after two pings, then
+    // 11 and 22 as the packets
+    fn reply(player: usize, n: usize) -> u8 {
+        match n {
+            8..12 if player == 0 => 0xAA,
+            0..12 => [0x88, 0x88, 0x00, 0x01][n % 4],
+            12..16 => 0,
+            _ if n.is_multiple_of(4) => [0x11, 0x22][player],
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn test_ping_and_transmission() {
+        let (hub, mut ports) = adapter(2);
+        let mut received = [Vec::new(), Vec::new()];
+
+        for _ in 0..24 * 16384 {
+            hub.lock().unwrap().tick();
+            for (player, port) in ports.iter_mut().enumerate() {
+                let n = received[player].len();
+                if let Some(byte) = port.poll_external(reply(player, n)) {
+                    received[player].push(byte);
+                }
+            }
+            if received.iter().all(|r| r.len() >= 24) {
+                break;
+            }
+        }
+
+        // nobody has answered the first ping, both answered the second
+        assert_eq!(received[0][..4], [0xFE, 0x01, 0x01, 0x01]);
+        assert_eq!(received[1][4..8], [0xFE, 0x32, 0x32, 0x32]);
+        for r in &received {
+            assert_eq!(r[12..16], [0xCC; 4]);
+            // the first round has no packets yet, the second both
+            assert_eq!(r[16..20], [0; 4]);
+            assert_eq!(r[20..24], [0x11, 0x22, 0, 0]);
+        }
+    }
+
+    // A ROM that answers the adapter's first BYTES bytes from its reply
+    // table, on the external clock, and keeps what came in at 0xFF80
+    fn player_rom(player: usize) -> Vec<u8> {
+        let mut code = vec![
+            0x21,
+            0x70,
+            0x01, // LD HL,0x0170
+            0x0E,
+            0x80, // LD C,0x80
+            0x2A, // LD A,(HL+)
+            0xE0,
+            0x01, // LDH (SB),A
+            0x3E,
+            0x80, // LD A,0x80
+            0xE0,
+            0x02, // LDH (SC),A
+            0xF0,
+            0x02, // LDH A,(SC)
+            0xE6,
+            0x80, // AND 0x80
+            0x20,
+            0xFA, // JR NZ,-6
+            0xF0,
+            0x01, // LDH A,(SB)
+            0xE2, // LDH (C),A
+            0x0C, // INC C
+            0x79, // LD A,C
+            0xFE,
+            0x80 + BYTES as u8, // CP 0x80 + BYTES
+            0x20,
+            0xEA, // JR NZ,-22
+            0x18,
+            0xFE, // JR -2
+        ];
+        code.resize(TABLE, 0);
+        code.extend((0..BYTES).map(|n| reply(player, n)));
+        code
+    }
+
+    #[test]
+    fn test_players_ping_and_transmission() {
+        let roms: Vec<_> = (0..2)
+            .map(|player| write_test_rom(&format!("dmg07-{player}"), &player_rom(player)))
+            .collect();
+        let players = roms
+            .iter()
+            .map(|rom| Box::new(test_gameboy(rom, Model::default())))
+            .collect();
+        let mut adapter = FourPlayerAdapter::new(players);
+
+        // both answer the pings, until they've stopped listening and the
+        // adapter goes back to pinging nobody
+        let connected: Vec<u8> = (0..10)
+            .map(|_| {
+                assert!(adapter.run_frame());
+                adapter.connected()
+            })
+            .collect();
+        assert!(connected.contains(&0b11));
+
+        let received: Vec<Vec<u8>> = adapter
+            .players
+            .iter()
+            .map(|gb| (0..BYTES as u16).map(|i| gb.peek(0xFF80 + i)).collect())
+            .collect();
+        assert_eq!(received[0][..4], [0xFE, 0x01, 0x01, 0x01]);
+        assert_eq!(received[1][4..8], [0xFE, 0x32, 0x32, 0x32]);
+        for r in &received {
+            assert_eq!(r[12..16], [0xCC; 4]);
+            assert_eq!(r[16..20], [0; 4]);
+            assert_eq!(r[20..24], [0x11, 0x22, 0, 0]);
+        }
+
+        for rom in roms {
+            fs::remove_file(rom).unwrap();
+        }
+    }
+}
@@ -1,32 +1,168 @@
 use std::{
-    cell::RefCell,
-    path::Path,
-    rc::Rc,
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
     thread::sleep,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use log::debug;
+use log::{debug, info, warn};
 
 use crate::{
-    cartridge::create_cartridge, cpu::CPU, display::GbDisplay, memory::MemoryBus, ppu::PPU,
+    bess,
+    capture::Capture,
+    cartridge::create_cartridge_from_rom,
+    cpu::CPU,
+    display::{Controls, GbDisplay, SCREEN_HEIGHT_PIXELS, SCREEN_WIDTH_PIXELS},
+    hash::crc32,
+    hooks::Hooks,
+    image::Image,
+    joypad::Buttons,
+    memory::MemoryBus,
+    model::Model,
+    movie::{Movie, MovieError, MovieFrame},
+    ppu::Rgb,
+    rewind::{self, Rewind},
+    savestate::{SaveState, StateError, StateWriter, STATE_MAGIC},
+    serial::SerialDevice,
+    sgb::{SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH},
 };
 
-pub static mut DUMP_INFO_TICK: bool = false;
-
 const CLOCK_SPEED_HZ: f32 = 4.194304e6;
+// 154 lines of 456 dots
+pub const M_TICKS_PER_FRAME: usize = 70224;
 const DESIRED_RENDER_FPS: f32 = 30.0;
+const HEADER_CHECKSUM_ADDRESS: u16 = 0x014D;
+const CGB_FLAG_ADDRESS: u16 = 0x0143;
+const SGB_FLAG_ADDRESS: u16 = 0x0146;
+const OLD_LICENSEE_ADDRESS: u16 = 0x014B;
+
+// the steps Speed::faster() and slower() go through
+const SPEED_STEPS: [f32; 6] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+
+// How fast run() goes compared to the real hardware
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    // clamped to 0.25 to 8 times the real speed
+    Multiplier(f32),
+    // as fast as the host can go
+    Uncapped,
+}
+
+impl Speed {
+    pub const MIN: f32 = 0.25;
+    pub const MAX: f32 = 8.0;
+
+    // real time per emulated frame, None when uncapped
+    pub fn frame_duration(self) -> Option<Duration> {
+        match self {
+            Speed::Multiplier(multiplier) => Some(Duration::from_secs_f32(
+                M_TICKS_PER_FRAME as f32 / CLOCK_SPEED_HZ / multiplier.clamp(Self::MIN, Self::MAX),
+            )),
+            Speed::Uncapped => None,
+        }
+    }
+
+    // the next step up from 0.25x to 8x, then uncapped
+    pub fn faster(self) -> Self {
+        match self {
+            Speed::Multiplier(multiplier) => SPEED_STEPS
+                .into_iter()
+                .find(|&step| step > multiplier)
+                .map_or(Speed::Uncapped, Speed::Multiplier),
+            Speed::Uncapped => Speed::Uncapped,
+        }
+    }
+
+    pub fn slower(self) -> Self {
+        match self {
+            Speed::Multiplier(multiplier) => Speed::Multiplier(
+                SPEED_STEPS
+                    .into_iter()
+                    .rev()
+                    .find(|&step| step < multiplier)
+                    .unwrap_or(Self::MIN),
+            ),
+            Speed::Uncapped => Speed::Multiplier(Self::MAX),
+        }
+    }
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Speed::Multiplier(1.0)
+    }
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    // a multiplier like 0.5 or 2, or "uncapped"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("uncapped") {
+            return Ok(Speed::Uncapped);
+        }
+
+        match s.trim_end_matches('x').parse::<f32>() {
+            Ok(multiplier) if (Self::MIN..=Self::MAX).contains(&multiplier) => {
+                Ok(Speed::Multiplier(multiplier))
+            }
+            _ => Err(format!(
+                "speed must be from {} to {}, or uncapped",
+                Self::MIN,
+                Self::MAX
+            )),
+        }
+    }
+}
 
 pub struct GbOptions {
-    pub limit_speed: bool,
+    pub speed: Speed,
     pub render: bool,
+
+    // start straight at 0x0100 with the post-boot register state instead
+    // of running the boot ROM. No boot ROM file is needed in this mode.
+    pub skip_boot: bool,
+    // defaults to the model's boot ROM under resources/
+    pub boot_rom_path: Option<PathBuf>,
+
+    pub model: Model,
+
+    // window size multiplier, rounded down to 1, 2, 4 or 8
+    pub scale: usize,
+
+    // directory for battery backed cartridge RAM (<rom name>.sav). RAM
+    // isn't kept between runs without one.
+    pub save_dir: Option<PathBuf>,
+
+    // keep rewind_seconds of history for rewind(), with a snapshot every
+    // rewind_interval frames, in at most rewind_budget bytes. Off when
+    // rewind_seconds is 0.
+    pub rewind_seconds: usize,
+    pub rewind_interval: usize,
+    pub rewind_budget: usize,
+
+    // where the screenshot hotkey writes its PNGs
+    pub screenshot_dir: PathBuf,
+    // where the video capture hotkey writes its GIFs
+    pub capture_dir: PathBuf,
 }
 
 impl Default for GbOptions {
     fn default() -> Self {
         Self {
-            limit_speed: true,
+            speed: Speed::default(),
             render: true,
+            skip_boot: false,
+            boot_rom_path: None,
+            model: Model::default(),
+            scale: 1,
+            save_dir: None,
+            rewind_seconds: 0,
+            rewind_interval: 1,
+            rewind_budget: rewind::DEFAULT_BUDGET,
+            screenshot_dir: PathBuf::from("."),
+            capture_dir: PathBuf::from("."),
         }
     }
 }
@@ -34,10 +170,31 @@ impl Default for GbOptions {
 pub struct Gameboy {
     bus: MemoryBus,
     cpu: CPU,
-    display: Option<GbDisplay>,
-    ppu: Rc<RefCell<PPU>>,
     running: bool,
     options: GbOptions,
+    save_path: Option<PathBuf>,
+    // cartridge title to global checksum, to tell save states of other
+    // ROMs apart
+    rom_header: [u8; bess::HEADER_SIZE],
+    rom_crc32: u32,
+    rewind: Option<Rewind>,
+    // the movie being recorded
+    movie: Option<Movie>,
+    // frames finished since power on, and whether the game read the
+    // joypad in the last one
+    frame_count: u64,
+    lagged: bool,
+    // video being captured, frame by frame
+    capture: Option<Capture>,
+    // run() stops emulating while paused
+    paused: bool,
+
+    cpu_ticker: usize,
+
+    // the last finished frame, as 0x00RRGGBB pixels
+    framebuffer: Vec<Rgb>,
+    framebuffer_width: usize,
+    framebuffer_height: usize,
 }
 
 impl Gameboy {
@@ -46,82 +203,919 @@ impl Gameboy {
         cartridge_path: Option<&Path>,
         options: Option<GbOptions>,
     ) -> Self {
-        let options = options.unwrap_or(GbOptions::default());
-        let display = if options.render {
-            let d = GbDisplay::start();
+        let mut options = options.unwrap_or(GbOptions::default());
 
-            if d.is_err() {
-                panic!("Failed to start display!");
-            }
+        let rom = cartridge_path.map(|cp| {
+            fs::read(cp).unwrap_or_else(|e| panic!("Failed to load cartridge at {:?}: {}", cp, e))
+        });
+        let rom_crc32 = rom.as_deref().map_or(0, crc32);
+        let cartridge = rom.map(create_cartridge_from_rom);
 
-            Some(d.unwrap())
+        let mut bus = if cartridge.is_some() && !options.skip_boot {
+            let boot_rom_path = options
+                .boot_rom_path
+                .clone()
+                .unwrap_or_else(|| options.model.default_boot_rom_path());
+
+            MemoryBus::new_and_load_bios(cartridge, options.model, &boot_rom_path)
         } else {
-            None
+            MemoryBus::new_and_empty(cartridge)
         };
 
-        let ppu = Rc::new(RefCell::new(PPU::new()));
+        // CGB hardware only runs in CGB mode if the header says the
+        // cartridge supports it (0x80: CGB enhanced, 0xC0: CGB only)
+        if options.model == Model::Cgb {
+            if bus.read_byte(CGB_FLAG_ADDRESS) & 0x80 != 0 {
+                bus.set_cgb_mode(true);
+            } else {
+                options.model = Model::CgbDmgMode;
+            }
+        }
+
+        // the SGB only takes packets from cartridges that declare support
+        if options.model.is_sgb()
+            && bus.read_byte(SGB_FLAG_ADDRESS) == 0x03
+            && bus.read_byte(OLD_LICENSEE_ADDRESS) == 0x33
+        {
+            bus.enable_sgb();
+        }
+
+        bus.ppu_mut().set_dmg_palettes(options.model.dmg_palettes());
 
-        let bus = if let Some(cp) = cartridge_path {
-            MemoryBus::new_and_load_bios(Some(create_cartridge(cp)), ppu.clone())
+        let (width, height) = if bus.sgb().is_some() {
+            (SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT)
         } else {
-            MemoryBus::new_and_empty(None, ppu.clone())
+            (SCREEN_WIDTH_PIXELS, SCREEN_HEIGHT_PIXELS)
         };
 
-        Self {
+        let save_path = options
+            .save_dir
+            .as_ref()
+            .zip(cartridge_path.and_then(|cp| cp.file_stem()))
+            .map(|(dir, stem)| dir.join(stem).with_extension("sav"));
+
+        if let Some(path) = save_path.as_ref().filter(|path| path.exists()) {
+            match fs::read(path) {
+                Ok(data) => bus.cartridge_mut().load_battery_ram(&data),
+                Err(e) => warn!("Failed to load save file {:?}: {}", path, e),
+            }
+        }
+
+        let mut rom_header = [0; bess::HEADER_SIZE];
+        for (address, byte) in (bess::HEADER_START..).zip(rom_header.iter_mut()) {
+            *byte = bus.cartridge().read_byte(address);
+        }
+
+        let rewind = (options.rewind_seconds > 0).then(|| {
+            Rewind::new(
+                options.rewind_seconds,
+                options.rewind_interval,
+                options.rewind_budget,
+            )
+        });
+
+        let mut gb = Self {
             bus,
             cpu: CPU::new(debug_mode),
             running: false,
-            ppu,
-            display,
             options,
-        }
+            save_path,
+            rom_header,
+            rom_crc32,
+            rewind,
+            movie: None,
+            frame_count: 0,
+            lagged: false,
+            capture: None,
+            paused: false,
+            cpu_ticker: 0,
+            framebuffer: vec![0; width * height],
+            framebuffer_width: width,
+            framebuffer_height: height,
+        };
+        gb.power_on();
+
+        gb
     }
 
-    pub fn boot(&mut self) {
+    // Put the CPU at the start of the boot ROM, or straight at the
+    // cartridge entry point when skipping the boot ROM, so a fresh Gameboy
+    // is ready for run_frame()
+    fn power_on(&mut self) {
         self.running = true;
+        self.cpu_ticker = 0;
         self.cpu.reset();
 
+        if self.options.skip_boot {
+            self.bus.skip_boot(self.options.model);
+            self.cpu.skip_boot(
+                self.options.model,
+                self.bus.read_byte(HEADER_CHECKSUM_ADDRESS),
+            );
+        }
+    }
+
+    // Run in a window (or headless, if rendering is off) until the window
+    // is closed or the CPU stops
+    pub fn boot(&mut self) {
         self.run();
+
+        if let Err(e) = self.save_battery() {
+            warn!("Failed to write save file: {}", e);
+        }
+    }
+
+    // Write battery backed cartridge RAM to the save directory, if there is
+    // one and the cartridge has a battery
+    pub fn save_battery(&self) -> io::Result<()> {
+        let (Some(path), Some(ram)) = (&self.save_path, self.bus.cartridge().battery_ram()) else {
+            return Ok(());
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, ram)?;
+        info!("Saved cartridge RAM to {:?}", path);
+
+        Ok(())
+    }
+
+    // Snapshot the whole machine, see savestate for the format, followed
+    // by the BESS blocks other emulators can load. Hooks, options and the
+    // boot ROM are not part of the state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut data = self.native_state();
+        bess::append(
+            &mut data,
+            &self.cpu,
+            &self.bus,
+            self.options.model,
+            &self.rom_header,
+        );
+
+        data
+    }
+
+    fn native_state(&self) -> Vec<u8> {
+        self.write_native_state(true)
+    }
+
+    // Rewind snapshots leave the screens out, as they change almost
+    // completely every frame in a scrolling game, and redraw them instead
+    fn rewind_state(&self) -> Vec<u8> {
+        self.write_native_state(false)
+    }
+
+    fn write_native_state(&self, screens: bool) -> Vec<u8> {
+        let mut w = StateWriter::new();
+
+        w.section(b"ROM ", |w| w.write_bytes(self.rom_id()));
+        w.section(b"CPU ", |w| self.cpu.save_state(w));
+        w.section(b"BUS ", |w| self.bus.save_state(w));
+        w.section(b"PPU ", |w| {
+            self.bus.ppu().save_state(w);
+            if screens {
+                self.bus.ppu().save_screen(w);
+            }
+        });
+        w.section(b"CART", |w| self.bus.cartridge().save_state(w));
+        if let Some(sgb) = self.bus.sgb() {
+            w.section(b"SGB ", |w| {
+                sgb.save_state(w);
+                if screens {
+                    sgb.save_screen(w);
+                }
+            });
+        }
+        w.section(b"GB  ", |w| {
+            w.write_u8(self.cpu_ticker as u8);
+            w.write_bool(self.running);
+            if screens {
+                for pixel in &self.framebuffer {
+                    w.write_u32(*pixel);
+                }
+            }
+        });
+
+        w.finish()
+    }
+
+    // Restore a state made with the same ROM, either by save_state() or
+    // by another emulator with BESS support. A state that fails to load
+    // part way leaves the machine in a mixed state, so reset() or load
+    // another state after an error.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        if !data.starts_with(STATE_MAGIC) && bess::has_footer(data) {
+            bess::load(
+                data,
+                &mut self.cpu,
+                &mut self.bus,
+                self.options.model,
+                &self.rom_header,
+            )?;
+            self.cpu_ticker = 0;
+            self.running = true;
+        } else {
+            self.load_native_state(data, true)?;
+        }
+
+        // recordings from here on have to start from a state
+        self.frame_count = self.frame_count.max(1);
+
+        Ok(())
+    }
+
+    fn load_native_state(&mut self, data: &[u8], screens: bool) -> Result<(), StateError> {
+        let (state, _) = SaveState::parse(data)?;
+
+        if state.section(b"ROM ")?.read_bytes(3)? != self.rom_id() {
+            return Err(StateError::WrongRom);
+        }
+        if state.find_section(b"SGB ").is_some() != self.bus.sgb().is_some() {
+            return Err(StateError::Invalid("SGB state doesn't match the model"));
+        }
+
+        self.cpu.load_state(&mut state.section(b"CPU ")?)?;
+        self.bus.load_state(&mut state.section(b"BUS ")?)?;
+        let mut r = state.section(b"PPU ")?;
+        self.bus.ppu_mut().load_state(&mut r)?;
+        if screens {
+            self.bus.ppu_mut().load_screen(&mut r)?;
+        }
+        self.bus
+            .cartridge_mut()
+            .load_state(&mut state.section(b"CART")?)?;
+        if let Some(sgb) = self.bus.sgb_mut() {
+            let mut r = state.section(b"SGB ")?;
+            sgb.load_state(&mut r)?;
+            if screens {
+                sgb.load_screen(&mut r)?;
+            }
+        }
+
+        let mut r = state.section(b"GB  ")?;
+        self.cpu_ticker = r.read_u8()? as usize;
+        self.running = r.read_bool()?;
+        if screens {
+            for pixel in self.framebuffer.iter_mut() {
+                *pixel = r.read_u32()?;
+            }
+        } else {
+            self.bus.redraw_screen();
+            self.update_framebuffer();
+        }
+
+        Ok(())
+    }
+
+    // Go back to the snapshot nearest to `frames` frames ago, or as far
+    // as the history goes, and return how many frames back that was.
+    // Snapshots are GbOptions::rewind_interval frames apart, and those
+    // newer than the one returned to are dropped. Returns 0 if rewind is
+    // off or there's no history yet.
+    pub fn rewind(&mut self, frames: usize) -> usize {
+        let Some((state, rewound)) = self
+            .rewind
+            .as_mut()
+            .and_then(|rewind| rewind.rewind(frames))
+            .map(|(state, rewound)| (state.to_vec(), rewound))
+        else {
+            return 0;
+        };
+
+        self.load_native_state(&state, false)
+            .expect("Rewind snapshots are made by this machine");
+
+        // the rewound frames are no longer part of the recording
+        if let Some(movie) = self.movie.as_mut() {
+            movie
+                .frames
+                .truncate(movie.frames.len().saturating_sub(rewound));
+        }
+
+        rewound
+    }
+
+    // header and global checksums
+    fn rom_id(&self) -> &[u8] {
+        let start = (HEADER_CHECKSUM_ADDRESS - bess::HEADER_START) as usize;
+        &self.rom_header[start..]
+    }
+
+    // Emulate up to the start of the next V-Blank, i.e. exactly one frame
+    // once running. Returns false if the CPU has stopped.
+    pub fn run_frame(&mut self) -> bool {
+        while self.running {
+            if self.step() {
+                break;
+            }
+        }
+
+        self.running
+    }
+
+    // Emulate the given number of master clock ticks (dots, 4 per normal
+    // speed M-cycle). Returns false if the CPU has stopped.
+    pub fn run_cycles(&mut self, m_ticks: usize) -> bool {
+        for _ in 0..m_ticks {
+            if !self.running {
+                break;
+            }
+            self.step();
+        }
+
+        self.running
+    }
+
+    // The last finished frame, row by row as 0x00RRGGBB pixels, with its
+    // size given by framebuffer_size()
+    pub fn framebuffer(&self) -> &[Rgb] {
+        &self.framebuffer
+    }
+
+    // width and height of the frame buffer: 160x144, or 256x224 for the
+    // SGB with its border
+    pub fn framebuffer_size(&self) -> (usize, usize) {
+        (self.framebuffer_width, self.framebuffer_height)
+    }
+
+    // the last finished frame, in the colours it was shown in
+    pub fn screenshot(&self) -> Image {
+        Image::new(
+            self.framebuffer_width,
+            self.framebuffer_height,
+            self.framebuffer.clone(),
+        )
+    }
+
+    // Write a screenshot to GbOptions::screenshot_dir, named after the
+    // time it was taken, and return its path
+    pub fn save_screenshot(&self) -> io::Result<PathBuf> {
+        let path = timestamped_path(&self.options.screenshot_dir, "png");
+        self.screenshot().save_png(&path)?;
+
+        Ok(path)
+    }
+
+    // Start writing every finished frame to a .gif or .y4m file, ending
+    // any capture already going
+    pub fn start_capture(&mut self, path: &Path) -> io::Result<()> {
+        self.stop_capture()?;
+        self.capture = Some(Capture::create(
+            path,
+            self.framebuffer_width,
+            self.framebuffer_height,
+        )?);
+
+        Ok(())
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    // finish the capture's files, if there is one
+    pub fn stop_capture(&mut self) -> io::Result<()> {
+        match self.capture.take() {
+            Some(capture) => capture.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.bus.set_buttons(buttons);
+    }
+
+    // the CPU's registers, for debuggers and test harnesses
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    // read memory as the CPU would see it, without calling hooks
+    pub fn peek(&self, address: u16) -> u8 {
+        self.bus.peek(address)
+    }
+
+    // plug a device into the link port, returning the one that was there
+    pub fn set_serial_device(&mut self, device: Box<dyn SerialDevice>) -> Box<dyn SerialDevice> {
+        self.bus.set_serial_device(device)
+    }
+
+    // CRC-32 of the ROM file, 0 without a cartridge
+    pub fn rom_crc32(&self) -> u32 {
+        self.rom_crc32
+    }
+
+    // CRC-32 of the last finished frame's pixels, as little endian
+    // 0x00RRGGBB words
+    pub fn frame_hash(&self) -> u32 {
+        let bytes: Vec<u8> = self
+            .framebuffer
+            .iter()
+            .flat_map(|pixel| pixel.to_le_bytes())
+            .collect();
+
+        crc32(&bytes)
+    }
+
+    // whether the game didn't read the joypad during the last frame
+    pub fn lagged(&self) -> bool {
+        self.lagged
+    }
+
+    // Start recording the buttons of each frame into a movie, starting
+    // from power on if nothing has run yet and from a save state
+    // otherwise. Buttons should only change between frames.
+    pub fn start_recording(&mut self) {
+        let start_state = (self.frame_count > 0).then(|| self.save_state());
+
+        self.movie = Some(Movie::new(
+            self.rom_crc32,
+            self.options.model,
+            (!self.options.skip_boot).then(|| crc32(self.bus.boot_rom())),
+            start_state,
+        ));
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.movie.is_some()
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Movie> {
+        self.movie.take()
+    }
+
+    // Play a whole movie. The Gameboy has to be set up with the movie's
+    // model and boot setting, and not have run yet if the movie starts
+    // from power on. Fails if a frame's lag doesn't match the recording.
+    pub fn play_movie(&mut self, movie: &Movie) -> Result<(), MovieError> {
+        if movie.rom_crc32 != self.rom_crc32 {
+            return Err(MovieError::WrongRom);
+        }
+        if movie.model.family() != self.options.model.family()
+            || movie.boot_rom_crc32.is_none() != self.options.skip_boot
+        {
+            return Err(MovieError::WrongSetup);
+        }
+        if movie
+            .boot_rom_crc32
+            .is_some_and(|crc| crc != crc32(self.bus.boot_rom()))
+        {
+            return Err(MovieError::WrongBootRom);
+        }
+
+        match &movie.start_state {
+            Some(state) => self.load_state(state)?,
+            None if self.frame_count > 0 => return Err(MovieError::NotAtPowerOn),
+            None => {}
+        }
+
+        for (i, frame) in movie.frames.iter().enumerate() {
+            self.set_buttons(frame.buttons);
+            self.run_frame();
+
+            if self.lagged != frame.lag {
+                return Err(MovieError::Desync { frame: i });
+            }
+        }
+
+        Ok(())
+    }
+
+    // false once the CPU has stopped or the window was closed
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.options.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.options.speed = speed;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    // Pause after emulating one more frame. Returns false if the CPU has
+    // stopped.
+    pub fn advance_frame(&mut self) -> bool {
+        self.paused = true;
+        self.run_frame()
+    }
+
+    // register callbacks to observe instructions, memory accesses,
+    // interrupts, PPU mode changes and finished frames
+    pub fn hooks_mut(&mut self) -> &mut Hooks {
+        self.bus.hooks_mut()
     }
 
     fn run(&mut self) {
-        let mut cpu_ticker = 0;
-        let m_ticks_per_cpu_step = 4; // or 2 if in cpu double speed mode
+        // the window stays with the loop rather than the Gameboy, which
+        // keeps the Gameboy free to move between threads
+        let mut display = if self.options.render {
+            let d = GbDisplay::start(
+                self.framebuffer_width,
+                self.framebuffer_height,
+                self.options.scale,
+            );
 
-        let mut last_m_tick = Instant::now();
-        let mut last_render = Instant::now();
+            if d.is_err() {
+                panic!("Failed to start display!");
+            }
+
+            Some(d.unwrap())
+        } else {
+            None
+        };
 
-        let m_tick_duration = Duration::from_secs_f32(1.0 / CLOCK_SPEED_HZ);
         let render_tick_duration = Duration::from_secs_f32(1.0 / DESIRED_RENDER_FPS);
 
+        let mut next_frame = Instant::now();
+        let mut last_render = Instant::now();
+        let mut controls = Controls::default();
+
         while self.running {
-            // each loop represents a single tick of the Master clock, or M tick
-            debug!("M tick");
+            // rewind one snapshot per frame while the key is held
+            if controls.rewind {
+                self.rewind(1);
+            } else if !self.paused {
+                self.run_frame();
+            }
+
+            // Frames are only drawn at the render rate, so going faster
+            // skips frames rather than waiting on the window. There is no
+            // APU yet, so no audio to stretch or mute either.
+            if let Some(display) = display.as_mut() {
+                if Instant::now() - render_tick_duration > last_render {
+                    self.running &= display.render(
+                        &self.framebuffer,
+                        self.framebuffer_width,
+                        self.framebuffer_height,
+                    );
+                    last_render = Instant::now();
+                    debug!("Render");
+
+                    // presses are seen once per render
+                    controls = display.controls();
+                    self.apply_controls(&controls);
+                }
+
+                let buttons = display.buttons();
+                self.bus.set_buttons(buttons);
+            }
+
+            if self.paused && !controls.rewind {
+                // nothing to do until the next render
+                sleep(
+                    (last_render + render_tick_duration).saturating_duration_since(Instant::now()),
+                );
+                next_frame = Instant::now();
+                continue;
+            }
 
-            if cpu_ticker >= m_ticks_per_cpu_step {
-                cpu_ticker = 0;
+            let speed = if controls.fast_forward {
+                Speed::Uncapped
+            } else {
+                self.options.speed
+            };
+
+            if let Some(frame_duration) = speed.frame_duration() {
+                next_frame += frame_duration;
+
+                let now = Instant::now();
+                if now < next_frame {
+                    sleep(next_frame - now);
+                } else {
+                    // running behind, don't try to catch up
+                    next_frame = now;
+                }
+            } else {
+                next_frame = Instant::now();
+            }
+        }
+    }
+
+    fn apply_controls(&mut self, controls: &Controls) {
+        if controls.pause {
+            self.paused = !self.paused;
+        }
+        if controls.frame_advance {
+            self.advance_frame();
+        }
+        if controls.speed_up {
+            self.options.speed = self.options.speed.faster();
+        }
+        if controls.slow_down {
+            self.options.speed = self.options.speed.slower();
+        }
+        if controls.capture {
+            if self.is_capturing() {
+                match self.stop_capture() {
+                    Ok(()) => info!("Stopped capturing"),
+                    Err(e) => warn!("Failed to finish capture: {}", e),
+                }
+            } else {
+                let path = timestamped_path(&self.options.capture_dir, "gif");
+                match self.start_capture(&path) {
+                    Ok(()) => info!("Capturing to {:?}", path),
+                    Err(e) => warn!("Failed to start capture: {}", e),
+                }
+            }
+        }
+        if controls.screenshot {
+            match self.save_screenshot() {
+                Ok(path) => info!("Saved screenshot to {:?}", path),
+                Err(e) => warn!("Failed to save screenshot: {}", e),
+            }
+        }
+    }
+
+    // Advance everything by one tick of the master clock. Returns true if
+    // a frame was finished.
+    pub fn step(&mut self) -> bool {
+        // each step represents a single tick of the Master clock, or M tick
+        debug!("M tick");
+
+        // the PPU always runs at 4 dots per normal speed M-cycle, the
+        // CPU and timer run twice as fast in double speed mode
+        let (m_ticks_per_cpu_step, cpu_clocks_per_m_tick) = if self.bus.double_speed() {
+            (2, 2)
+        } else {
+            (4, 1)
+        };
+
+        if self.cpu_ticker >= m_ticks_per_cpu_step {
+            self.cpu_ticker = 0;
+
+            if !self.bus.dma_stalling_cpu() {
                 self.running &= self.cpu.step(&mut self.bus);
             }
-            cpu_ticker += 1;
+        }
+        self.cpu_ticker += 1;
 
-            self.running &= self.ppu.borrow_mut().step(&mut self.bus);
+        let frame_finished = self.bus.tick(cpu_clocks_per_m_tick);
+        if frame_finished {
+            self.update_framebuffer();
+            self.bus.hooks_mut().frame_complete(&self.framebuffer);
 
-            if self.options.limit_speed && Instant::now() - m_tick_duration < last_m_tick {
-                sleep(m_tick_duration - (Instant::now() - last_m_tick));
-                last_m_tick = Instant::now();
+            if let Some(capture) = self.capture.as_mut() {
+                if let Err(e) = capture.frame(&self.framebuffer) {
+                    warn!("Stopped capturing after failing to write: {}", e);
+                    self.capture = None;
+                }
             }
 
-            if self.options.render && Instant::now() - render_tick_duration > last_render {
-                self.running &= self
-                    .display
-                    .as_mut()
-                    .unwrap()
-                    .render(&self.bus, &self.ppu.borrow_mut());
-                last_render = Instant::now();
-                debug!("Render");
+            self.frame_count += 1;
+            self.lagged = !self.bus.take_joypad_polled();
+            if let Some(movie) = self.movie.as_mut() {
+                movie.frames.push(MovieFrame {
+                    buttons: self.bus.buttons(),
+                    lag: self.lagged,
+                });
             }
 
-            unsafe { DUMP_INFO_TICK = false };
+            if self.rewind.as_mut().is_some_and(Rewind::frame_finished) {
+                let state = self.rewind_state();
+                if let Some(rewind) = self.rewind.as_mut() {
+                    rewind.push(state);
+                }
+            }
+        }
+
+        frame_finished
+    }
+
+    // Copy out the finished frame: the SGB picture with its border, or
+    // just the LCD
+    fn update_framebuffer(&mut self) {
+        if let Some(sgb) = self.bus.sgb() {
+            self.framebuffer = sgb.frame();
+        } else if self.bus.registers.LCDC.lcd_display_enable {
+            self.framebuffer
+                .copy_from_slice(self.bus.ppu().get_screen_buffer().as_flattened());
+        } else {
+            self.framebuffer.fill(0);
         }
     }
 }
+
+// a file in dir named after the current time
+fn timestamped_path(dir: &Path, extension: &str) -> PathBuf {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    dir.join(format!(
+        "gbars-{}-{:03}.{}",
+        time.as_secs(),
+        time.subsec_millis(),
+        extension
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::display::{SCREEN_HEIGHT_PIXELS, SCREEN_WIDTH_PIXELS};
+
+    use crate::savestate::StateError;
+
+    use crate::joypad::Buttons;
+    use crate::movie::{Movie, MovieError};
+
+    use crate::{model::Model, testing::test_options};
+
+    use super::{Gameboy, GbOptions, Speed};
+
+    fn gameboy() -> Gameboy {
+        Gameboy::new(false, None, Some(test_options(Model::default())))
+    }
+
+    #[test]
+    fn test_gameboy_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Gameboy>();
+    }
+
+    #[test]
+    fn test_run_frame_headless() {
+        let mut gb = gameboy();
+
+        assert!(gb.run_frame());
+        assert!(gb.run_frame());
+        assert_eq!(
+            gb.framebuffer_size(),
+            (SCREEN_WIDTH_PIXELS, SCREEN_HEIGHT_PIXELS)
+        );
+        assert_eq!(
+            gb.framebuffer().len(),
+            SCREEN_WIDTH_PIXELS * SCREEN_HEIGHT_PIXELS
+        );
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let mut gb = gameboy();
+        for _ in 0..10 {
+            gb.run_frame();
+        }
+
+        let state = gb.save_state();
+        for _ in 0..10 {
+            gb.run_frame();
+        }
+        let expected = gb.save_state();
+
+        gb.load_state(&state).unwrap();
+        for _ in 0..10 {
+            gb.run_frame();
+        }
+        assert_eq!(gb.save_state(), expected);
+
+        assert_eq!(gb.load_state(b"not a state"), Err(StateError::BadMagic));
+    }
+
+    #[test]
+    fn test_speed_steps() {
+        assert_eq!(Speed::default().faster(), Speed::Multiplier(2.0));
+        assert_eq!(Speed::Multiplier(8.0).faster(), Speed::Uncapped);
+        assert_eq!(Speed::Uncapped.slower(), Speed::Multiplier(8.0));
+        assert_eq!(Speed::Multiplier(0.5).slower(), Speed::Multiplier(0.25));
+        assert_eq!(Speed::Multiplier(0.25).slower(), Speed::Multiplier(0.25));
+
+        let real_time = Speed::default().frame_duration().unwrap();
+        let double = "2x".parse::<Speed>().unwrap().frame_duration().unwrap();
+        assert!((real_time.as_secs_f32() / double.as_secs_f32() - 2.0).abs() < 1e-3);
+        assert_eq!("uncapped".parse(), Ok(Speed::Uncapped));
+        assert!("16".parse::<Speed>().is_err());
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut gb = Gameboy::new(
+            false,
+            None,
+            Some(GbOptions {
+                rewind_seconds: 1,
+                ..test_options(Model::default())
+            }),
+        );
+        // stripes in tile 0, which fills the screen, and different ones
+        // later on
+        let stripes = |gb: &mut Gameboy, byte| {
+            for address in 0x8000..0x8010 {
+                gb.bus.write_byte(address, byte);
+            }
+        };
+        stripes(&mut gb, 0xF0);
+        for _ in 0..10 {
+            gb.run_frame();
+        }
+        let state = gb.native_state();
+        stripes(&mut gb, 0x0F);
+        for _ in 0..10 {
+            gb.run_frame();
+        }
+        assert!(gb.native_state() != state);
+
+        // snapshots leave the screens out, and they're redrawn
+        assert!(gb.rewind_state().len() < state.len() / 2);
+        assert_eq!(gb.rewind(10), 10);
+        assert!(gb.native_state() == state);
+        assert_eq!(gb.rewind(100), 9);
+    }
+
+    #[test]
+    fn test_bess_round_trip() {
+        let mut gb = gameboy();
+        for _ in 0..10 {
+            gb.run_frame();
+        }
+        gb.cpu.registers_mut().set_bc(0x1234);
+        gb.bus.write_byte(0xC123, 0x56);
+        gb.bus.write_byte(0xFF80, 0x78);
+
+        // hide the native state, leaving only the BESS blocks to load
+        let mut state = gb.save_state();
+        state[0] = 0;
+
+        let mut loaded = gameboy();
+        loaded.load_state(&state).unwrap();
+
+        assert_eq!(loaded.cpu.pc(), gb.cpu.pc());
+        assert_eq!(loaded.cpu.sp(), gb.cpu.sp());
+        assert_eq!(loaded.cpu.registers().get_bc(), 0x1234);
+        assert_eq!(loaded.cpu.registers().get_af(), gb.cpu.registers().get_af());
+        for address in (0x8000..0xA000).chain(0xC000..0xE000).chain(0xFF80..0xFFFF) {
+            assert_eq!(loaded.bus.peek(address), gb.bus.peek(address));
+        }
+        assert_eq!(loaded.bus.registers.LY, gb.bus.registers.LY);
+    }
+
+    #[test]
+    fn test_movie_playback() {
+        let mut gb = gameboy();
+        for _ in 0..5 {
+            gb.run_frame();
+        }
+
+        gb.start_recording();
+        for i in 0..10 {
+            gb.set_buttons(Buttons {
+                a: i % 2 == 0,
+                left: i > 5,
+                ..Default::default()
+            });
+            gb.run_frame();
+        }
+        let movie = Movie::parse(&gb.stop_recording().unwrap().to_text()).unwrap();
+        assert_eq!(movie.frames.len(), 10);
+        assert!(movie.start_state.is_some());
+
+        let mut played = gameboy();
+        played.play_movie(&movie).unwrap();
+        assert_eq!(played.frame_hash(), gb.frame_hash());
+        assert!(played.native_state() == gb.native_state());
+
+        let mut wrong_rom = movie.clone();
+        wrong_rom.rom_crc32 ^= 1;
+        assert_eq!(played.play_movie(&wrong_rom), Err(MovieError::WrongRom));
+
+        let mut with_boot_rom = movie.clone();
+        with_boot_rom.boot_rom_crc32 = Some(0);
+        assert_eq!(
+            played.play_movie(&with_boot_rom),
+            Err(MovieError::WrongSetup)
+        );
+    }
+
+    #[test]
+    fn test_movie_boot_rom() {
+        let mut gb = Gameboy::new(
+            false,
+            None,
+            Some(GbOptions {
+                skip_boot: false,
+                ..test_options(Model::default())
+            }),
+        );
+        gb.start_recording();
+        gb.run_frame();
+        let movie = gb.stop_recording().unwrap();
+        assert!(movie.boot_rom_crc32.is_some());
+
+        let mut other_boot_rom = movie.clone();
+        other_boot_rom.boot_rom_crc32 = movie.boot_rom_crc32.map(|crc| crc ^ 1);
+        assert_eq!(
+            gb.play_movie(&other_boot_rom),
+            Err(MovieError::WrongBootRom)
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
 pub enum DisplayRegisters {
     SCROLLX,
     SCROLLY,
@@ -32,7 +34,7 @@ impl DisplayRegisters {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LCDC {
     pub lcd_display_enable: bool,             // (0=Off, 1=On)
     pub window_tile_map_display_select: bool, // (0=9800-9BFF, 1=9C00-9FFF)
@@ -80,7 +82,43 @@ impl std::convert::From<u8> for LCDC {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank,
+    LCD,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+impl Interrupt {
+    // in priority order, highest first
+    pub const ALL: [Interrupt; 5] = [
+        Interrupt::VBlank,
+        Interrupt::LCD,
+        Interrupt::Timer,
+        Interrupt::Serial,
+        Interrupt::Joypad,
+    ];
+
+    // address of the handler the CPU jumps to
+    pub fn vector(&self) -> u16 {
+        0x40 + 8 * self.bit().trailing_zeros() as u16
+    }
+
+    // bit of the interrupt in IE and IF
+    pub fn bit(&self) -> u8 {
+        match self {
+            Interrupt::VBlank => 1,
+            Interrupt::LCD => 1 << 1,
+            Interrupt::Timer => 1 << 2,
+            Interrupt::Serial => 1 << 3,
+            Interrupt::Joypad => 1 << 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct IE {
     joypad: bool,
     serial: bool,
@@ -111,14 +149,14 @@ impl std::convert::From<u8> for IE {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct STAT {
     pub lyc_int_select: bool,
     pub mode_2_int_select: bool,
     pub mode_1_int_select: bool,
     pub mode_0_int_select: bool,
     pub lyc_eq_ly: bool,
-    pub ppu_mode: bool,
+    pub ppu_mode: u8,
 }
 
 impl STAT {
@@ -129,29 +167,47 @@ impl STAT {
             mode_1_int_select: false,
             mode_0_int_select: false,
             lyc_eq_ly: false,
-            ppu_mode: false,
+            ppu_mode: 0,
         }
     }
     pub fn to_byte(&self) -> u8 {
-        ((self.lyc_int_select as u8) << 6)
-            | ((self.lyc_int_select as u8) << 5)
-            | ((self.mode_2_int_select as u8) << 4)
-            | ((self.mode_1_int_select as u8) << 3)
-            | ((self.mode_0_int_select as u8) << 2)
-            | (self.ppu_mode as u8)
+        0x80 | ((self.lyc_int_select as u8) << 6)
+            | ((self.mode_2_int_select as u8) << 5)
+            | ((self.mode_1_int_select as u8) << 4)
+            | ((self.mode_0_int_select as u8) << 3)
+            | ((self.lyc_eq_ly as u8) << 2)
+            | (self.ppu_mode & 0b11)
     }
 
+    // the LY=LYC and mode bits are read only
     pub fn write_byte(&mut self, val: u8) {
         self.lyc_int_select = val >> 6 & 1 == 1;
         self.mode_2_int_select = val >> 5 & 1 == 1;
         self.mode_1_int_select = val >> 4 & 1 == 1;
         self.mode_0_int_select = val >> 3 & 1 == 1;
-        self.lyc_eq_ly = val >> 2 & 1 == 1;
-        self.ppu_mode = val & 0b11 > 1;
     }
 }
 
-#[derive(Debug)]
+// CGB speed switch register. Writing bit 0 arms a switch which happens on
+// the next STOP instruction; bit 7 reports the current speed.
+#[derive(Debug, Default, Clone)]
+pub struct KEY1 {
+    pub switch_armed: bool,
+    pub double_speed: bool,
+}
+
+impl KEY1 {
+    pub fn to_byte(&self) -> u8 {
+        ((self.double_speed as u8) << 7) | 0x7E | (self.switch_armed as u8)
+    }
+
+    pub fn write_byte(&mut self, val: u8) {
+        self.switch_armed = val & 1 == 1;
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(non_snake_case)]
 pub struct HardwareRegisters {
     pub IE: IE,
     pub LCDC: LCDC,
@@ -161,6 +217,16 @@ pub struct HardwareRegisters {
     pub WX: u8,
     pub WY: u8,
     pub STAT: STAT,
+    pub BGP: u8,
+    pub OBP0: u8,
+    pub OBP1: u8,
+
+    // CGB only
+    pub KEY1: KEY1,
+    pub VBK: u8,
+    pub SVBK: u8,
+    // undocumented CGB registers at 0xFF72 - 0xFF75
+    pub undocumented: [u8; 4],
 }
 
 impl HardwareRegisters {
@@ -174,11 +240,76 @@ impl HardwareRegisters {
             LY: 0,
             WX: 0,
             WY: 0,
+            BGP: 0,
+            OBP0: 0,
+            OBP1: 0,
+            KEY1: KEY1::default(),
+            VBK: 0,
+            SVBK: 0,
+            undocumented: [0; 4],
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.IE.to_byte());
+        w.write_u8(self.LCDC.to_byte());
+        for reg in [self.SCY, self.SCX, self.LY, self.WX, self.WY] {
+            w.write_u8(reg);
+        }
+
+        let stat = &self.STAT;
+        for flag in [
+            stat.lyc_int_select,
+            stat.mode_2_int_select,
+            stat.mode_1_int_select,
+            stat.mode_0_int_select,
+            stat.lyc_eq_ly,
+        ] {
+            w.write_bool(flag);
+        }
+        w.write_u8(stat.ppu_mode);
+
+        for reg in [self.BGP, self.OBP0, self.OBP1, self.VBK, self.SVBK] {
+            w.write_u8(reg);
         }
+        w.write_bool(self.KEY1.switch_armed);
+        w.write_bool(self.KEY1.double_speed);
+        w.write_bytes(&self.undocumented);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.IE = IE::from(r.read_u8()?);
+        self.LCDC = LCDC::from(r.read_u8()?);
+        self.SCY = r.read_u8()?;
+        self.SCX = r.read_u8()?;
+        self.LY = r.read_u8()?;
+        self.WX = r.read_u8()?;
+        self.WY = r.read_u8()?;
+
+        self.STAT.lyc_int_select = r.read_bool()?;
+        self.STAT.mode_2_int_select = r.read_bool()?;
+        self.STAT.mode_1_int_select = r.read_bool()?;
+        self.STAT.mode_0_int_select = r.read_bool()?;
+        self.STAT.lyc_eq_ly = r.read_bool()?;
+        self.STAT.ppu_mode = r.read_u8()?;
+
+        self.BGP = r.read_u8()?;
+        self.OBP0 = r.read_u8()?;
+        self.OBP1 = r.read_u8()?;
+        self.VBK = r.read_u8()?;
+        self.SVBK = r.read_u8()?;
+        self.KEY1.switch_armed = r.read_bool()?;
+        self.KEY1.double_speed = r.read_bool()?;
+        r.read_into(&mut self.undocumented)?;
+
+        Ok(())
     }
 }
 
 pub enum RegisterAddresses {
+    P1,
+    SB,
+    SC,
     LCDC,
     WX,
     WY,
@@ -187,11 +318,39 @@ pub enum RegisterAddresses {
     SCY,
     SCX,
     STAT,
+    BGP,
+    OBP0,
+    OBP1,
+    BCPS,
+    BCPD,
+    OCPS,
+    OCPD,
+    DIV,
+    TIMA,
+    TMA,
+    TAC,
+    KEY1,
+    VBK,
+    HDMA1,
+    HDMA2,
+    HDMA3,
+    HDMA4,
+    HDMA5,
+    SVBK,
+    FF72,
+    FF73,
+    FF74,
+    FF75,
+    FF76,
+    FF77,
 }
 
 impl RegisterAddresses {
     pub fn address(&self) -> u16 {
         match self {
+            RegisterAddresses::P1 => 0xFF00,
+            RegisterAddresses::SB => 0xFF01,
+            RegisterAddresses::SC => 0xFF02,
             RegisterAddresses::LCDC => 0xFF40,
             RegisterAddresses::LY => 0xFF44,
             RegisterAddresses::IE => 0xFFFF,
@@ -200,11 +359,39 @@ impl RegisterAddresses {
             RegisterAddresses::WX => 0xFF4B,
             RegisterAddresses::WY => 0xFF4A,
             RegisterAddresses::STAT => 0xFF41,
+            RegisterAddresses::BGP => 0xFF47,
+            RegisterAddresses::OBP0 => 0xFF48,
+            RegisterAddresses::OBP1 => 0xFF49,
+            RegisterAddresses::BCPS => 0xFF68,
+            RegisterAddresses::BCPD => 0xFF69,
+            RegisterAddresses::OCPS => 0xFF6A,
+            RegisterAddresses::OCPD => 0xFF6B,
+            RegisterAddresses::DIV => 0xFF04,
+            RegisterAddresses::TIMA => 0xFF05,
+            RegisterAddresses::TMA => 0xFF06,
+            RegisterAddresses::TAC => 0xFF07,
+            RegisterAddresses::KEY1 => 0xFF4D,
+            RegisterAddresses::VBK => 0xFF4F,
+            RegisterAddresses::HDMA1 => 0xFF51,
+            RegisterAddresses::HDMA2 => 0xFF52,
+            RegisterAddresses::HDMA3 => 0xFF53,
+            RegisterAddresses::HDMA4 => 0xFF54,
+            RegisterAddresses::HDMA5 => 0xFF55,
+            RegisterAddresses::SVBK => 0xFF70,
+            RegisterAddresses::FF72 => 0xFF72,
+            RegisterAddresses::FF73 => 0xFF73,
+            RegisterAddresses::FF74 => 0xFF74,
+            RegisterAddresses::FF75 => 0xFF75,
+            RegisterAddresses::FF76 => 0xFF76,
+            RegisterAddresses::FF77 => 0xFF77,
         }
     }
 
     pub fn from_address(address: u16) -> Option<Self> {
         match address {
+            0xFF00 => Some(RegisterAddresses::P1),
+            0xFF01 => Some(RegisterAddresses::SB),
+            0xFF02 => Some(RegisterAddresses::SC),
             0xFF40 => Some(RegisterAddresses::LCDC),
             0xFF41 => Some(RegisterAddresses::STAT),
             0xFF42 => Some(RegisterAddresses::SCY),
@@ -213,6 +400,31 @@ impl RegisterAddresses {
             0xFFFF => Some(RegisterAddresses::IE),
             0xFF4B => Some(RegisterAddresses::WX),
             0xFF4A => Some(RegisterAddresses::WY),
+            0xFF47 => Some(RegisterAddresses::BGP),
+            0xFF48 => Some(RegisterAddresses::OBP0),
+            0xFF49 => Some(RegisterAddresses::OBP1),
+            0xFF68 => Some(RegisterAddresses::BCPS),
+            0xFF69 => Some(RegisterAddresses::BCPD),
+            0xFF6A => Some(RegisterAddresses::OCPS),
+            0xFF6B => Some(RegisterAddresses::OCPD),
+            0xFF04 => Some(RegisterAddresses::DIV),
+            0xFF05 => Some(RegisterAddresses::TIMA),
+            0xFF06 => Some(RegisterAddresses::TMA),
+            0xFF07 => Some(RegisterAddresses::TAC),
+            0xFF4D => Some(RegisterAddresses::KEY1),
+            0xFF4F => Some(RegisterAddresses::VBK),
+            0xFF51 => Some(RegisterAddresses::HDMA1),
+            0xFF52 => Some(RegisterAddresses::HDMA2),
+            0xFF53 => Some(RegisterAddresses::HDMA3),
+            0xFF54 => Some(RegisterAddresses::HDMA4),
+            0xFF55 => Some(RegisterAddresses::HDMA5),
+            0xFF70 => Some(RegisterAddresses::SVBK),
+            0xFF72 => Some(RegisterAddresses::FF72),
+            0xFF73 => Some(RegisterAddresses::FF73),
+            0xFF74 => Some(RegisterAddresses::FF74),
+            0xFF75 => Some(RegisterAddresses::FF75),
+            0xFF76 => Some(RegisterAddresses::FF76),
+            0xFF77 => Some(RegisterAddresses::FF77),
             _ => None,
         }
     }
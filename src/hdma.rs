@@ -0,0 +1,120 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// CGB VRAM DMA (HDMA1-HDMA5). Copies blocks of 16 bytes from ROM/RAM to
+// VRAM, either all at once (general purpose DMA) or one block per H-Blank.
+// This only tracks the register state; the bus does the copying.
+#[derive(Debug)]
+pub struct Hdma {
+    source: u16,
+    destination: u16,
+
+    // number of blocks left minus one, as read back from HDMA5
+    remaining: u8,
+
+    hblank_active: bool,
+}
+
+pub const HDMA_BLOCK_SIZE: u16 = 0x10;
+
+impl Hdma {
+    pub fn new() -> Self {
+        Self {
+            source: 0,
+            destination: 0,
+            remaining: 0x7F,
+            hblank_active: false,
+        }
+    }
+
+    pub fn write_source_high(&mut self, val: u8) {
+        self.source = (self.source & 0x00FF) | (val as u16) << 8;
+    }
+
+    pub fn write_source_low(&mut self, val: u8) {
+        self.source = (self.source & 0xFF00) | (val & 0xF0) as u16;
+    }
+
+    pub fn write_destination_high(&mut self, val: u8) {
+        self.destination = (self.destination & 0x00FF) | ((val & 0x1F) as u16) << 8;
+    }
+
+    pub fn write_destination_low(&mut self, val: u8) {
+        self.destination = (self.destination & 0xFF00) | (val & 0xF0) as u16;
+    }
+
+    // Returns the number of blocks to copy straight away: the whole length
+    // for a general purpose DMA, none for an H-Blank DMA or a cancel.
+    pub fn write_control(&mut self, val: u8) -> u16 {
+        if self.hblank_active && val & 0x80 == 0 {
+            // cancel the running H-Blank DMA, leaving the remaining length
+            self.hblank_active = false;
+            return 0;
+        }
+
+        self.remaining = val & 0x7F;
+
+        if val & 0x80 > 0 {
+            self.hblank_active = true;
+            0
+        } else {
+            let blocks = self.remaining as u16 + 1;
+            self.remaining = 0x7F;
+            blocks
+        }
+    }
+
+    pub fn read_control(&self) -> u8 {
+        if self.hblank_active {
+            self.remaining
+        } else {
+            0x80 | self.remaining
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.source);
+        w.write_u16(self.destination);
+        w.write_u8(self.remaining);
+        w.write_bool(self.hblank_active);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.source = r.read_u16()?;
+        self.destination = r.read_u16()?;
+        self.remaining = r.read_u8()?;
+        self.hblank_active = r.read_bool()?;
+
+        Ok(())
+    }
+
+    pub fn hblank_active(&self) -> bool {
+        self.hblank_active
+    }
+
+    // Source and destination addresses of the next block, advancing past
+    // it. The destination always lands in VRAM.
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, 0x8000 | self.destination);
+
+        self.source = self.source.wrapping_add(HDMA_BLOCK_SIZE);
+        self.destination = (self.destination + HDMA_BLOCK_SIZE) & 0x1FF0;
+
+        block
+    }
+
+    // count down one H-Blank block, finishing the transfer after the last
+    pub fn finish_hblank_block(&mut self) {
+        if self.remaining == 0 {
+            self.hblank_active = false;
+            self.remaining = 0x7F;
+        } else {
+            self.remaining -= 1;
+        }
+    }
+}
+
+impl Default for Hdma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
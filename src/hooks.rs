@@ -0,0 +1,224 @@
+use std::cell::RefCell;
+
+use crate::{hardware_registers::Interrupt, instructions::Instruction, ppu::PPUMode, ppu::Rgb};
+
+type InstructionHook = Box<dyn FnMut(u16, &Instruction) + Send>;
+type MemoryHook = Box<dyn FnMut(u16, u8) + Send>;
+type InterruptHook = Box<dyn FnMut(Interrupt) + Send>;
+type PPUModeHook = Box<dyn FnMut(PPUMode, u8) + Send>;
+type FrameHook = Box<dyn FnMut(&[Rgb]) + Send>;
+type BreakpointHook = Box<dyn FnMut(u16) + Send>;
+
+// Callbacks for observing the machine from outside, e.g. for tracing or
+// debugging tools. Each event has at most one hook, and an event with no
+// hook costs a single branch.
+#[derive(Default)]
+pub struct Hooks {
+    instruction: Option<InstructionHook>,
+    // reads go through &self, so the read hook needs its own cell
+    memory_read: Option<RefCell<MemoryHook>>,
+    memory_write: Option<MemoryHook>,
+    interrupt: Option<InterruptHook>,
+    ppu_mode: Option<PPUModeHook>,
+    frame: Option<FrameHook>,
+    breakpoint: Option<BreakpointHook>,
+}
+
+impl Hooks {
+    // called with the address and instruction after each instruction runs
+    pub fn on_instruction(&mut self, hook: impl FnMut(u16, &Instruction) + Send + 'static) {
+        self.instruction = Some(Box::new(hook));
+    }
+
+    // called with the address and value of every bus read
+    pub fn on_memory_read(&mut self, hook: impl FnMut(u16, u8) + Send + 'static) {
+        self.memory_read = Some(RefCell::new(Box::new(hook)));
+    }
+
+    // called with the address and value of every bus write
+    pub fn on_memory_write(&mut self, hook: impl FnMut(u16, u8) + Send + 'static) {
+        self.memory_write = Some(Box::new(hook));
+    }
+
+    // called when the CPU jumps to an interrupt handler
+    pub fn on_interrupt(&mut self, hook: impl FnMut(Interrupt) + Send + 'static) {
+        self.interrupt = Some(Box::new(hook));
+    }
+
+    // called with the new mode and LY whenever the PPU changes mode
+    pub fn on_ppu_mode_change(&mut self, hook: impl FnMut(PPUMode, u8) + Send + 'static) {
+        self.ppu_mode = Some(Box::new(hook));
+    }
+
+    // called with the finished frame on entering V-Blank
+    pub fn on_frame_complete(&mut self, hook: impl FnMut(&[Rgb]) + Send + 'static) {
+        self.frame = Some(Box::new(hook));
+    }
+
+    // called with the address of each LD B,B after it runs
+    pub fn on_breakpoint(&mut self, hook: impl FnMut(u16) + Send + 'static) {
+        self.breakpoint = Some(Box::new(hook));
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn instruction(&mut self, pc: u16, instruction: &Instruction) {
+        if let Some(hook) = self.instruction.as_mut() {
+            hook(pc, instruction);
+        }
+        if let Some(hook) = self.breakpoint.as_mut() {
+            if instruction.is_breakpoint() {
+                hook(pc);
+            }
+        }
+    }
+
+    pub fn memory_read(&self, address: u16, value: u8) {
+        if let Some(hook) = self.memory_read.as_ref() {
+            (hook.borrow_mut())(address, value);
+        }
+    }
+
+    pub fn memory_write(&mut self, address: u16, value: u8) {
+        if let Some(hook) = self.memory_write.as_mut() {
+            hook(address, value);
+        }
+    }
+
+    pub fn interrupt(&mut self, interrupt: Interrupt) {
+        if let Some(hook) = self.interrupt.as_mut() {
+            hook(interrupt);
+        }
+    }
+
+    pub fn ppu_mode_change(&mut self, mode: PPUMode, ly: u8) {
+        if let Some(hook) = self.ppu_mode.as_mut() {
+            hook(mode, ly);
+        }
+    }
+
+    pub fn frame_complete(&mut self, frame: &[Rgb]) {
+        if let Some(hook) = self.frame.as_mut() {
+            hook(frame);
+        }
+    }
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("instruction", &self.instruction.is_some())
+            .field("memory_read", &self.memory_read.is_some())
+            .field("memory_write", &self.memory_write.is_some())
+            .field("interrupt", &self.interrupt.is_some())
+            .field("ppu_mode", &self.ppu_mode.is_some())
+            .field("frame", &self.frame.is_some())
+            .field("breakpoint", &self.breakpoint.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{
+        hardware_registers::Interrupt,
+        model::Model,
+        ppu::PPUMode,
+        testing::{test_gameboy, write_test_rom},
+    };
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Event {
+        Instruction(u16),
+        Read(u16),
+        Write(u16, u8),
+        Interrupt(Interrupt),
+        PPUMode(PPUMode, u8),
+        Frame(usize),
+        Breakpoint(u16),
+    }
+
+    #[test]
+    fn test_hook_events() {
+        // clear the V-Blank left pending by the boot ROM, enable it, EI,
+        // HALT, then a breakpoint once the handler returns
+        let rom = write_test_rom(
+            "hooks",
+            &[
+                0xAF, // XOR A
+                0xE0, 0x0F, // LDH (IF),A
+                0x3E, 0x01, // LD A,0x01
+                0xE0, 0xFF, // LDH (IE),A
+                0xFB, // EI
+                0x76, // HALT
+                0x40, // LD B,B
+                0x18, 0xFE, // JR -2
+            ],
+        );
+        let mut data = fs::read(&rom).unwrap();
+        data[0x40] = 0xD9; // RETI
+        fs::write(&rom, data).unwrap();
+
+        let mut gb = test_gameboy(&rom, Model::default());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let log = |events: &Arc<Mutex<Vec<Event>>>| {
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(event)
+        };
+
+        let hooks = gb.hooks_mut();
+        let push = log(&events);
+        hooks.on_instruction(move |pc, _| push(Event::Instruction(pc)));
+        let push = log(&events);
+        hooks.on_memory_read(move |address, _| push(Event::Read(address)));
+        let push = log(&events);
+        hooks.on_memory_write(move |address, value| push(Event::Write(address, value)));
+        let push = log(&events);
+        hooks.on_interrupt(move |interrupt| push(Event::Interrupt(interrupt)));
+        let push = log(&events);
+        hooks.on_ppu_mode_change(move |mode, ly| push(Event::PPUMode(mode, ly)));
+        let push = log(&events);
+        hooks.on_frame_complete(move |frame| push(Event::Frame(frame.len())));
+        let push = log(&events);
+        hooks.on_breakpoint(move |pc| push(Event::Breakpoint(pc)));
+
+        assert!(gb.run_frame());
+        gb.run_frame();
+        let events = events.lock().unwrap().clone();
+        let position = |event| events.iter().position(|e| *e == event).unwrap();
+
+        let instructions: Vec<u16> = events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Instruction(pc) => Some(*pc),
+                _ => None,
+            })
+            .take(10)
+            .collect();
+        assert_eq!(
+            instructions,
+            [0x100, 0x101, 0x150, 0x151, 0x153, 0x155, 0x157, 0x158, 0x40, 0x159]
+        );
+        assert!(events.contains(&Event::Write(0xFFFF, 0x01)));
+
+        // nothing after the HALT is fetched until V-Blank wakes the CPU
+        let interrupt = position(Event::Interrupt(Interrupt::VBlank));
+        assert!(position(Event::Instruction(0x158)) < interrupt);
+        assert!(position(Event::PPUMode(PPUMode::Mode1VerticalBlank, 144)) < interrupt);
+        assert!(position(Event::Read(0x159)) > interrupt);
+        assert!(position(Event::Breakpoint(0x159)) > interrupt);
+
+        let frames = events.iter().filter(|e| matches!(e, Event::Frame(_)));
+        assert_eq!(frames.count(), 2);
+        assert!(events.contains(&Event::Frame(160 * 144)));
+
+        fs::remove_file(rom).unwrap();
+    }
+}
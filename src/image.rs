@@ -0,0 +1,515 @@
+use std::{fs, io, path::Path};
+
+use crate::{
+    hash::{adler32, crc32_update},
+    ppu::Rgb,
+};
+
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+// the most a stored deflate block can hold
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+// A picture as 0x00RRGGBB pixels, row by row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Rgb>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, pixels: Vec<Rgb>) -> Self {
+        assert_eq!(pixels.len(), width * height, "Image size doesn't match");
+
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    // An 8 bit RGB PNG. The image data isn't compressed (it's stored in
+    // the zlib stream as is), which keeps the writer simple at about 70KB
+    // for a Game Boy screen.
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut png = PNG_SIGNATURE.to_vec();
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // bit depth 8, colour type 2 (RGB), deflate, adaptive filtering
+        // and no interlacing
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        chunk(&mut png, b"IHDR", &header);
+
+        // each row starts with its filter type, 0 for none
+        let mut raw = Vec::with_capacity(self.height * (1 + self.width * 3));
+        for row in self.pixels.chunks(self.width.max(1)) {
+            raw.push(0);
+            for pixel in row {
+                raw.extend_from_slice(&pixel.to_be_bytes()[1..]);
+            }
+        }
+        chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+
+        chunk(&mut png, b"IEND", &[]);
+
+        png
+    }
+
+    pub fn save_png(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_png())
+    }
+
+    pub fn from_png(data: &[u8]) -> Result<Self, String> {
+        decode_png(data)
+    }
+
+    pub fn load_png(path: &Path) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("failed to read {:?}: {}", path, e))?;
+        decode_png(&data)
+    }
+}
+
+fn chunk(png: &mut Vec<u8>, tag: &[u8; 4], contents: &[u8]) {
+    png.extend_from_slice(&(contents.len() as u32).to_be_bytes());
+    png.extend_from_slice(tag);
+    png.extend_from_slice(contents);
+
+    let crc = crc32_update(crc32_update(0, tag), contents);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// a zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // deflate with a 32KB window, no preset dictionary
+    let mut out = vec![0x78, 0x01];
+
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+
+    out
+}
+
+// Reading PNGs, for comparing against images made elsewhere. Handles any
+// non-interlaced PNG, dropping transparency and the low byte of 16 bit
+// samples.
+fn decode_png(data: &[u8]) -> Result<Image, String> {
+    let mut rest = data.strip_prefix(PNG_SIGNATURE).ok_or("not a PNG file")?;
+
+    let mut header = None;
+    let mut palette: &[u8] = &[];
+    let mut compressed = Vec::new();
+    loop {
+        if rest.len() < 12 {
+            return Err("truncated PNG".to_string());
+        }
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        let tag = &rest[4..8];
+        let contents = rest.get(8..8 + len).ok_or("truncated PNG")?;
+        rest = rest.get(12 + len..).ok_or("truncated PNG")?;
+
+        match tag {
+            b"IHDR" if len == 13 => header = Some(contents),
+            b"PLTE" => palette = contents,
+            b"IDAT" => compressed.extend_from_slice(contents),
+            b"IEND" => break,
+            _ => {}
+        }
+    }
+
+    let header = header.ok_or("PNG has no header")?;
+    let width = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+    let height = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+    let (bit_depth, colour_type, interlace) = (header[8] as usize, header[9], header[12]);
+    if interlace != 0 {
+        return Err("interlaced PNGs aren't supported".to_string());
+    }
+    let (channels, bit_depths): (usize, &[usize]) = match colour_type {
+        0 => (1, &[1, 2, 4, 8, 16]),
+        2 => (3, &[8, 16]),
+        3 => (1, &[1, 2, 4, 8]),
+        4 => (2, &[8, 16]),
+        6 => (4, &[8, 16]),
+        _ => return Err(format!("bad PNG colour type {colour_type}")),
+    };
+    if !bit_depths.contains(&bit_depth) {
+        return Err(format!(
+            "bad PNG bit depth {bit_depth} for colour type {colour_type}"
+        ));
+    }
+
+    // zlib header, then deflate
+    let raw = inflate(compressed.get(2..).ok_or("truncated PNG")?)?;
+
+    let bits_per_pixel = channels * bit_depth;
+    let stride = (width * bits_per_pixel).div_ceil(8);
+    let bytes_per_pixel = bits_per_pixel.div_ceil(8);
+    if raw.len() < height * (stride + 1) {
+        return Err("truncated PNG image data".to_string());
+    }
+
+    let mut pixels = Vec::with_capacity(width * height);
+    let mut previous = vec![0; stride];
+    for row in raw.chunks(stride + 1).take(height) {
+        let filter = row[0];
+        let mut line = row[1..].to_vec();
+        for i in 0..stride {
+            let a = if i >= bytes_per_pixel {
+                line[i - bytes_per_pixel]
+            } else {
+                0
+            };
+            let b = previous[i];
+            let c = if i >= bytes_per_pixel {
+                previous[i - bytes_per_pixel]
+            } else {
+                0
+            };
+            let predicted = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => return Err(format!("bad PNG filter {filter}")),
+            };
+            line[i] = line[i].wrapping_add(predicted);
+        }
+
+        // samples scaled to 8 bits, except palette indices
+        let sample = |index: usize| -> u8 {
+            match bit_depth {
+                8 => line[index],
+                16 => line[index * 2],
+                _ => {
+                    let per_byte = 8 / bit_depth;
+                    let shift = 8 - bit_depth * (index % per_byte + 1);
+                    let value = line[index / per_byte] >> shift & ((1 << bit_depth) - 1);
+                    if colour_type == 3 {
+                        value
+                    } else {
+                        (value as usize * 0xFF / ((1 << bit_depth) - 1)) as u8
+                    }
+                }
+            }
+        };
+
+        for x in 0..width {
+            let [r, g, b] = match colour_type {
+                0 | 4 => [sample(x * channels); 3],
+                3 => {
+                    let entry = sample(x) as usize * 3;
+                    palette
+                        .get(entry..entry + 3)
+                        .ok_or("PNG palette index out of range")?
+                        .try_into()
+                        .unwrap()
+                }
+                _ => [
+                    sample(x * channels),
+                    sample(x * channels + 1),
+                    sample(x * channels + 2),
+                ],
+            };
+            pixels.push((r as Rgb) << 16 | (g as Rgb) << 8 | b as Rgb);
+        }
+
+        previous = line;
+    }
+
+    Ok(Image::new(width, height, pixels))
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// the order code length code lengths are listed in
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    // in bits
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u8) -> Result<u32, String> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self
+                .data
+                .get(self.pos / 8)
+                .ok_or("truncated deflate data")?;
+            value |= ((byte >> (self.pos % 8)) as u32 & 1) << i;
+            self.pos += 1;
+        }
+
+        Ok(value)
+    }
+}
+
+// a canonical Huffman code: how many codes there are of each length, and
+// the symbols in code order
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut symbols = Vec::new();
+        for length in 1..16 {
+            for (symbol, _) in lengths.iter().enumerate().filter(|(_, &l)| l == length) {
+                symbols.push(symbol as u16);
+            }
+        }
+
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, r: &mut BitReader) -> Result<u16, String> {
+        // codes of each length follow on from the shorter ones
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= r.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err("bad Huffman code".to_string())
+    }
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut r = BitReader { data, pos: 0 };
+    let mut out = Vec::new();
+
+    loop {
+        let last = r.bits(1)? == 1;
+        match r.bits(2)? {
+            0 => {
+                r.pos = r.pos.div_ceil(8) * 8;
+                let len = r.bits(16)? as usize;
+                r.bits(16)?;
+                let start = r.pos / 8;
+                out.extend_from_slice(
+                    data.get(start..start + len)
+                        .ok_or("truncated deflate data")?,
+                );
+                r.pos += len * 8;
+            }
+            1 => {
+                let mut lengths = [8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                inflate_block(
+                    &mut r,
+                    &mut out,
+                    &Huffman::new(&lengths),
+                    &Huffman::new(&[5; 30]),
+                )?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut r)?;
+                inflate_block(&mut r, &mut out, &literals, &distances)?;
+            }
+            _ => return Err("bad deflate block type".to_string()),
+        }
+
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn read_dynamic_codes(r: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let literal_count = r.bits(5)? as usize + 257;
+    let distance_count = r.bits(5)? as usize + 1;
+    let code_length_count = r.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[i] = r.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match code_lengths.decode(r)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or("bad code lengths")?, 3 + r.bits(2)?),
+            17 => (0, 3 + r.bits(3)?),
+            _ => (0, 11 + r.bits(7)?),
+        };
+        lengths.extend((0..repeat).map(|_| length));
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err("bad code lengths".to_string());
+    }
+
+    Ok((
+        Huffman::new(&lengths[..literal_count]),
+        Huffman::new(&lengths[literal_count..]),
+    ))
+}
+
+fn inflate_block(
+    r: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(r)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let i = symbol - 257;
+                if i >= LENGTH_BASES.len() {
+                    return Err("bad deflate length".to_string());
+                }
+                let length = LENGTH_BASES[i] as usize + r.bits(LENGTH_EXTRA_BITS[i])? as usize;
+
+                let i = distances.decode(r)? as usize;
+                if i >= DISTANCE_BASES.len() {
+                    return Err("bad deflate distance".to_string());
+                }
+                let distance =
+                    DISTANCE_BASES[i] as usize + r.bits(DISTANCE_EXTRA_BITS[i])? as usize;
+                if distance > out.len() {
+                    return Err("deflate distance goes back too far".to_string());
+                }
+
+                // the copy can overlap what it's writing
+                let start = out.len() - distance;
+                for i in 0..length {
+                    out.push(out[start + i]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::crc32;
+
+    use super::Image;
+
+    // 12x10 RGB, compressed with dynamic Huffman codes and using each
+    // filter type
+    const COMPRESSED_PNG: &str = "89504e470d0a1a0a0000000d494844520000000c0000000a08020000000f4e28ad000000be4944415478da8d8ead0ac20014468f3f30b87017c48117168405654198200c1c2c086bc29ec06431da2c37d897cdbe81c587309b7d0dbb338b221cbe74f8380043984201356ce1000d9ce10a377840076ba5e037dd56c2022cc4222cc6122cc5322cc74aacc2d63de68886a203d148d44463d1b168223a114d4567a259fffd44002144107fc1197a32f5aaf05dedcdd62f07bf37fe3cfbe8eacb9b6f1e7eec706ac3d3dffc19bee7b35474219a8b16a2a5e8ea333c811432c8a184aadd17cdf728d197e47e090000000049454e44ae426082";
+
+    #[test]
+    fn test_png_decode() {
+        let image = Image::new(300, 200, (0..60_000).collect());
+        let mut png = image.to_png();
+        assert_eq!(Image::from_png(&png), Ok(image));
+
+        // IHDR's bit depth, which RGB only has at 8 or 16
+        for bit_depth in [0, 4, 7] {
+            png[24] = bit_depth;
+            assert!(Image::from_png(&png).is_err());
+        }
+
+        let png: Vec<u8> = (0..COMPRESSED_PNG.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&COMPRESSED_PNG[i..i + 2], 16).unwrap())
+            .collect();
+        let image = Image::from_png(&png).unwrap();
+        assert_eq!((image.width, image.height), (12, 10));
+        for y in 0..10 {
+            for x in 0..12 {
+                let (r, g, b) = ((x * 20) & 0xFF, (y * 25) & 0xFF, (x * y * 7) & 0xFF);
+                assert_eq!(image.pixels[y * 12 + x], (r << 16 | g << 8 | b) as u32);
+            }
+        }
+
+        assert!(Image::from_png(b"not a png").is_err());
+    }
+
+    #[test]
+    fn test_png_layout() {
+        let image = Image::new(300, 200, (0..60_000).collect());
+        let png = image.to_png();
+
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]));
+
+        // walk the chunks, checking their CRCs
+        let mut pos = 8;
+        let mut tags = Vec::new();
+        while pos < png.len() {
+            let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+            let body = &png[pos + 4..pos + 8 + len];
+            let crc = u32::from_be_bytes(png[pos + 8 + len..pos + 12 + len].try_into().unwrap());
+            assert_eq!(crc32(body), crc);
+
+            tags.push(body[..4].to_vec());
+            pos += 12 + len;
+        }
+        assert_eq!(tags, [&b"IHDR"[..], b"IDAT", b"IEND"]);
+
+        // 200 rows of a filter byte and 900 bytes of pixels need three
+        // stored blocks
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(idat_len, 2 + 180_200 + 3 * 5 + 4);
+    }
+}
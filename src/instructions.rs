@@ -229,6 +229,10 @@ pub enum Instruction {
     // routine, control is returned with IME = 1.
     EI,
 
+    // Reset the interrupt master enable (IME) flag, disabling maskable
+    // interrupts straight away.
+    DI,
+
     // Halt
     HALT,
 
@@ -317,6 +321,36 @@ impl Instruction {
         }
     }
 
+    // LD B,B does nothing, so test ROMs (and debuggers) use it as a
+    // software breakpoint
+    pub fn is_breakpoint(&self) -> bool {
+        matches!(
+            self,
+            Instruction::LD(LoadType::Byte(LoadByteTarget::B, LoadByteSource::B))
+        )
+    }
+
+    // Bytes taken by the instruction with this opcode, including the 0xCB
+    // prefix and any immediate operand
+    pub fn length(byte: u8, prefixed: bool) -> usize {
+        if prefixed {
+            return 2;
+        }
+
+        match byte {
+            0x01 | 0x08 | 0x11 | 0x21 | 0x31 => 3,
+            0xC2 | 0xC3 | 0xC4 | 0xCA | 0xCC | 0xCD => 3,
+            0xD2 | 0xD4 | 0xDA | 0xDC | 0xEA | 0xFA => 3,
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => 2,
+            0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => 2,
+            0x18 | 0x20 | 0x28 | 0x30 | 0x38 => 2,
+            0xE0 | 0xE8 | 0xF0 | 0xF8 => 2,
+            // STOP is followed by a padding byte
+            0x10 => 2,
+            _ => 1,
+        }
+    }
+
     #[rustfmt::skip]
     fn from_byte_not_prefixed(byte: u8) -> Option<Instruction> {
         match byte {
@@ -563,7 +597,7 @@ impl Instruction {
 	    0xF0 => Some(Instruction::LD(LoadType::AFromByteAddress(LdByteAddress::A8))),
 	    0xF1 => Some(Instruction::POP(StackTarget::AF)),
 	    0xF2 => Some(Instruction::LD(LoadType::AFromByteAddress(LdByteAddress::C))),
-	    0xF3 => Some(Instruction::EI),
+	    0xF3 => Some(Instruction::DI),
 	    0xF4 => None,
 	    0xF5 => Some(Instruction::PUSH(StackTarget::AF)),
 	    0xF6 => Some(Instruction::OR(ORTargetType::D8)),
@@ -883,6 +917,7 @@ impl Instruction {
                 ArithmeticTargetType::Word(_) => 2,
             },
             Instruction::EI => 1,
+            Instruction::DI => 1,
             Instruction::HALT => 1,
             Instruction::INC(arithmetic_target_type) => match arithmetic_target_type {
                 ArithmeticTargetType::Byte(arithmetic_byte_target) => {
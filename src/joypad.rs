@@ -0,0 +1,131 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// Joypad register (P1, 0xFF00). The CPU selects the d-pad (bit 4 low)
+// and/or the buttons (bit 5 low) and reads the selected keys back in the
+// lower nibble, where a pressed key reads as 0.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Buttons {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+}
+
+impl Buttons {
+    // pressed d-pad keys in the P1 bit order (right, left, up, down)
+    fn dpad_bits(&self) -> u8 {
+        (self.right as u8) | (self.left as u8) << 1 | (self.up as u8) << 2 | (self.down as u8) << 3
+    }
+
+    // pressed buttons in the P1 bit order (A, B, select, start)
+    fn button_bits(&self) -> u8 {
+        (self.a as u8) | (self.b as u8) << 1 | (self.select as u8) << 2 | (self.start as u8) << 3
+    }
+}
+
+#[derive(Debug)]
+pub struct Joypad {
+    buttons: Buttons,
+    // bits 4 and 5 as last written
+    select: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            buttons: Buttons::default(),
+            select: 0x30,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        let mut pressed = 0;
+
+        if self.select & 0x10 == 0 {
+            pressed |= self.buttons.dpad_bits();
+        }
+        if self.select & 0x20 == 0 {
+            pressed |= self.buttons.button_bits();
+        }
+
+        0xC0 | self.select | (!pressed & 0x0F)
+    }
+
+    pub fn write(&mut self, val: u8) {
+        self.select = val & 0x30;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.buttons.dpad_bits() | self.buttons.button_bits() << 4);
+        w.write_u8(self.select);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let pressed = r.read_u8()?;
+        self.buttons = Buttons {
+            right: pressed & 0x01 > 0,
+            left: pressed & 0x02 > 0,
+            up: pressed & 0x04 > 0,
+            down: pressed & 0x08 > 0,
+            a: pressed & 0x10 > 0,
+            b: pressed & 0x20 > 0,
+            select: pressed & 0x40 > 0,
+            start: pressed & 0x80 > 0,
+        };
+        self.select = r.read_u8()? & 0x30;
+
+        Ok(())
+    }
+
+    pub fn select_bits(&self) -> u8 {
+        self.select
+    }
+
+    pub fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+
+    // Update the held keys. Returns true if a key was newly pressed on a
+    // selected line, which requests the joypad interrupt.
+    pub fn set_buttons(&mut self, buttons: Buttons) -> bool {
+        let before = self.read();
+        self.buttons = buttons;
+
+        // a selected input line going from high to low
+        before & !self.read() & 0x0F != 0
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Buttons, Joypad};
+
+    #[test]
+    fn test_select_lines() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(Buttons {
+            a: true,
+            down: true,
+            ..Default::default()
+        });
+
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0xE7);
+
+        joypad.write(0x10);
+        assert_eq!(joypad.read(), 0xDE);
+
+        joypad.write(0x30);
+        assert_eq!(joypad.read(), 0xFF);
+    }
+}
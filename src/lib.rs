@@ -0,0 +1,28 @@
+pub mod bess;
+pub mod capture;
+pub mod cartridge;
+pub mod cpu;
+pub mod display;
+pub mod dmg07;
+pub mod gameboy;
+pub mod hardware_registers;
+pub mod hash;
+pub mod hdma;
+pub mod hooks;
+pub mod image;
+pub mod instructions;
+pub mod joypad;
+pub mod link;
+pub mod memory;
+pub mod model;
+pub mod movie;
+pub mod ppu;
+pub mod printer;
+pub mod registers;
+pub mod rewind;
+pub mod savestate;
+pub mod serial;
+pub mod sgb;
+pub mod tcp_link;
+pub mod testing;
+pub mod timer;
@@ -0,0 +1,196 @@
+use std::{
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use crate::{display::GbDisplay, gameboy::Gameboy, image::Image, ppu::Rgb, serial::link_cable};
+
+const RENDER_FPS: f32 = 30.0;
+
+// Two Game Boys joined by a link cable, stepped a master clock tick at a
+// time each so that neither gets ahead of the other, for trading and
+// versus modes. They're boxed, as a Gameboy is a few hundred KiB.
+pub struct LinkedPair {
+    pub left: Box<Gameboy>,
+    pub right: Box<Gameboy>,
+}
+
+impl LinkedPair {
+    // plug the two ends of a new cable in, replacing any serial devices
+    pub fn new(mut left: Box<Gameboy>, mut right: Box<Gameboy>) -> Self {
+        let (left_port, right_port) = link_cable();
+        left.set_serial_device(Box::new(left_port));
+        right.set_serial_device(Box::new(right_port));
+
+        Self { left, right }
+    }
+
+    pub fn into_inner(self) -> (Box<Gameboy>, Box<Gameboy>) {
+        (self.left, self.right)
+    }
+
+    // Advance both by one master clock tick. Returns whether each finished
+    // a frame.
+    pub fn step(&mut self) -> (bool, bool) {
+        let left = self.left.is_running() && self.left.step();
+        let right = self.right.is_running() && self.right.step();
+
+        (left, right)
+    }
+
+    // Emulate the given number of master clock ticks on both. Returns
+    // false if either CPU has stopped.
+    pub fn run_cycles(&mut self, m_ticks: usize) -> bool {
+        for _ in 0..m_ticks {
+            if !self.is_running() {
+                break;
+            }
+            self.step();
+        }
+
+        self.is_running()
+    }
+
+    // Emulate up to the left machine's next V-Blank, with the right one
+    // kept in step. Returns false if either CPU has stopped.
+    pub fn run_frame(&mut self) -> bool {
+        while self.is_running() {
+            if self.step().0 {
+                break;
+            }
+        }
+
+        self.is_running()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.left.is_running() && self.right.is_running()
+    }
+
+    // both last frames side by side, the left machine's first
+    pub fn screenshot(&self) -> Image {
+        let (left_width, left_height) = self.left.framebuffer_size();
+        let (right_width, right_height) = self.right.framebuffer_size();
+        let width = left_width + right_width;
+        let height = left_height.max(right_height);
+
+        let row = |frame: &[Rgb], frame_width: usize, frame_height: usize, y: usize| {
+            let mut row = vec![0; frame_width];
+            if y < frame_height {
+                row.copy_from_slice(&frame[y * frame_width..(y + 1) * frame_width]);
+            }
+            row
+        };
+
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            pixels.extend(row(self.left.framebuffer(), left_width, left_height, y));
+            pixels.extend(row(self.right.framebuffer(), right_width, right_height, y));
+        }
+
+        Image::new(width, height, pixels)
+    }
+
+    // Run both in one window, side by side, at the left machine's speed
+    // until the window is closed. The left machine takes the usual keys
+    // and the right one the second player's.
+    pub fn run(&mut self, scale: usize) {
+        let first = self.screenshot();
+        let mut display = GbDisplay::start(first.width, first.height, scale)
+            .unwrap_or_else(|_| panic!("Failed to start display!"));
+
+        let render_duration = Duration::from_secs_f32(1.0 / RENDER_FPS);
+        let mut next_frame = Instant::now();
+        let mut last_render = Instant::now() - render_duration;
+
+        while self.run_frame() {
+            if last_render.elapsed() >= render_duration {
+                let frame = self.screenshot();
+                if !display.render(&frame.pixels, frame.width, frame.height) {
+                    break;
+                }
+                last_render = Instant::now();
+
+                self.left.set_buttons(display.buttons());
+                self.right.set_buttons(display.second_player_buttons());
+            }
+
+            match self.left.speed().frame_duration() {
+                Some(frame_duration) => {
+                    next_frame += frame_duration;
+
+                    let now = Instant::now();
+                    if now < next_frame {
+                        sleep(next_frame - now);
+                    } else {
+                        next_frame = now;
+                    }
+                }
+                None => next_frame = Instant::now(),
+            }
+        }
+
+        for gb in [&self.left, &self.right] {
+            if let Err(e) = gb.save_battery() {
+                log::warn!("Failed to write save file: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use crate::{
+        gameboy::Gameboy,
+        model::Model,
+        testing::{test_gameboy, write_test_rom},
+    };
+
+    use super::LinkedPair;
+
+    // A ROM that puts sb in SB, starts a transfer with sc, waits for it
+    // and copies what came in to 0xFF80
+    fn transfer_rom(name: &str, sb: u8, sc: u8) -> PathBuf {
+        write_test_rom(
+            name,
+            &[
+                0x3E, sb, // LD A,sb
+                0xE0, 0x01, // LDH (SB),A
+                0x3E, sc, // LD A,sc
+                0xE0, 0x02, // LDH (SC),A
+                0xF0, 0x02, // LDH A,(SC)
+                0xE6, 0x80, // AND 0x80
+                0x20, 0xFA, // JR NZ,-6
+                0xF0, 0x01, // LDH A,(SB)
+                0xE0, 0x80, // LDH (0xFF80),A
+                0x18, 0xFE, // JR -2
+            ],
+        )
+    }
+
+    fn gameboy(rom: &Path) -> Box<Gameboy> {
+        Box::new(test_gameboy(rom, Model::default()))
+    }
+
+    #[test]
+    fn test_linked_transfer() {
+        let master = transfer_rom("link-master", 0x12, 0x81);
+        let slave = transfer_rom("link-slave", 0x34, 0x80);
+
+        let mut pair = LinkedPair::new(gameboy(&slave), gameboy(&master));
+        assert!(pair.run_frame());
+        assert_eq!(pair.left.peek(0xFF80), 0x12);
+        assert_eq!(pair.right.peek(0xFF80), 0x34);
+
+        let screenshot = pair.screenshot();
+        assert_eq!((screenshot.width, screenshot.height), (320, 144));
+
+        fs::remove_file(master).unwrap();
+        fs::remove_file(slave).unwrap();
+    }
+}
@@ -1,99 +1,483 @@
-pub mod cartridge;
-pub mod cpu;
-pub mod display;
-pub mod gameboy;
-pub mod hardware_registers;
-pub mod instructions;
-pub mod memory;
-pub mod ppu;
-pub mod registers;
-
 use std::{
-    env, fs,
+    fs,
     path::{Path, PathBuf},
 };
 
-use gameboy::Gameboy;
+use clap::{Args, Parser, Subcommand};
+use gbars::{
+    cartridge::CartridgeHeader,
+    gameboy::{Gameboy, GbOptions, Speed},
+    instructions::Instruction,
+    link::LinkedPair,
+    model::Model,
+    movie::Movie,
+    printer::Printer,
+    serial::SerialLogger,
+    tcp_link::TcpLink,
+    testing::mooneye,
+};
+
+#[derive(Parser)]
+#[command(name = "gbars", about = "A Game Boy emulator")]
+struct Cli {
+    /// error, warn, info, debug or trace. RUST_LOG takes precedence
+    #[arg(long, global = true, default_value = "error")]
+    log_level: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a ROM
+    Run(Box<RunArgs>),
+    /// Disassemble a ROM, byte by byte from the start
+    Disasm { rom: PathBuf },
+    /// Print the cartridge header of a ROM
+    Info { rom: PathBuf },
+    /// Run every Mooneye test ROM under a directory and print a summary
+    Mooneye { dir: PathBuf },
+}
+
+#[derive(Args)]
+struct RunArgs {
+    rom: PathBuf,
+
+    /// Window size multiplier (1, 2, 4 or 8)
+    #[arg(long, default_value_t = 2)]
+    scale: usize,
+
+    /// Speed multiplier from 0.25 to 8, or uncapped
+    #[arg(long, default_value = "1")]
+    speed: Speed,
+
+    /// Run as fast as possible, the same as --speed uncapped
+    #[arg(long)]
+    no_speed_limit: bool,
+
+    /// Boot ROM to run, instead of the model's one under resources/
+    #[arg(long)]
+    boot_rom: Option<PathBuf>,
+
+    /// Start at the cartridge entry point without a boot ROM
+    #[arg(long)]
+    skip_boot: bool,
+
+    /// dmg0, dmg, mgb, sgb, sgb2 or cgb
+    #[arg(long, default_value = "dmg")]
+    model: Model,
+
+    /// Directory to keep battery backed cartridge RAM in
+    #[arg(long)]
+    save_dir: Option<PathBuf>,
+
+    /// Run this many frames without a window, then exit
+    #[arg(long)]
+    frames: Option<usize>,
 
-use crate::{gameboy::GbOptions, instructions::Instruction};
+    /// Seconds of history to keep for rewinding (hold R), 0 to turn it off
+    #[arg(long, default_value_t = 60)]
+    rewind_seconds: usize,
+
+    /// Frames between rewind snapshots
+    #[arg(long, default_value_t = 2)]
+    rewind_interval: usize,
+
+    /// Most memory the rewind history can use, in MiB
+    #[arg(long, default_value_t = 64)]
+    rewind_mib: usize,
+
+    /// Save state to start from
+    #[arg(long)]
+    load_state: Option<PathBuf>,
+
+    /// Write a save state here on exit
+    #[arg(long)]
+    save_state: Option<PathBuf>,
+
+    /// Write a PNG of the last frame here on exit (after --frames when
+    /// headless)
+    #[arg(long)]
+    screenshot: Option<PathBuf>,
+
+    /// Capture video of the run to a .gif, or a .y4m with a .wav next to it
+    #[arg(long)]
+    capture: Option<PathBuf>,
+
+    /// Directory for screenshots taken with F12
+    #[arg(long, default_value = ".")]
+    screenshot_dir: PathBuf,
+
+    /// Directory for videos recorded with F10
+    #[arg(long, default_value = ".")]
+    capture_dir: PathBuf,
+
+    /// Play this movie without a window, then print the final frame's hash
+    #[arg(long, conflicts_with_all = ["frames", "load_state", "record_movie"])]
+    movie: Option<PathBuf>,
+
+    /// Record the buttons pressed into this movie file, written on exit
+    #[arg(long)]
+    record_movie: Option<PathBuf>,
+
+    /// Write the bytes sent over the link port to this file, or - for stdout
+    #[arg(long)]
+    serial_log: Option<PathBuf>,
+
+    /// Link a second Game Boy running this ROM by cable, shown to the right
+    /// of the first. Its saves go in a player2 directory under --save-dir.
+    #[arg(long, conflicts_with_all = ["movie", "load_state", "save_state", "record_movie", "capture", "serial_log"])]
+    link: Option<PathBuf>,
+
+    /// Wait for another gbars to connect a link cable over TCP on this
+    /// address, like 0.0.0.0:5000
+    #[arg(long, conflicts_with_all = ["link", "link_connect", "serial_log"])]
+    link_host: Option<String>,
+
+    /// Connect a link cable over TCP to a gbars started with --link-host
+    #[arg(long, conflicts_with_all = ["link", "serial_log"])]
+    link_connect: Option<String>,
+
+    /// Plug a Game Boy Printer in, writing each printout to a PNG in this
+    /// directory
+    #[arg(long, conflicts_with_all = ["link", "link_host", "link_connect", "serial_log"])]
+    printer: Option<PathBuf>,
+}
 
 pub fn main() {
-    // decode_file("resources/game.gb");
-    create_and_run(&PathBuf::from("resources/game.gb"));
+    let cli = Cli::parse();
+    configure_logger(&cli.log_level);
+
+    match cli.command {
+        Command::Run(args) => run(*args),
+        Command::Disasm { rom } => decode_file(&rom),
+        Command::Info { rom } => print_info(&rom),
+        Command::Mooneye { dir } => run_mooneye(&dir),
+    }
 }
 
-pub fn create_and_run(cartridge_path: &Path) {
-    configure_logger();
-    let mut gb = Gameboy::new(
-        false,
-        Some(cartridge_path),
-        Some(GbOptions {
-            limit_speed: false,
-            render: false,
-        }),
-    );
-    gb.boot();
+fn options(args: &RunArgs, save_dir: Option<PathBuf>) -> GbOptions {
+    let headless = args.frames.is_some() || args.movie.is_some();
+
+    GbOptions {
+        speed: if args.no_speed_limit || headless {
+            Speed::Uncapped
+        } else {
+            args.speed
+        },
+        render: !headless,
+        skip_boot: args.skip_boot,
+        boot_rom_path: args.boot_rom.clone(),
+        model: args.model,
+        scale: args.scale,
+        save_dir,
+        // headless runs have no way to rewind
+        rewind_seconds: if !headless { args.rewind_seconds } else { 0 },
+        rewind_interval: args.rewind_interval,
+        rewind_budget: args.rewind_mib << 20,
+        screenshot_dir: args.screenshot_dir.clone(),
+        capture_dir: args.capture_dir.clone(),
+    }
+}
+
+fn run(args: RunArgs) {
+    if let Some(link_rom) = &args.link {
+        run_linked(&args, link_rom);
+        return;
+    }
+
+    let options = options(&args, args.save_dir.clone());
+    let mut gb = Gameboy::new(false, Some(&args.rom), Some(options));
+
+    if let Some(path) = &args.load_state {
+        let data = fs::read(path).unwrap_or_else(|e| panic!("Failed to read {:?}: {}", path, e));
+        if let Err(e) = gb.load_state(&data) {
+            panic!("Failed to load save state {:?}: {}", path, e);
+        }
+    }
+
+    if let Some(path) = &args.serial_log {
+        let logger = if path.as_os_str() == "-" {
+            SerialLogger::stdout()
+        } else {
+            SerialLogger::create(path)
+                .unwrap_or_else(|e| panic!("Failed to create {:?}: {}", path, e))
+        };
+        gb.set_serial_device(Box::new(logger));
+    }
+
+    if let Some(dir) = &args.printer {
+        gb.set_serial_device(Box::new(Printer::new(dir)));
+    }
+
+    if let Some(addr) = &args.link_host {
+        eprintln!("Waiting for the other player to connect to {addr}");
+        let link = TcpLink::host(addr.as_str())
+            .unwrap_or_else(|e| panic!("Failed to host a link on {}: {}", addr, e));
+        gb.set_serial_device(Box::new(link));
+    }
+
+    if let Some(addr) = &args.link_connect {
+        let link = TcpLink::connect(addr.as_str())
+            .unwrap_or_else(|e| panic!("Failed to connect a link to {}: {}", addr, e));
+        gb.set_serial_device(Box::new(link));
+    }
+
+    if let Some(path) = &args.capture {
+        if let Err(e) = gb.start_capture(path) {
+            panic!("Failed to start capturing to {:?}: {}", path, e);
+        }
+    }
+
+    if let Some(path) = &args.movie {
+        let text =
+            fs::read_to_string(path).unwrap_or_else(|e| panic!("Failed to read {:?}: {}", path, e));
+        let movie = Movie::parse(&text)
+            .unwrap_or_else(|e| panic!("Failed to parse movie {:?}: {}", path, e));
+
+        if let Err(e) = gb.play_movie(&movie) {
+            eprintln!("Movie playback failed: {e}");
+            std::process::exit(1);
+        }
+
+        println!(
+            "{} frames ({} lag), final frame hash {:08x}",
+            movie.frames.len(),
+            movie.lag_frames(),
+            gb.frame_hash()
+        );
+    } else {
+        run_interactive(&args, &mut gb);
+    }
+
+    if let Err(e) = gb.stop_capture() {
+        eprintln!("Failed to finish capture: {e}");
+    }
+
+    if let Some(path) = &args.screenshot {
+        if let Err(e) = gb.screenshot().save_png(path) {
+            eprintln!("Failed to write screenshot: {e}");
+        }
+    }
 }
 
-fn configure_logger() {
-    if env::var("RUST_LOG").is_err() {
-        unsafe { env::set_var("RUST_LOG", "error") }
+// run for --frames or in a window, with the state and movie options
+fn run_interactive(args: &RunArgs, gb: &mut Gameboy) {
+    if args.record_movie.is_some() {
+        gb.start_recording();
+    }
+
+    match args.frames {
+        Some(frames) => {
+            for _ in 0..frames {
+                if !gb.run_frame() {
+                    break;
+                }
+            }
+
+            if let Err(e) = gb.save_battery() {
+                eprintln!("Failed to write save file: {e}");
+            }
+        }
+        None => gb.boot(),
+    }
+
+    if let Some(path) = &args.save_state {
+        if let Err(e) = fs::write(path, gb.save_state()) {
+            eprintln!("Failed to write save state: {e}");
+        }
+    }
+
+    if let (Some(path), Some(movie)) = (&args.record_movie, gb.stop_recording()) {
+        if let Err(e) = fs::write(path, movie.to_text()) {
+            eprintln!("Failed to write movie: {e}");
+        }
     }
-    env_logger::init();
 }
 
-pub fn decode_file<P: AsRef<Path> + std::fmt::Debug + Copy>(path: P) {
-    let read_res = fs::read(path);
-
-    match read_res {
-        Ok(data) => {
-            let mut pos = 0;
-
-            while pos < data.len() {
-                let read_pos = pos;
-                let mut instruction_byte = data[pos];
-                let prefix = instruction_byte == 0xCB;
-                if prefix {
-                    instruction_byte = data[pos + 1];
-                    pos += 2;
-                } else {
-                    pos += 1;
+// run two machines linked by cable, for --frames or in one window
+fn run_linked(args: &RunArgs, link_rom: &Path) {
+    let player2_save_dir = args.save_dir.as_ref().map(|dir| dir.join("player2"));
+    let left = Gameboy::new(
+        false,
+        Some(&args.rom),
+        Some(options(args, args.save_dir.clone())),
+    );
+    let right = Gameboy::new(false, Some(link_rom), Some(options(args, player2_save_dir)));
+    let mut pair = LinkedPair::new(Box::new(left), Box::new(right));
+
+    match args.frames {
+        Some(frames) => {
+            for _ in 0..frames {
+                if !pair.run_frame() {
+                    break;
                 }
+            }
 
-                if let Some(instruction) = Instruction::from_byte(instruction_byte, prefix) {
-                    let description =
-                        format!("0x{}{:x}", if prefix { "cb" } else { "" }, instruction_byte);
-                    println!(
-                        "Position 0x{:x}={}: {:?}",
-                        read_pos, description, instruction
-                    );
-                } else {
-                    let description =
-                        format!("0x{}{:x}", if prefix { "cb" } else { "" }, instruction_byte);
-                    println!("Position 0x{:x}={}: ???", read_pos, description);
-                };
+            for gb in [&pair.left, &pair.right] {
+                if let Err(e) = gb.save_battery() {
+                    eprintln!("Failed to write save file: {e}");
+                }
             }
         }
-        Err(_) => panic!("Failed to load cartridge at {:?}", path),
+        None => pair.run(args.scale),
+    }
+
+    if let Some(path) = &args.screenshot {
+        if let Err(e) = pair.screenshot().save_png(path) {
+            eprintln!("Failed to write screenshot: {e}");
+        }
+    }
+}
+
+fn run_mooneye(dir: &Path) {
+    let reports = mooneye::run_dir(dir)
+        .unwrap_or_else(|e| panic!("Failed to read test ROMs in {:?}: {}", dir, e));
+    print!("{}", mooneye::summary_table(&reports));
+}
+
+fn print_info(path: &Path) {
+    let rom = fs::read(path).unwrap_or_else(|e| panic!("Failed to read {:?}: {}", path, e));
+    let header = match CartridgeHeader::from_rom(&rom) {
+        Ok(header) => header,
+        Err(e) => {
+            eprintln!("{:?} doesn't have a valid cartridge header: {e}", path);
+            std::process::exit(1);
+        }
+    };
+
+    let cartridge_type = match &header.cartridge_type {
+        Some(t) => format!("{:?}", t),
+        None => "unknown".to_string(),
     };
+    let computed_checksum = CartridgeHeader::computed_header_checksum(&rom);
+
+    println!("Title:           {}", header.title);
+    println!(
+        "Cartridge type:  {} (0x{:02x})",
+        cartridge_type, header.cartridge_type_byte
+    );
+    println!(
+        "ROM size:        {} KiB ({} banks)",
+        header.rom_banks * 16,
+        header.rom_banks
+    );
+    println!(
+        "RAM size:        {} KiB ({} banks)",
+        header.ram_banks * 8,
+        header.ram_banks
+    );
+    println!(
+        "CGB flag:        0x{:02x}{}",
+        header.cgb_flag,
+        match header.cgb_flag {
+            0xC0 => " (CGB only)",
+            0x80 => " (CGB enhanced)",
+            _ => "",
+        }
+    );
+    println!(
+        "SGB flag:        0x{:02x}{}",
+        header.sgb_flag,
+        if header.sgb_flag == 0x03 {
+            " (SGB functions)"
+        } else {
+            ""
+        }
+    );
+    println!(
+        "Destination:     {}",
+        if header.destination == 0 {
+            "Japan"
+        } else {
+            "overseas"
+        }
+    );
+    println!("Version:         {}", header.version);
+    println!(
+        "Header checksum: 0x{:02x} ({})",
+        header.header_checksum,
+        if computed_checksum == header.header_checksum {
+            "ok".to_string()
+        } else {
+            format!("expected 0x{:02x}", computed_checksum)
+        }
+    );
+    println!("Global checksum: 0x{:04x}", header.global_checksum);
+}
+
+fn configure_logger(log_level: &str) {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_level)).init();
+}
+
+pub fn decode_file<P: AsRef<Path> + std::fmt::Debug + Copy>(path: P) {
+    let data = fs::read(path).unwrap_or_else(|_| panic!("Failed to load cartridge at {:?}", path));
+
+    for line in disassemble(&data) {
+        println!("{}", line);
+    }
+}
+
+// One line per instruction, with its position, its bytes and what they
+// decode to
+fn disassemble(data: &[u8]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let prefix = data[pos] == 0xCB;
+        let Some(&instruction_byte) = data.get(pos + prefix as usize) else {
+            lines.push(format!("Position 0x{:x}=cb: ??? (cut off)", pos));
+            break;
+        };
+
+        let instruction = Instruction::from_byte(instruction_byte, prefix);
+        let length = match instruction {
+            Some(_) => Instruction::length(instruction_byte, prefix),
+            None => 1,
+        };
+        let end = data.len().min(pos + length);
+
+        let bytes: Vec<String> = data[pos..end]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let description = match instruction {
+            Some(instruction) if end == pos + length => format!("{:?}", instruction),
+            Some(instruction) => format!("{:?} (cut off)", instruction),
+            None => "???".to_string(),
+        };
+        lines.push(format!(
+            "Position 0x{:x}={}: {}",
+            pos,
+            bytes.join(" "),
+            description
+        ));
+
+        pos = end;
+    }
+
+    lines
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
-
-    use crate::gameboy::{Gameboy, GbOptions};
+    use super::disassemble;
 
     #[test]
-    fn test_roms() {
-        let mut gb = Gameboy::new(
-            true,
-            Some(&PathBuf::from("resources/cpu_instrs.gb")),
-            Some(GbOptions {
-                limit_speed: false,
-                render: false,
-            }),
-        );
-        gb.boot();
+    fn test_disassemble() {
+        // LD A,0x12; JP 0x0150; BIT 7,A; an unknown opcode; a cut off prefix
+        let lines = disassemble(&[0x3E, 0x12, 0xC3, 0x50, 0x01, 0xCB, 0x7F, 0xD3, 0xCB]);
+
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("Position 0x0=3e 12: LD("));
+        assert!(lines[1].starts_with("Position 0x2=c3 50 01: JP("));
+        assert!(lines[2].starts_with("Position 0x5=cb 7f: BIT("));
+        assert_eq!(lines[3], "Position 0x7=d3: ???");
+        assert_eq!(lines[4], "Position 0x8=cb: ??? (cut off)");
+
+        // an operand running past the end
+        assert!(disassemble(&[0xFA, 0x00])[0].ends_with("(cut off)"));
     }
 }
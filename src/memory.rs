@@ -1,13 +1,34 @@
-use std::{cell::RefCell, fs, rc::Rc};
+use std::{cell::Cell, fs, path::Path};
+
+use log::warn;
 
 use crate::{
     cartridge::{basic::BasicCartridge, Cartridge},
-    hardware_registers::{HardwareRegisters, RegisterAddresses, IE, LCDC},
+    hardware_registers::{
+        DisplayRegisters, HardwareRegisters, Interrupt, RegisterAddresses, IE, LCDC,
+    },
+    hdma::{Hdma, HDMA_BLOCK_SIZE},
+    hooks::Hooks,
+    joypad::{Buttons, Joypad},
+    model::{Model, DMG_BOOT_ROM_SIZE},
     ppu::{PPUMode, PPU},
+    savestate::{StateError, StateReader, StateWriter},
+    serial::{Serial, SerialDevice},
+    sgb::{Sgb, VRAM_TRANSFER_BYTES},
+    timer::Timer,
 };
 
 const BOOT_ROM_LOCK_REGISTER: u16 = 0xFF50;
-const BOOT_ROM_BIN_PATH: &'static str = "resources/dmg_boot.bin";
+const INTERRUPT_FLAG_REGISTER: u16 = 0xFF0F;
+const WRAM_BANK_SIZE: usize = 0x1000;
+// the CPU is halted for 8 M-cycles (16 in double speed) per HDMA block
+const HDMA_BLOCK_M_CYCLES: usize = 8;
+const LOGO_HEADER_ADDRESS: u16 = 0x0104;
+const LOGO_HEADER_LEN: u16 = 48;
+const LOGO_TILES_ADDRESS: u16 = 0x8010;
+const REGISTERED_TILE: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+// SGB VRAM transfers send the first 256 tiles shown on screen, 20 per row
+const SGB_TRANSFER_TILES_PER_ROW: u16 = 20;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum MemoryRegion {
@@ -88,65 +109,144 @@ pub struct MemoryBus {
     // 0xFF80 - 0xFFFE: High RAM Area
     //
     // 0xFFFF: Interrupt Enabled Register
-    boot_rom: [u8; 0x100],
+    boot_rom: Vec<u8>,
 
     memory: [u8; 0x10000],
 
+    // 0xC000 - 0xCFFF is always bank 0, 0xD000 - 0xDFFF is bank 1 on DMG
+    // and bank 1-7 (selected by SVBK) on CGB
+    wram: [[u8; WRAM_BANK_SIZE]; 8],
+
     cartridge: Box<dyn Cartridge>,
 
-    ppu: Rc<RefCell<PPU>>,
+    ppu: PPU,
+
+    timer: Timer,
+
+    joypad: Joypad,
+    // set when the program reads P1, to find lag frames. Reads go through
+    // &self, hence the cell.
+    joypad_polled: Cell<bool>,
+    serial: Serial,
+    // present when running on a SGB with a cartridge that supports it
+    sgb: Option<Sgb>,
+
+    hdma: Hdma,
+    // CPU steps to skip while a VRAM DMA is running
+    dma_cpu_stall: usize,
+    ppu_mode: PPUMode,
+    hblank_started: bool,
+    vblank_started: bool,
+    // whether any of the LCD interrupt sources STAT selects is active
+    stat_line: bool,
+
+    // set when a CGB cartridge runs on CGB hardware, enabling the banking
+    // and speed switch registers
+    cgb_mode: bool,
 
     pub registers: HardwareRegisters,
+
+    hooks: Hooks,
 }
 
 impl MemoryBus {
-    pub fn new_and_empty(cartridge: Option<Box<dyn Cartridge>>, ppu: Rc<RefCell<PPU>>) -> Self {
+    pub fn new_and_empty(cartridge: Option<Box<dyn Cartridge>>) -> Self {
         let bus = Self {
-            boot_rom: [0; 0x100],
+            boot_rom: vec![0; DMG_BOOT_ROM_SIZE],
             memory: [0; 0x10000],
+            wram: [[0; WRAM_BANK_SIZE]; 8],
             // gpu: GPU::new(),
             cartridge: cartridge.unwrap_or_else(|| Box::new(BasicCartridge::new())),
-            ppu,
+            ppu: PPU::new(),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            joypad_polled: Cell::new(false),
+            serial: Serial::new(),
+            sgb: None,
+            hdma: Hdma::new(),
+            dma_cpu_stall: 0,
+            ppu_mode: PPUMode::Mode2OAMScan,
+            hblank_started: false,
+            vblank_started: false,
+            stat_line: false,
+            cgb_mode: false,
             registers: HardwareRegisters::from_zeros(),
+            hooks: Hooks::default(),
         };
 
         bus
     }
 
-    pub fn new_and_load_bios(cartridge: Option<Box<dyn Cartridge>>, ppu: Rc<RefCell<PPU>>) -> Self {
-        let mut bus = Self::new_and_empty(cartridge, ppu);
-        let read_res = fs::read(BOOT_ROM_BIN_PATH);
+    pub fn new_and_load_bios(
+        cartridge: Option<Box<dyn Cartridge>>,
+        model: Model,
+        boot_rom_path: &Path,
+    ) -> Self {
+        let mut bus = Self::new_and_empty(cartridge);
+        let read_res = fs::read(boot_rom_path);
 
         match read_res {
             Ok(data) => {
-                let n_data = data.len();
-
-                for i in 0..n_data {
-                    bus.boot_rom[i] = data[i];
+                if data.len() != model.boot_rom_size() {
+                    warn!(
+                        "Boot rom at {:?} is 0x{:x} bytes, expected 0x{:x} for {:?}",
+                        boot_rom_path,
+                        data.len(),
+                        model.boot_rom_size(),
+                        model
+                    );
                 }
+
+                bus.boot_rom = data;
             }
-            Err(_) => panic!("Failed to load boot rom"),
+            Err(e) => panic!("Failed to load boot rom at {:?}: {}", boot_rom_path, e),
         };
 
         bus
     }
 
+    pub fn boot_rom(&self) -> &[u8] {
+        &self.boot_rom
+    }
+
     pub fn read_byte(&self, address: u16) -> u8 {
-        let booting = self.memory[BOOT_ROM_LOCK_REGISTER as usize] & 1 == 0;
+        let value = self.read(address);
+        self.hooks.memory_read(address, value);
+
+        if address == RegisterAddresses::P1.address() {
+            self.joypad_polled.set(true);
+        }
+
+        value
+    }
+
+    fn read(&self, address: u16) -> u8 {
+        let booting = self.boot_mode_active();
+
+        // the CGB boot ROM continues past the cartridge header
+        if booting && (0x200..0x900).contains(&address) && self.boot_rom.len() > 0x200 {
+            return self.boot_rom.get(address as usize).copied().unwrap_or(0xFF);
+        }
 
         let region = MemoryRegion::from_addr(address, booting);
 
         match region {
             MemoryRegion::BootROM => {
                 if booting {
-                    self.boot_rom[address as usize]
+                    self.boot_rom.get(address as usize).copied().unwrap_or(0xFF)
                 } else {
                     self.memory[address as usize]
                 }
             }
-            MemoryRegion::TileRAM => self.ppu.borrow().read_vram(address),
-            MemoryRegion::BackgroundMap => self.ppu.borrow().read_tile_map(address),
-            MemoryRegion::OAM => self.ppu.borrow().read_oam(address),
+            MemoryRegion::TileRAM => self.ppu.read_vram(self.vram_bank(), address),
+            MemoryRegion::BackgroundMap => self.ppu.read_tile_map(self.vram_bank(), address),
+            MemoryRegion::OAM => self.ppu.read_oam(address),
+            MemoryRegion::InterruptEnabledRegister => self.registers.IE.to_byte(),
+
+            MemoryRegion::WorkingRAM | MemoryRegion::EchoRAM => {
+                let (bank, offset) = self.wram_location(address);
+                self.wram[bank][offset]
+            }
 
             // Handle sections that go to the cartridge
             MemoryRegion::GameROMBank0
@@ -155,6 +255,10 @@ impl MemoryBus {
 
             MemoryRegion::IO => match RegisterAddresses::from_address(address) {
                 Some(reg) => match reg {
+                    RegisterAddresses::P1 => self.read_p1(),
+                    RegisterAddresses::SB => self.serial.read_sb(),
+                    // the clock speed bit only exists on the CGB
+                    RegisterAddresses::SC => self.serial.read_sc(self.cgb_mode),
                     RegisterAddresses::LCDC => self.registers.LCDC.to_byte(),
                     RegisterAddresses::LY => self.registers.LY,
                     RegisterAddresses::IE => self.registers.IE.to_byte(),
@@ -163,6 +267,28 @@ impl MemoryBus {
                     RegisterAddresses::WX => self.registers.WX,
                     RegisterAddresses::WY => self.registers.WY,
                     RegisterAddresses::STAT => self.registers.STAT.to_byte(),
+                    RegisterAddresses::BGP => self.registers.BGP,
+                    RegisterAddresses::OBP0 => self.registers.OBP0,
+                    RegisterAddresses::OBP1 => self.registers.OBP1,
+                    RegisterAddresses::BCPS if self.cgb_mode => self.ppu.bg_palettes().read_spec(),
+                    RegisterAddresses::BCPD if self.cgb_mode => self.ppu.bg_palettes().read_data(),
+                    RegisterAddresses::OCPS if self.cgb_mode => self.ppu.obj_palettes().read_spec(),
+                    RegisterAddresses::OCPD if self.cgb_mode => self.ppu.obj_palettes().read_data(),
+                    RegisterAddresses::DIV => self.timer.read_div(),
+                    RegisterAddresses::TIMA => self.timer.read_tima(),
+                    RegisterAddresses::TMA => self.timer.read_tma(),
+                    RegisterAddresses::TAC => self.timer.read_tac(),
+                    RegisterAddresses::KEY1 if self.cgb_mode => self.registers.KEY1.to_byte(),
+                    RegisterAddresses::VBK if self.cgb_mode => self.registers.VBK | 0xFE,
+                    RegisterAddresses::SVBK if self.cgb_mode => self.registers.SVBK | 0xF8,
+                    RegisterAddresses::HDMA5 if self.cgb_mode => self.hdma.read_control(),
+                    RegisterAddresses::FF72 => self.registers.undocumented[0],
+                    RegisterAddresses::FF73 => self.registers.undocumented[1],
+                    RegisterAddresses::FF74 if self.cgb_mode => self.registers.undocumented[2],
+                    RegisterAddresses::FF75 => self.registers.undocumented[3] | 0x8F,
+                    // PCM amplitude registers, silent until there is an APU
+                    RegisterAddresses::FF76 | RegisterAddresses::FF77 => 0x00,
+                    _ => 0xFF,
                 },
                 None => self.memory[address as usize],
             },
@@ -172,7 +298,22 @@ impl MemoryBus {
     }
 
     pub fn write_byte(&mut self, address: u16, value: u8) {
-        let booting = self.memory[BOOT_ROM_LOCK_REGISTER as usize] & 1 == 1;
+        self.hooks.memory_write(address, value);
+        self.write(address, value);
+    }
+
+    // read and write without calling the memory hooks, for tools looking
+    // at memory rather than the running program
+    pub fn peek(&self, address: u16) -> u8 {
+        self.read(address)
+    }
+
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.write(address, value);
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        let booting = self.boot_mode_active();
 
         let region = MemoryRegion::from_addr(address, booting);
 
@@ -181,9 +322,21 @@ impl MemoryBus {
             MemoryRegion::BootROM => {}
 
             // graphics RAM should be handled by the GPU
-            MemoryRegion::TileRAM => self.ppu.borrow_mut().write_vram(address, value),
-            MemoryRegion::OAM => self.ppu.borrow_mut().write_oam(address, value),
-            MemoryRegion::BackgroundMap => self.ppu.borrow_mut().write_tile_map(address, value),
+            MemoryRegion::TileRAM => {
+                let bank = self.vram_bank();
+                self.ppu.write_vram(bank, address, value)
+            }
+            MemoryRegion::OAM => self.ppu.write_oam(address, value),
+            MemoryRegion::InterruptEnabledRegister => self.registers.IE = IE::from(value),
+            MemoryRegion::BackgroundMap => {
+                let bank = self.vram_bank();
+                self.ppu.write_tile_map(bank, address, value)
+            }
+
+            MemoryRegion::WorkingRAM | MemoryRegion::EchoRAM => {
+                let (bank, offset) = self.wram_location(address);
+                self.wram[bank][offset] = value;
+            }
 
             // Handle sections that go to the cartridge
             MemoryRegion::GameROMBank0
@@ -192,6 +345,14 @@ impl MemoryBus {
 
             MemoryRegion::IO => match RegisterAddresses::from_address(address) {
                 Some(reg) => match reg {
+                    RegisterAddresses::P1 => {
+                        self.joypad.write(value);
+                        if let Some(sgb) = self.sgb.as_mut() {
+                            sgb.write_p1(value);
+                        }
+                    }
+                    RegisterAddresses::SB => self.serial.write_sb(value),
+                    RegisterAddresses::SC => self.serial.write_sc(value),
                     RegisterAddresses::LCDC => self.registers.LCDC = LCDC::from(value),
                     RegisterAddresses::LY => self.registers.LY = value,
                     RegisterAddresses::IE => self.registers.IE = IE::from(value),
@@ -200,6 +361,57 @@ impl MemoryBus {
                     RegisterAddresses::WX => self.registers.WX = value,
                     RegisterAddresses::WY => self.registers.WY = value,
                     RegisterAddresses::STAT => self.registers.STAT.write_byte(value),
+                    RegisterAddresses::BGP => self.registers.BGP = value,
+                    RegisterAddresses::OBP0 => self.registers.OBP0 = value,
+                    RegisterAddresses::OBP1 => self.registers.OBP1 = value,
+                    RegisterAddresses::BCPS if self.cgb_mode => {
+                        self.ppu.bg_palettes_mut().write_spec(value)
+                    }
+                    RegisterAddresses::BCPD if self.cgb_mode => {
+                        self.ppu.bg_palettes_mut().write_data(value)
+                    }
+                    RegisterAddresses::OCPS if self.cgb_mode => {
+                        self.ppu.obj_palettes_mut().write_spec(value)
+                    }
+                    RegisterAddresses::OCPD if self.cgb_mode => {
+                        self.ppu.obj_palettes_mut().write_data(value)
+                    }
+                    RegisterAddresses::DIV => {
+                        let overflowed = self.timer.write_div();
+                        if overflowed {
+                            self.request_interrupt(Interrupt::Timer)
+                        }
+                    }
+                    RegisterAddresses::TIMA => self.timer.write_tima(value),
+                    RegisterAddresses::TMA => self.timer.write_tma(value),
+                    RegisterAddresses::TAC => self.timer.write_tac(value),
+                    RegisterAddresses::KEY1 if self.cgb_mode => {
+                        self.registers.KEY1.write_byte(value)
+                    }
+                    RegisterAddresses::VBK if self.cgb_mode => self.registers.VBK = value & 1,
+                    RegisterAddresses::SVBK if self.cgb_mode => self.registers.SVBK = value & 0b111,
+                    RegisterAddresses::HDMA1 if self.cgb_mode => self.hdma.write_source_high(value),
+                    RegisterAddresses::HDMA2 if self.cgb_mode => self.hdma.write_source_low(value),
+                    RegisterAddresses::HDMA3 if self.cgb_mode => {
+                        self.hdma.write_destination_high(value)
+                    }
+                    RegisterAddresses::HDMA4 if self.cgb_mode => {
+                        self.hdma.write_destination_low(value)
+                    }
+                    RegisterAddresses::HDMA5 if self.cgb_mode => {
+                        let blocks = self.hdma.write_control(value);
+
+                        for _ in 0..blocks {
+                            self.copy_hdma_block();
+                        }
+                    }
+                    RegisterAddresses::FF72 => self.registers.undocumented[0] = value,
+                    RegisterAddresses::FF73 => self.registers.undocumented[1] = value,
+                    RegisterAddresses::FF74 if self.cgb_mode => {
+                        self.registers.undocumented[2] = value
+                    }
+                    RegisterAddresses::FF75 => self.registers.undocumented[3] = value & 0x70,
+                    _ => {}
                 },
                 None => self.memory[address as usize] = value,
             },
@@ -213,7 +425,304 @@ impl MemoryBus {
     }
 
     pub fn boot_mode_active(&self) -> bool {
-        self.memory[BOOT_ROM_LOCK_REGISTER as usize] == 0
+        self.memory[BOOT_ROM_LOCK_REGISTER as usize] & 1 == 0
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+    }
+
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.registers.KEY1.double_speed
+    }
+
+    // Called by STOP. If a speed switch has been armed through KEY1, flip
+    // the CPU speed and report that the STOP was consumed by the switch.
+    pub fn try_speed_switch(&mut self) -> bool {
+        if !self.cgb_mode || !self.registers.KEY1.switch_armed {
+            return false;
+        }
+
+        self.registers.KEY1.switch_armed = false;
+        self.registers.KEY1.double_speed = !self.registers.KEY1.double_speed;
+        self.timer.write_div();
+
+        true
+    }
+
+    pub fn cartridge(&self) -> &dyn Cartridge {
+        self.cartridge.as_ref()
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut dyn Cartridge {
+        self.cartridge.as_mut()
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut Hooks {
+        &mut self.hooks
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut PPU {
+        &mut self.ppu
+    }
+
+    // Advance the timer, PPU and DMA by one tick of the master clock (one
+    // dot), the timer by the given number of CPU clocks. Returns true if a
+    // frame was finished.
+    pub fn tick(&mut self, cpu_clocks: usize) -> bool {
+        self.step_timer(cpu_clocks);
+
+        self.ppu.step(&mut self.registers, self.cgb_mode);
+        self.update_ppu_lock(self.ppu.mode());
+        self.update_stat();
+        self.step_dma();
+
+        let frame_finished = std::mem::take(&mut self.vblank_started);
+        if frame_finished {
+            self.step_sgb();
+        }
+
+        frame_finished
+    }
+
+    pub fn enable_sgb(&mut self) {
+        self.sgb = Some(Sgb::new());
+    }
+
+    pub fn sgb(&self) -> Option<&Sgb> {
+        self.sgb.as_ref()
+    }
+
+    pub fn sgb_mut(&mut self) -> Option<&mut Sgb> {
+        self.sgb.as_mut()
+    }
+
+    // all WRAM banks, bank 0 first
+    pub fn wram(&self) -> &[u8] {
+        self.wram.as_flattened()
+    }
+
+    pub fn wram_mut(&mut self) -> &mut [u8] {
+        self.wram.as_flattened_mut()
+    }
+
+    // Set FF00-FF7F from a state that only records the register values
+    // (BESS), skipping the side effects writing them would have. Any DMA
+    // in progress is dropped and the PPU starts again at the start of LY.
+    pub fn restore_io_registers(&mut self, io: &[u8; 0x80]) {
+        for (address, &value) in (0xFF00..).zip(io.iter()) {
+            match RegisterAddresses::from_address(address) {
+                // the SGB would take this as a packet bit
+                Some(RegisterAddresses::P1) => self.joypad.write(value),
+                Some(RegisterAddresses::DIV) => self.timer.set_counter((value as u16) << 8),
+                Some(RegisterAddresses::KEY1) if self.cgb_mode => {
+                    self.registers.KEY1.switch_armed = value & 0x01 != 0;
+                    self.registers.KEY1.double_speed = value & 0x80 != 0;
+                }
+                // palette data comes separately, and HDMA5 would start a
+                // transfer
+                Some(
+                    RegisterAddresses::BCPD | RegisterAddresses::OCPD | RegisterAddresses::HDMA5,
+                ) => {}
+                _ => self.write(address, value),
+            }
+        }
+
+        self.hdma = Hdma::new();
+        self.dma_cpu_stall = 0;
+        self.ppu.restart_line(self.registers.LY);
+        self.ppu_mode = self.ppu.mode();
+        self.hblank_started = false;
+        self.vblank_started = false;
+    }
+
+    // The bus's own state and the small components hanging off it. The
+    // PPU, cartridge and SGB are saved separately, and the boot ROM is
+    // left as loaded.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.memory);
+        w.write_bytes(self.wram.as_flattened());
+        self.registers.save_state(w);
+        self.timer.save_state(w);
+        self.joypad.save_state(w);
+        self.hdma.save_state(w);
+        w.write_u32(self.dma_cpu_stall as u32);
+        w.write_u8(self.ppu_mode as u8);
+        w.write_bool(self.hblank_started);
+        w.write_bool(self.vblank_started);
+        w.write_bool(self.cgb_mode);
+        self.serial.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_into(&mut self.memory)?;
+        r.read_into(self.wram.as_flattened_mut())?;
+        self.registers.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.joypad.load_state(r)?;
+        self.hdma.load_state(r)?;
+        self.dma_cpu_stall = r.read_u32()? as usize;
+        self.ppu_mode = PPUMode::try_from(r.read_u8()?)?;
+        self.hblank_started = r.read_bool()?;
+        self.vblank_started = r.read_bool()?;
+        self.cgb_mode = r.read_bool()?;
+        self.serial.load_state(r)?;
+        self.stat_line = self.stat_sources_active();
+
+        Ok(())
+    }
+
+    fn read_p1(&self) -> u8 {
+        let value = self.joypad.read();
+
+        match self
+            .sgb
+            .as_ref()
+            .and_then(|sgb| sgb.read_p1_override(self.joypad.select_bits()))
+        {
+            Some(low) => (value & 0xF0) | low,
+            None => value,
+        }
+    }
+
+    pub fn buttons(&self) -> Buttons {
+        self.joypad.buttons()
+    }
+
+    // whether P1 has been read since the last call
+    pub fn take_joypad_polled(&self) -> bool {
+        self.joypad_polled.replace(false)
+    }
+
+    // plug a device into the link port, returning the one that was there
+    pub fn set_serial_device(&mut self, device: Box<dyn SerialDevice>) -> Box<dyn SerialDevice> {
+        self.serial.set_device(device)
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        if self.joypad.set_buttons(buttons) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.memory[INTERRUPT_FLAG_REGISTER as usize] |= interrupt.bit();
+    }
+
+    // the highest priority interrupt that is both requested and enabled
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending =
+            self.registers.IE.to_byte() & self.memory[INTERRUPT_FLAG_REGISTER as usize] & 0x1F;
+
+        Interrupt::ALL
+            .into_iter()
+            .find(|interrupt| pending & interrupt.bit() > 0)
+    }
+
+    // clear the request of an interrupt the CPU is about to handle
+    pub fn acknowledge_interrupt(&mut self, interrupt: Interrupt) {
+        self.memory[INTERRUPT_FLAG_REGISTER as usize] &= !interrupt.bit();
+        self.hooks.interrupt(interrupt);
+    }
+
+    // advance the timer and serial port by the given number of CPU clocks
+    pub fn step_timer(&mut self, cpu_clocks: usize) {
+        for _ in 0..cpu_clocks {
+            if self.timer.tick() {
+                self.request_interrupt(Interrupt::Timer);
+            }
+            if self.serial.tick(self.cgb_mode) {
+                self.request_interrupt(Interrupt::Serial);
+            }
+        }
+    }
+
+    fn vram_bank(&self) -> u8 {
+        if self.cgb_mode {
+            self.registers.VBK & 1
+        } else {
+            0
+        }
+    }
+
+    // map an address in working RAM (or its echo) to a bank and offset
+    fn wram_location(&self, address: u16) -> (usize, usize) {
+        let offset = (address as usize - 0xC000) % 0x2000;
+
+        if offset < WRAM_BANK_SIZE {
+            (0, offset)
+        } else if self.cgb_mode {
+            (
+                (self.registers.SVBK as usize).max(1),
+                offset - WRAM_BANK_SIZE,
+            )
+        } else {
+            (1, offset - WRAM_BANK_SIZE)
+        }
+    }
+
+    // Put the I/O registers, VRAM and OAM into the state the boot ROM of
+    // the given model leaves them in, and lock the boot ROM out, so
+    // execution can start straight at 0x0100.
+    pub fn skip_boot(&mut self, model: Model) {
+        // No boot ROM touches OAM, which powers up with unpredictable
+        // contents. Clear it so a reset doesn't keep the last sprites.
+        for address in 0xFE00..0xFEA0 {
+            self.ppu.write_oam(address, 0);
+        }
+
+        if model.leaves_logo_in_vram() {
+            self.load_boot_logo();
+        }
+
+        for (address, value) in model.post_boot_io() {
+            if address == RegisterAddresses::DIV.address() {
+                // writing DIV resets it, so set the internal counter directly
+                self.timer.set_counter((value as u16) << 8);
+            } else {
+                self.write_byte(address, value);
+            }
+        }
+    }
+
+    // Decompress the logo from the cartridge header into tiles 1-24 the
+    // same way the boot ROM does (every bit doubled in both directions),
+    // followed by the (R) tile, and lay the tiles out in the tile map.
+    fn load_boot_logo(&mut self) {
+        let mut tile_address = LOGO_TILES_ADDRESS;
+
+        for i in 0..LOGO_HEADER_LEN {
+            let logo_byte = self.read_byte(LOGO_HEADER_ADDRESS + i);
+
+            for nibble in [logo_byte >> 4, logo_byte & 0xF] {
+                let doubled = (0..4).fold(0u8, |acc, bit| {
+                    let set = (nibble >> bit) & 1;
+                    acc | (set << (bit * 2)) | (set << (bit * 2 + 1))
+                });
+
+                self.write_byte(tile_address, doubled);
+                self.write_byte(tile_address + 2, doubled);
+                tile_address += 4;
+            }
+        }
+
+        for (i, row) in REGISTERED_TILE.iter().enumerate() {
+            self.write_byte(tile_address + 2 * i as u16, *row);
+        }
+
+        for i in 0..12 {
+            self.write_byte(0x9904 + i, i as u8 + 1);
+            self.write_byte(0x9924 + i, i as u8 + 13);
+        }
+        self.write_byte(0x9910, 0x19);
     }
 
     pub fn wrapping_inc_byte(&mut self, address: u16, wrapping_val: u8) -> u8 {
@@ -224,5 +733,259 @@ impl MemoryBus {
         self.read_byte(address)
     }
 
-    pub fn update_ppu_lock(&mut self, _ppu_mode: PPUMode) {}
+    pub fn update_ppu_lock(&mut self, ppu_mode: PPUMode) {
+        if self.ppu_mode != ppu_mode {
+            match ppu_mode {
+                PPUMode::Mode0HorizontalBlank => self.hblank_started = true,
+                PPUMode::Mode1VerticalBlank => {
+                    self.vblank_started = true;
+                    self.request_interrupt(Interrupt::VBlank);
+                }
+                _ => {}
+            }
+
+            self.hooks.ppu_mode_change(ppu_mode, self.registers.LY);
+        }
+
+        self.ppu_mode = ppu_mode;
+    }
+
+    // Keep STAT's mode and LY=LYC bits current, and request the LCD
+    // interrupt when one of the sources STAT selects becomes active. The
+    // sources share one line, so another becoming active while one already
+    // is doesn't request it again.
+    fn update_stat(&mut self) {
+        self.registers.STAT.ppu_mode = self.ppu_mode as u8;
+        self.registers.STAT.lyc_eq_ly =
+            self.registers.LY == self.memory[DisplayRegisters::LYC.get_address()];
+
+        let line = self.stat_sources_active();
+        if line && !self.stat_line {
+            self.request_interrupt(Interrupt::LCD);
+        }
+        self.stat_line = line;
+    }
+
+    fn stat_sources_active(&self) -> bool {
+        let stat = &self.registers.STAT;
+
+        (stat.lyc_int_select && stat.lyc_eq_ly)
+            || match self.ppu_mode {
+                PPUMode::Mode0HorizontalBlank => stat.mode_0_int_select,
+                PPUMode::Mode1VerticalBlank => stat.mode_1_int_select,
+                PPUMode::Mode2OAMScan => stat.mode_2_int_select,
+                PPUMode::Mode3DrawingPixels => false,
+            }
+    }
+
+    // Copy an H-Blank DMA block if the PPU entered H-Blank since the last
+    // call
+    pub fn step_dma(&mut self) {
+        if !self.hblank_started {
+            return;
+        }
+        self.hblank_started = false;
+
+        if self.hdma.hblank_active() {
+            self.copy_hdma_block();
+            self.hdma.finish_hblank_block();
+        }
+    }
+
+    // draw the last frame again, for states that leave the screen out
+    pub fn redraw_screen(&mut self) {
+        let cgb_mode = self.cgb_mode();
+        self.ppu.redraw(&self.registers, cgb_mode);
+
+        if let Some(sgb) = self.sgb.as_mut() {
+            sgb.update_screen(self.ppu.get_shade_buffer());
+        }
+    }
+
+    // Once per frame on entering V-Blank, hand the SGB any VRAM transfer it
+    // is waiting for and the finished frame to colour
+    fn step_sgb(&mut self) {
+        let Some(pending) = self.sgb.as_ref().map(|sgb| sgb.pending_vram_transfer()) else {
+            return;
+        };
+
+        if pending {
+            let data = self.sgb_transfer_data();
+            self.sgb.as_mut().unwrap().vram_transfer(&data);
+        }
+
+        self.sgb
+            .as_mut()
+            .unwrap()
+            .update_screen(self.ppu.get_shade_buffer());
+    }
+
+    // The tile data of the BG tiles shown on screen, in screen order
+    fn sgb_transfer_data(&self) -> Vec<u8> {
+        let lcdc = &self.registers.LCDC;
+        let map_base: u16 = if lcdc.bg_tile_map_display_selct {
+            0x9C00
+        } else {
+            0x9800
+        };
+
+        let mut data = Vec::with_capacity(VRAM_TRANSFER_BYTES);
+        for tile in 0..(VRAM_TRANSFER_BYTES as u16 / 16) {
+            let map_address = map_base
+                + (tile / SGB_TRANSFER_TILES_PER_ROW) * 32
+                + tile % SGB_TRANSFER_TILES_PER_ROW;
+            let tile_num = self.read_byte(map_address);
+
+            let tile_address = if lcdc.bg_window_tile_data_select {
+                0x8000 + tile_num as u16 * 16
+            } else {
+                (0x9000 + (tile_num as i8 as i32) * 16) as u16
+            };
+
+            data.extend((0..16).map(|i| self.read_byte(tile_address + i)));
+        }
+
+        data
+    }
+
+    // Returns true (and counts down) while a VRAM DMA is holding the CPU
+    pub fn dma_stalling_cpu(&mut self) -> bool {
+        if self.dma_cpu_stall > 0 {
+            self.dma_cpu_stall -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn copy_hdma_block(&mut self) {
+        let (source, destination) = self.hdma.next_block();
+
+        for i in 0..HDMA_BLOCK_SIZE {
+            let value = self.read_byte(source.wrapping_add(i));
+            self.write_byte(destination + i, value);
+        }
+
+        self.dma_cpu_stall += if self.double_speed() {
+            HDMA_BLOCK_M_CYCLES * 2
+        } else {
+            HDMA_BLOCK_M_CYCLES
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{model::Model, ppu::PPUMode};
+
+    use super::MemoryBus;
+
+    #[test]
+    fn test_skip_boot_io_state() {
+        let mut bus = MemoryBus::new_and_empty(None);
+        assert!(bus.boot_mode_active());
+        bus.ppu_mut().write_oam(0xFE00, 0x55);
+
+        bus.skip_boot(Model::Dmg);
+
+        assert!(!bus.boot_mode_active());
+        assert_eq!(bus.read_byte(0xFF40), 0x91);
+        assert_eq!(bus.read_byte(0xFF47), 0xFC);
+        assert_eq!(bus.read_byte(0xFF0F), 0xE1);
+        assert_eq!(bus.read_byte(0x9910), 0x19);
+        assert_eq!(bus.read_byte(0x8190), 0x3C);
+        assert_eq!(bus.read_byte(0xFE00), 0x00);
+    }
+
+    #[test]
+    fn test_stat_interrupt() {
+        let mut bus = MemoryBus::new_and_empty(None);
+        bus.write_byte(0xFF45, 2);
+        bus.write_byte(0xFF41, 0x40);
+
+        while bus.registers.LY < 2 {
+            assert_eq!(bus.read_byte(0xFF0F) & 0x02, 0);
+            bus.tick(0);
+        }
+        assert_eq!(bus.read_byte(0xFF0F) & 0x02, 0x02);
+        assert_eq!(bus.read_byte(0xFF41) & 0x47, 0x46);
+
+        // LY=LYC stays set for the whole line, which is one interrupt
+        bus.write_byte(0xFF0F, 0);
+        bus.tick(0);
+        assert_eq!(bus.read_byte(0xFF0F) & 0x02, 0);
+    }
+
+    #[test]
+    fn test_serial_transfer() {
+        let mut bus = MemoryBus::new_and_empty(None);
+
+        bus.write_byte(0xFF01, b'O');
+        bus.write_byte(0xFF02, 0x81);
+        bus.step_timer(8 * 512 - 1);
+        assert_eq!(bus.read_byte(0xFF02), 0xFF);
+        assert_eq!(bus.read_byte(0xFF0F) & 0x08, 0);
+
+        bus.step_timer(1);
+        assert_eq!(bus.read_byte(0xFF01), 0xFF);
+        assert_eq!(bus.read_byte(0xFF02), 0x7F);
+        assert_eq!(bus.read_byte(0xFF0F) & 0x08, 0x08);
+    }
+
+    #[test]
+    fn test_cgb_wram_and_vram_banking() {
+        let mut bus = MemoryBus::new_and_empty(None);
+        bus.set_cgb_mode(true);
+
+        for bank in 1..8 {
+            bus.write_byte(0xFF70, bank);
+            bus.write_byte(0xD000, bank * 0x10);
+        }
+        bus.write_byte(0xFF70, 0);
+        assert_eq!(bus.read_byte(0xD000), 0x10);
+        bus.write_byte(0xFF70, 5);
+        assert_eq!(bus.read_byte(0xD000), 0x50);
+        assert_eq!(bus.read_byte(0xF000), 0x50);
+
+        bus.write_byte(0x8000, 0xAA);
+        bus.write_byte(0xFF4F, 1);
+        assert_eq!(bus.read_byte(0x8000), 0x00);
+        bus.write_byte(0x8000, 0x55);
+        bus.write_byte(0xFF4F, 0);
+        assert_eq!(bus.read_byte(0x8000), 0xAA);
+    }
+
+    #[test]
+    fn test_general_purpose_and_hblank_dma() {
+        let mut bus = MemoryBus::new_and_empty(None);
+        bus.set_cgb_mode(true);
+
+        for i in 0..0x40 {
+            bus.write_byte(0xC000 + i, i as u8);
+        }
+
+        // two blocks from 0xC000 to 0x8100, all at once
+        bus.write_byte(0xFF51, 0xC0);
+        bus.write_byte(0xFF52, 0x00);
+        bus.write_byte(0xFF53, 0x01);
+        bus.write_byte(0xFF54, 0x00);
+        bus.write_byte(0xFF55, 0x01);
+        assert_eq!(bus.read_byte(0x811F), 0x1F);
+        assert_eq!(bus.read_byte(0xFF55), 0xFF);
+        assert_eq!((0..100).filter(|_| bus.dma_stalling_cpu()).count(), 16);
+
+        // continues from 0xC020 to 0x8120, one block per H-Blank
+        bus.write_byte(0xFF55, 0x81);
+        assert_eq!(bus.read_byte(0xFF55), 0x01);
+        bus.update_ppu_lock(PPUMode::Mode3DrawingPixels);
+        bus.update_ppu_lock(PPUMode::Mode0HorizontalBlank);
+        bus.step_dma();
+        assert_eq!(bus.read_byte(0x812F), 0x2F);
+        assert_eq!(bus.read_byte(0x8130), 0x00);
+        assert_eq!(bus.read_byte(0xFF55), 0x00);
+
+        // cancelling keeps the remaining length with bit 7 set
+        bus.write_byte(0xFF55, 0x00);
+        assert_eq!(bus.read_byte(0xFF55), 0x80);
+    }
 }
@@ -0,0 +1,249 @@
+use std::path::PathBuf;
+
+use crate::{
+    ppu::{palette::rgb555_to_rgb888, DmgPalettes, Rgb},
+    registers::{FlagsRegister, Registers},
+};
+
+// Boot ROM sizes. The CGB boot ROM is split in two around the cartridge
+// header, with 0x0100 - 0x01FF always mapped to the cartridge.
+pub const DMG_BOOT_ROM_SIZE: usize = 0x100;
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
+// I/O register values left behind by the boot ROM, as documented in the
+// Pan Docs "Power Up Sequence". Where the docs leave a value unknown for a
+// model we fall back to the DMG value. Written in order, so the boot ROM
+// lock goes last.
+const POST_BOOT_IO: [(u16, u8); 45] = [
+    (0xFF00, 0xCF),
+    (0xFF01, 0x00),
+    (0xFF02, 0x7E),
+    (0xFF04, 0xAB),
+    (0xFF05, 0x00),
+    (0xFF06, 0x00),
+    (0xFF07, 0xF8),
+    (0xFF0F, 0xE1),
+    (0xFF10, 0x80),
+    (0xFF11, 0xBF),
+    (0xFF12, 0xF3),
+    (0xFF13, 0xFF),
+    (0xFF14, 0xBF),
+    (0xFF16, 0x3F),
+    (0xFF17, 0x00),
+    (0xFF18, 0xFF),
+    (0xFF19, 0xBF),
+    (0xFF1A, 0x7F),
+    (0xFF1B, 0xFF),
+    (0xFF1C, 0x9F),
+    (0xFF1D, 0xFF),
+    (0xFF1E, 0xBF),
+    (0xFF20, 0xFF),
+    (0xFF21, 0x00),
+    (0xFF22, 0x00),
+    (0xFF23, 0xBF),
+    (0xFF24, 0x77),
+    (0xFF25, 0xF3),
+    (0xFF26, 0xF1),
+    (0xFF40, 0x91),
+    (0xFF41, 0x85),
+    (0xFF42, 0x00),
+    (0xFF43, 0x00),
+    (0xFF44, 0x00),
+    (0xFF45, 0x00),
+    (0xFF46, 0xFF),
+    (0xFF47, 0xFC),
+    (0xFF48, 0xFF),
+    (0xFF49, 0xFF),
+    (0xFF4A, 0x00),
+    (0xFF4B, 0x00),
+    (0xFF4D, 0xFF),
+    (0xFF4F, 0xFF),
+    (0xFFFF, 0x00),
+    (0xFF50, 0x01),
+];
+
+// Screen colours for the four DMG shades, lightest first.
+const GREY_SHADES: [Rgb; 4] = [0xffffff, 0xb0b0b0, 0x525252, 0x000000];
+
+// The compatibility palettes (BG, OBJ0, OBJ1) the CGB boot ROM loads for a
+// DMG cartridge it doesn't recognise, as 15 bit CGB colours.
+const CGB_COMPAT_PALETTES: [[u16; 4]; 3] = [
+    [0x7FFF, 0x1BEF, 0x6180, 0x0000],
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Model {
+    // original DMG with the early boot ROM revision
+    Dmg0,
+    #[default]
+    Dmg,
+    // Game Boy Pocket / Light
+    Mgb,
+    Sgb,
+    Sgb2,
+    // Game Boy Color. Runs CGB cartridges in CGB mode and falls back to
+    // CgbDmgMode for DMG-only cartridges
+    Cgb,
+    // Game Boy Color running a DMG cartridge in compatibility mode
+    CgbDmgMode,
+}
+
+impl Model {
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::Cgb | Model::CgbDmgMode)
+    }
+
+    // The model as it was picked, before a CGB chose DMG mode for a
+    // cartridge, for comparing setups
+    pub fn family(&self) -> Model {
+        match self {
+            Model::CgbDmgMode => Model::Cgb,
+            model => *model,
+        }
+    }
+
+    pub fn boot_rom_size(&self) -> usize {
+        if self.is_cgb() {
+            CGB_BOOT_ROM_SIZE
+        } else {
+            DMG_BOOT_ROM_SIZE
+        }
+    }
+
+    pub fn default_boot_rom_path(&self) -> PathBuf {
+        PathBuf::from(match self {
+            Model::Dmg0 => "resources/dmg0_boot.bin",
+            Model::Dmg => "resources/dmg_boot.bin",
+            Model::Mgb => "resources/mgb_boot.bin",
+            Model::Sgb => "resources/sgb_boot.bin",
+            Model::Sgb2 => "resources/sgb2_boot.bin",
+            Model::Cgb | Model::CgbDmgMode => "resources/cgb_boot.bin",
+        })
+    }
+
+    // CPU registers the boot ROM hands over with. On the models that
+    // leave the flags depending on the cartridge, H and C are set unless
+    // the header checksum byte (0x014D) is zero.
+    pub fn post_boot_registers(&self, header_checksum: u8) -> Registers {
+        let checksum_flags = FlagsRegister {
+            zero: true,
+            subtraction: false,
+            half_carry: header_checksum != 0,
+            carry: header_checksum != 0,
+        };
+
+        let (a, f, bc, de, hl) = match self {
+            Model::Dmg0 => (0x01, FlagsRegister::default(), 0xFF13, 0x00C1, 0x8403),
+            Model::Dmg => (0x01, checksum_flags, 0x0013, 0x00D8, 0x014D),
+            Model::Mgb => (0xFF, checksum_flags, 0x0013, 0x00D8, 0x014D),
+            Model::Sgb => (0x01, FlagsRegister::default(), 0x0014, 0x0000, 0xC060),
+            Model::Sgb2 => (0xFF, FlagsRegister::default(), 0x0014, 0x0000, 0xC060),
+            Model::Cgb => (0x11, FlagsRegister::from(0x80), 0x0000, 0xFF56, 0x000D),
+            Model::CgbDmgMode => (0x11, FlagsRegister::from(0x80), 0x0000, 0x0008, 0x007C),
+        };
+
+        let mut registers = Registers {
+            a,
+            f,
+            ..Default::default()
+        };
+        registers.set_bc(bc);
+        registers.set_de(de);
+        registers.set_hl(hl);
+
+        registers
+    }
+
+    pub fn post_boot_io(&self) -> Vec<(u16, u8)> {
+        POST_BOOT_IO
+            .iter()
+            .map(|&(address, value)| {
+                let value = match (self, address) {
+                    (Model::Dmg0, 0xFF04) => 0x18,
+                    (Model::Dmg0, 0xFF41) => 0x81,
+                    (Model::Cgb | Model::CgbDmgMode, 0xFF02) => 0x7F,
+                    (Model::Cgb, 0xFF4D) => 0x7E,
+                    (Model::Cgb, 0xFF4F) => 0x00,
+                    _ => value,
+                };
+
+                (address, value)
+            })
+            .collect()
+    }
+
+    // Whether the boot ROM leaves the scrolled-in Nintendo logo in VRAM.
+    // The SGB boot ROM hands the logo to the SNES instead of drawing it.
+    pub fn leaves_logo_in_vram(&self) -> bool {
+        !self.is_sgb()
+    }
+
+    // Colours the DMG palette registers map onto. A CGB running a DMG
+    // cartridge colourises it with the compatibility palettes instead.
+    pub fn dmg_palettes(&self) -> DmgPalettes {
+        match self {
+            Model::Cgb | Model::CgbDmgMode => {
+                CGB_COMPAT_PALETTES.map(|palette| palette.map(rgb555_to_rgb888))
+            }
+            _ => [GREY_SHADES; 3],
+        }
+    }
+}
+
+// the name FromStr takes. CgbDmgMode is a CGB that picked DMG mode itself.
+impl std::fmt::Display for Model {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self.family() {
+            Model::Dmg0 => "dmg0",
+            Model::Dmg => "dmg",
+            Model::Mgb => "mgb",
+            Model::Sgb => "sgb",
+            Model::Sgb2 => "sgb2",
+            Model::Cgb | Model::CgbDmgMode => "cgb",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+impl std::str::FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dmg0" => Ok(Model::Dmg0),
+            "dmg" => Ok(Model::Dmg),
+            "mgb" => Ok(Model::Mgb),
+            "sgb" => Ok(Model::Sgb),
+            "sgb2" => Ok(Model::Sgb2),
+            "cgb" => Ok(Model::Cgb),
+            _ => Err(format!(
+                "unknown model '{s}', expected one of dmg0, dmg, mgb, sgb, sgb2, cgb"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::Model;
+
+    #[test_case(Model::Dmg0, 0x01)]
+    #[test_case(Model::Dmg, 0x01)]
+    #[test_case(Model::Mgb, 0xFF)]
+    #[test_case(Model::Sgb, 0x01)]
+    #[test_case(Model::Sgb2, 0xFF)]
+    #[test_case(Model::Cgb, 0x11)]
+    #[test_case(Model::CgbDmgMode, 0x11)]
+    fn test_post_boot_a_register(model: Model, a: u8) {
+        assert_eq!(model.post_boot_registers(0x00).a, a);
+    }
+}
@@ -0,0 +1,351 @@
+use std::fmt::{Display, Write};
+
+use crate::{joypad::Buttons, model::Model, savestate::StateError};
+
+// Input movies: the buttons held in each frame from a known starting
+// point, which replay to exactly the same run. Movies are text, one line
+// per frame so they diff well:
+//
+//   gbars movie 1
+//   rom 1a2b3c4d          (CRC-32 of the ROM file)
+//   model dmg
+//   boot skip             (or "rom", then the CRC-32 of the boot ROM it
+//                          ran)
+//   start power-on        (or "start state", then the save state in
+//                          base64 lines, then "end")
+//   input
+//   U..R...A              (one line per frame: UDLRsSBA, s being select
+//   ........ lag           and S start, "." when released. "lag" marks
+//                          frames where the game didn't read the joypad)
+const HEADER: &str = "gbars movie 1";
+const BUTTON_CHARS: &[u8; 8] = b"UDLRsSBA";
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE64_LINE_LENGTH: usize = 76;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MovieError {
+    Parse { line: usize, message: String },
+    WrongRom,
+    // the movie was made with another model, or with(out) the boot ROM
+    WrongSetup,
+    // the movie ran a different boot ROM, which can take a different
+    // number of cycles to hand over
+    WrongBootRom,
+    // a power-on movie played on a machine that has already run
+    NotAtPowerOn,
+    State(StateError),
+    // a frame's lag didn't match the recording, so the run has gone
+    // differently
+    Desync { frame: usize },
+}
+
+impl Display for MovieError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MovieError::Parse { line, message } => write!(f, "line {line}: {message}"),
+            MovieError::WrongRom => write!(f, "the movie was made with a different ROM"),
+            MovieError::WrongSetup => write!(
+                f,
+                "the movie was made with a different model or boot ROM setting"
+            ),
+            MovieError::WrongBootRom => write!(f, "the movie was made with a different boot ROM"),
+            MovieError::NotAtPowerOn => {
+                write!(f, "the movie starts at power on, but the machine has run")
+            }
+            MovieError::State(e) => write!(f, "failed to load the movie's start state: {e}"),
+            MovieError::Desync { frame } => write!(f, "the movie desynced at frame {frame}"),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<StateError> for MovieError {
+    fn from(e: StateError) -> Self {
+        MovieError::State(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MovieFrame {
+    pub buttons: Buttons,
+    // the game didn't read the joypad in this frame
+    pub lag: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_crc32: u32,
+    pub model: Model,
+    // the CRC-32 of the boot ROM it ran, or None if it skipped it
+    pub boot_rom_crc32: Option<u32>,
+    // a save state to start from, or power on if None
+    pub start_state: Option<Vec<u8>>,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn new(
+        rom_crc32: u32,
+        model: Model,
+        boot_rom_crc32: Option<u32>,
+        start_state: Option<Vec<u8>>,
+    ) -> Self {
+        Self {
+            rom_crc32,
+            model,
+            boot_rom_crc32,
+            start_state,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn lag_frames(&self) -> usize {
+        self.frames.iter().filter(|frame| frame.lag).count()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+
+        writeln!(text, "{HEADER}").unwrap();
+        writeln!(text, "rom {:08x}", self.rom_crc32).unwrap();
+        writeln!(text, "model {}", self.model).unwrap();
+        match self.boot_rom_crc32 {
+            None => writeln!(text, "boot skip").unwrap(),
+            Some(crc) => writeln!(text, "boot rom {crc:08x}").unwrap(),
+        }
+
+        match &self.start_state {
+            None => writeln!(text, "start power-on").unwrap(),
+            Some(state) => {
+                writeln!(text, "start state").unwrap();
+                let encoded = base64_encode(state);
+                for line in encoded.as_bytes().chunks(BASE64_LINE_LENGTH) {
+                    text.push_str(std::str::from_utf8(line).unwrap());
+                    text.push('\n');
+                }
+                writeln!(text, "end").unwrap();
+            }
+        }
+
+        writeln!(text, "input").unwrap();
+        for frame in &self.frames {
+            text.push_str(&buttons_to_text(frame.buttons));
+            if frame.lag {
+                text.push_str(" lag");
+            }
+            text.push('\n');
+        }
+
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<Self, MovieError> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim_end()));
+        let error = |line: usize, message: &str| MovieError::Parse {
+            line,
+            message: message.to_string(),
+        };
+
+        let mut next = |expected: &str| -> Result<(usize, &str), MovieError> {
+            let (n, line) = lines
+                .next()
+                .ok_or_else(|| error(0, &format!("expected {expected}, found the end")))?;
+            let value = line
+                .strip_prefix(expected)
+                .ok_or_else(|| error(n, &format!("expected {expected}")))?;
+
+            Ok((n, value.trim()))
+        };
+
+        next(HEADER)?;
+
+        let (n, rom) = next("rom")?;
+        let rom_crc32 = u32::from_str_radix(rom, 16).map_err(|_| error(n, "bad ROM CRC"))?;
+
+        let (n, model) = next("model")?;
+        let model = model.parse().map_err(|e: String| error(n, &e))?;
+
+        let (n, boot) = next("boot")?;
+        let boot_rom_crc32 = match boot.split_once(' ') {
+            None if boot == "skip" => None,
+            Some(("rom", crc)) => {
+                Some(u32::from_str_radix(crc, 16).map_err(|_| error(n, "bad boot ROM CRC"))?)
+            }
+            _ => return Err(error(n, "boot must be skip or rom and the boot ROM's CRC")),
+        };
+
+        let (n, start) = next("start")?;
+        let start_state = match start {
+            "power-on" => None,
+            "state" => {
+                let mut encoded = String::new();
+                loop {
+                    let (_, line) = next("")?;
+                    if line == "end" {
+                        break;
+                    }
+                    encoded.push_str(line);
+                }
+
+                Some(base64_decode(&encoded).ok_or_else(|| error(n, "bad base64 state"))?)
+            }
+            _ => return Err(error(n, "start must be power-on or state")),
+        };
+
+        next("input")?;
+        let mut frames = Vec::new();
+        while let Ok((n, line)) = next("") {
+            if line.is_empty() {
+                continue;
+            }
+
+            let (buttons, lag) = match line.split_once(' ') {
+                Some((buttons, "lag")) => (buttons, true),
+                Some(_) => return Err(error(n, "unexpected text after the buttons")),
+                None => (line, false),
+            };
+            let buttons =
+                buttons_from_text(buttons).ok_or_else(|| error(n, "buttons must be UDLRsSBA"))?;
+
+            frames.push(MovieFrame { buttons, lag });
+        }
+
+        Ok(Self {
+            rom_crc32,
+            model,
+            boot_rom_crc32,
+            start_state,
+            frames,
+        })
+    }
+}
+
+fn button_list(buttons: &Buttons) -> [bool; 8] {
+    [
+        buttons.up,
+        buttons.down,
+        buttons.left,
+        buttons.right,
+        buttons.select,
+        buttons.start,
+        buttons.b,
+        buttons.a,
+    ]
+}
+
+fn buttons_to_text(buttons: Buttons) -> String {
+    button_list(&buttons)
+        .iter()
+        .zip(BUTTON_CHARS)
+        .map(|(&pressed, &c)| if pressed { c as char } else { '.' })
+        .collect()
+}
+
+fn buttons_from_text(text: &str) -> Option<Buttons> {
+    if text.len() != BUTTON_CHARS.len() {
+        return None;
+    }
+
+    let mut pressed = [false; 8];
+    for ((p, c), expected) in pressed.iter_mut().zip(text.bytes()).zip(BUTTON_CHARS) {
+        *p = match c {
+            b'.' => false,
+            c if c == *expected => true,
+            _ => return None,
+        };
+    }
+
+    let [up, down, left, right, select, start, b, a] = pressed;
+    Some(Buttons {
+        right,
+        left,
+        up,
+        down,
+        a,
+        b,
+        select,
+        start,
+    })
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_CHARS[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut n = 0u32;
+    let mut bits = 0;
+
+    for c in text.bytes() {
+        let value = BASE64_CHARS.iter().position(|&b| b == c)? as u32;
+        n = n << 6 | value;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{joypad::Buttons, model::Model};
+
+    use super::{base64_decode, base64_encode, Movie, MovieFrame};
+
+    #[test]
+    fn test_text_round_trip() {
+        let mut movie = Movie::new(
+            0x1a2b3c4d,
+            Model::Cgb,
+            Some(0x41c3a5b9),
+            Some((0..=255).collect()),
+        );
+        movie.frames.push(MovieFrame::default());
+        movie.frames.push(MovieFrame {
+            buttons: Buttons {
+                up: true,
+                start: true,
+                a: true,
+                ..Default::default()
+            },
+            lag: true,
+        });
+
+        let text = movie.to_text();
+        assert!(text.contains("\nboot rom 41c3a5b9\n"));
+        assert!(text.ends_with("input\n........\nU....S.A lag\n"));
+        assert_eq!(Movie::parse(&text), Ok(movie));
+
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob"] {
+            assert_eq!(base64_decode(&base64_encode(data)).as_deref(), Some(data));
+        }
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+    }
+}
@@ -1,22 +1,31 @@
 pub mod oam;
+pub mod palette;
 pub mod sprite;
 
 use crate::{
     display::{SCREEN_HEIGHT_PIXELS, SCREEN_WIDTH_PIXELS},
-    hardware_registers::RegisterAddresses,
-    memory::MemoryBus,
-    ppu::{oam::OAMEntry, sprite::Sprite},
+    hardware_registers::HardwareRegisters,
+    ppu::{oam::OAMEntry, palette::ColorPalettes, sprite::Sprite},
+    savestate::{StateError, StateReader, StateWriter},
 };
 
-#[derive(Copy, Debug, Clone)]
-pub enum DrawColor {
-    BLACK,
-    DARKGREY,
-    LIGHTGREY,
-    WHITE,
-}
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+// BG map attribute bits (CGB only)
+const ATTR_PALETTE_MASK: u8 = 0b111;
+const ATTR_BANK: u8 = 1 << 3;
+const ATTR_X_FLIP: u8 = 1 << 5;
+const ATTR_Y_FLIP: u8 = 1 << 6;
+const ATTR_PRIORITY: u8 = 1 << 7;
+
+// 24 bit 0x00RRGGBB colour, as written to the screen buffer
+pub type Rgb = u32;
+
+// Colours used for the four DMG shades (lightest first) when not in CGB
+// mode, for the BG, OBJ0 and OBJ1 palettes respectively.
+pub type DmgPalettes = [[Rgb; 4]; 3];
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ColorIdx {
     Zero,
     One,
@@ -35,7 +44,19 @@ impl From<u8> for ColorIdx {
         }
     }
 }
-#[derive(Debug, Copy, Clone)]
+
+impl From<ColorIdx> for u8 {
+    fn from(value: ColorIdx) -> Self {
+        match value {
+            ColorIdx::Zero => 0,
+            ColorIdx::One => 1,
+            ColorIdx::Two => 2,
+            ColorIdx::Three => 3,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PPUMode {
     Mode0HorizontalBlank,
     Mode1VerticalBlank,
@@ -43,17 +64,48 @@ pub enum PPUMode {
     Mode3DrawingPixels,
 }
 
+impl TryFrom<u8> for PPUMode {
+    type Error = StateError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(PPUMode::Mode0HorizontalBlank),
+            1 => Ok(PPUMode::Mode1VerticalBlank),
+            2 => Ok(PPUMode::Mode2OAMScan),
+            3 => Ok(PPUMode::Mode3DrawingPixels),
+            _ => Err(StateError::Invalid("PPU mode")),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PPU {
     lx: usize,
     mode: PPUMode,
-    sprites: [Sprite; 384],
+    // tile data for VRAM bank 0 and (CGB only) bank 1
+    sprites: [[Sprite; 384]; 2],
     oam_entries: [OAMEntry; 40],
 
-    screen_buffer: [[DrawColor; SCREEN_WIDTH_PIXELS]; SCREEN_HEIGHT_PIXELS],
+    screen_buffer: [[Rgb; SCREEN_WIDTH_PIXELS]; SCREEN_HEIGHT_PIXELS],
+    // the DMG shade (0-3) of each pixel before colouring, which the SGB
+    // colours itself
+    shade_buffer: [[u8; SCREEN_WIDTH_PIXELS]; SCREEN_HEIGHT_PIXELS],
+
+    // internal line counter of the window, which only advances on lines
+    // where the window was drawn
+    window_line: u8,
+
+    dmg_palettes: DmgPalettes,
+    bg_palettes: ColorPalettes,
+    obj_palettes: ColorPalettes,
 
     tile_map_lower: [u8; 32 * 32],
     tile_map_upper: [u8; 32 * 32],
+
+    // CGB BG map attributes, which live in VRAM bank 1 at the tile map
+    // addresses
+    attr_map_lower: [u8; 32 * 32],
+    attr_map_upper: [u8; 32 * 32],
 }
 
 impl PPU {
@@ -61,11 +113,18 @@ impl PPU {
         Self {
             lx: 0,
             mode: PPUMode::Mode2OAMScan,
-            sprites: [Sprite::from_zeros(); 384],
+            sprites: [[Sprite::from_zeros(); 384]; 2],
             oam_entries: [OAMEntry::from_zeros(); 40],
-            screen_buffer: [[DrawColor::BLACK; SCREEN_WIDTH_PIXELS]; SCREEN_HEIGHT_PIXELS],
+            screen_buffer: [[0; SCREEN_WIDTH_PIXELS]; SCREEN_HEIGHT_PIXELS],
+            shade_buffer: [[0; SCREEN_WIDTH_PIXELS]; SCREEN_HEIGHT_PIXELS],
+            window_line: 0,
+            dmg_palettes: [[0xffffff, 0xb0b0b0, 0x525252, 0x000000]; 3],
+            bg_palettes: ColorPalettes::new(),
+            obj_palettes: ColorPalettes::new(),
             tile_map_lower: [0; 32 * 32],
             tile_map_upper: [0; 32 * 32],
+            attr_map_lower: [0; 32 * 32],
+            attr_map_upper: [0; 32 * 32],
         }
     }
 
@@ -75,34 +134,130 @@ impl PPU {
     // remain the same regardless of whether the CPU is in
     // Double Speed mode, so there are 4 dots per Normal
     // Speed M-cycle, and 2 per Double Speed M-cycle.
-    pub fn step(&mut self, memory: &mut MemoryBus) -> bool {
-        if self.update_scan_registers(memory) {
-            self.render_line(memory);
+    pub fn step(&mut self, registers: &mut HardwareRegisters, cgb_mode: bool) {
+        if self.update_scan_registers(registers) {
+            self.render_line(registers, cgb_mode);
         }
+    }
 
-        true
+    pub fn mode(&self) -> PPUMode {
+        self.mode
     }
-    pub fn read_tile_map(&self, address: u16) -> u8 {
-        if address < 0x9800 {
-            panic!("Invalid vram tile read address: {address}");
-        } else if address <= 0x9BFF {
-            self.tile_map_lower[address as usize - 0x9800]
-        } else if address <= 0x9FFF {
-            self.tile_map_lower[address as usize - 0x9C00]
-        } else {
-            panic!("Invalid vram tile read address: {address}");
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.lx as u16);
+        w.write_u8(self.mode as u8);
+        w.write_u8(self.window_line);
+
+        for bank in 0..2 {
+            for address in 0x8000..0x9800 {
+                w.write_u8(self.read_vram(bank, address));
+            }
+        }
+        for address in 0xFE00..0xFEA0 {
+            w.write_u8(self.read_oam(address));
+        }
+        w.write_bytes(&self.tile_map_lower);
+        w.write_bytes(&self.tile_map_upper);
+        w.write_bytes(&self.attr_map_lower);
+        w.write_bytes(&self.attr_map_upper);
+
+        for color in self.dmg_palettes.as_flattened() {
+            w.write_u32(*color);
         }
+        self.bg_palettes.save_state(w);
+        self.obj_palettes.save_state(w);
     }
 
-    pub fn write_tile_map(&mut self, address: u16, value: u8) {
-        if address < 0x9800 {
-            panic!("Invalid vram tile read address: {address}");
-        } else if address <= 0x9BFF {
-            self.tile_map_lower[address as usize - 0x9800] = value;
-        } else if address <= 0x9FFF {
-            self.tile_map_lower[address as usize - 0x9C00] = value;
-        } else {
-            panic!("Invalid vram tile read address: {address}");
+    // the last frame drawn, kept apart from the rest of the state as
+    // redraw() can stand in for it
+    pub fn save_screen(&self, w: &mut StateWriter) {
+        for color in self.screen_buffer.as_flattened() {
+            w.write_u32(*color);
+        }
+        w.write_bytes(self.shade_buffer.as_flattened());
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.lx = r.read_u16()? as usize;
+        self.mode = PPUMode::try_from(r.read_u8()?)?;
+        self.window_line = r.read_u8()?;
+
+        for bank in 0..2 {
+            for address in 0x8000..0x9800 {
+                self.write_vram(bank, address, r.read_u8()?);
+            }
+        }
+        for address in 0xFE00..0xFEA0 {
+            self.write_oam(address, r.read_u8()?);
+        }
+        r.read_into(&mut self.tile_map_lower)?;
+        r.read_into(&mut self.tile_map_upper)?;
+        r.read_into(&mut self.attr_map_lower)?;
+        r.read_into(&mut self.attr_map_upper)?;
+
+        for color in self.dmg_palettes.as_flattened_mut() {
+            *color = r.read_u32()?;
+        }
+        self.bg_palettes.load_state(r)?;
+        self.obj_palettes.load_state(r)?;
+
+        Ok(())
+    }
+
+    pub fn load_screen(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for color in self.screen_buffer.as_flattened_mut() {
+            *color = r.read_u32()?;
+        }
+        r.read_into(self.shade_buffer.as_flattened_mut())?;
+
+        Ok(())
+    }
+
+    // Go back to the first dot of line ly, for states that don't record
+    // the position within the line
+    pub fn restart_line(&mut self, ly: u8) {
+        self.lx = 0;
+        self.update_mode(ly);
+    }
+
+    pub fn set_dmg_palettes(&mut self, palettes: DmgPalettes) {
+        self.dmg_palettes = palettes;
+    }
+
+    pub fn bg_palettes(&self) -> &ColorPalettes {
+        &self.bg_palettes
+    }
+
+    pub fn bg_palettes_mut(&mut self) -> &mut ColorPalettes {
+        &mut self.bg_palettes
+    }
+
+    pub fn obj_palettes(&self) -> &ColorPalettes {
+        &self.obj_palettes
+    }
+
+    pub fn obj_palettes_mut(&mut self) -> &mut ColorPalettes {
+        &mut self.obj_palettes
+    }
+
+    pub fn read_tile_map(&self, bank: u8, address: u16) -> u8 {
+        match (bank, address) {
+            (0, 0x9800..=0x9BFF) => self.tile_map_lower[address as usize - 0x9800],
+            (0, 0x9C00..=0x9FFF) => self.tile_map_upper[address as usize - 0x9C00],
+            (_, 0x9800..=0x9BFF) => self.attr_map_lower[address as usize - 0x9800],
+            (_, 0x9C00..=0x9FFF) => self.attr_map_upper[address as usize - 0x9C00],
+            _ => panic!("Invalid vram tile map address: {address}"),
+        }
+    }
+
+    pub fn write_tile_map(&mut self, bank: u8, address: u16, value: u8) {
+        match (bank, address) {
+            (0, 0x9800..=0x9BFF) => self.tile_map_lower[address as usize - 0x9800] = value,
+            (0, 0x9C00..=0x9FFF) => self.tile_map_upper[address as usize - 0x9C00] = value,
+            (_, 0x9800..=0x9BFF) => self.attr_map_lower[address as usize - 0x9800] = value,
+            (_, 0x9C00..=0x9FFF) => self.attr_map_upper[address as usize - 0x9C00] = value,
+            _ => panic!("Invalid vram tile map address: {address}"),
         }
     }
 
@@ -133,84 +288,276 @@ impl PPU {
             .write_byte(offset_address % 4, val);
     }
 
-    pub fn read_vram(&self, address: u16) -> u8 {
+    pub fn read_vram(&self, bank: u8, address: u16) -> u8 {
         if address < 0x8000 || address > 0x97FF {
             panic!("Attempting vram read from invalid OAM address: {address}");
         }
 
         let offset_address = address as usize - 0x8000;
 
-        self.sprites
+        self.sprites[bank as usize & 1]
             .get(offset_address / 16)
             .unwrap()
             .read_byte(offset_address % 16)
     }
 
-    pub fn write_vram(&mut self, address: u16, val: u8) {
+    pub fn write_vram(&mut self, bank: u8, address: u16, val: u8) {
         if address < 0x8000 || address > 0x97FF {
             panic!("Attempting vram write from invalid OAM address: {address}");
         }
 
         let offset_address = address as usize - 0x8000;
 
-        self.sprites
+        self.sprites[bank as usize & 1]
             .get_mut(offset_address / 16)
             .unwrap()
             .write_byte(offset_address % 16, val)
     }
 
-    fn render_line(&mut self, memory: &mut MemoryBus) {
-        for x in 0..SCREEN_WIDTH_PIXELS {
-            self.screen_buffer[memory.registers.LY as usize][x] =
-                self.get_pixel(memory.registers.LY, x as u8, memory);
+    // Draw every line again from VRAM, OAM and the registers as they are
+    // now. Anything the game changed part way through the last frame,
+    // like a status bar's scroll, is drawn as it was at the end.
+    pub fn redraw(&mut self, registers: &HardwareRegisters, cgb_mode: bool) {
+        let mut registers = registers.clone();
+        let window_line = self.window_line;
+
+        for ly in 0..SCREEN_HEIGHT_PIXELS as u8 {
+            registers.LY = ly;
+            self.render_line(&registers, cgb_mode);
         }
+        self.window_line = window_line;
     }
 
-    fn get_pixel(&self, y: u8, x: u8, memory: &mut MemoryBus) -> DrawColor {
-        //TODO: OAM pixel
+    fn render_line(&mut self, registers: &HardwareRegisters, cgb_mode: bool) {
+        let ly = registers.LY;
+        let lcdc = &registers.LCDC;
+
+        if ly == 0 {
+            self.window_line = 0;
+        }
+
+        let window_visible =
+            lcdc.window_display_enable && registers.WY <= ly && registers.WX <= 166;
 
-        let pixel = self.get_bg_pixel(y, x, memory);
+        let objects = if lcdc.obj_display_enable {
+            self.scan_oam(ly, lcdc.obj_size, cgb_mode)
+        } else {
+            Vec::new()
+        };
 
-        //TODO: palettes
-        match pixel {
-            ColorIdx::Zero => DrawColor::WHITE,
-            ColorIdx::One => DrawColor::LIGHTGREY,
-            ColorIdx::Two => DrawColor::DARKGREY,
-            ColorIdx::Three => DrawColor::BLACK,
+        for x in 0..SCREEN_WIDTH_PIXELS {
+            let window_x = (x + 7).checked_sub(registers.WX as usize);
+
+            let (bg_idx, bg_attr) = match window_x {
+                Some(window_x) if window_visible => {
+                    self.window_pixel(window_x, registers, cgb_mode)
+                }
+                _ => self.bg_pixel(x, ly as usize, registers, cgb_mode),
+            };
+
+            // on DMG, clearing LCDC bit 0 blanks the BG and window. On CGB
+            // it instead takes away their priority over objects
+            let bg_idx = if cgb_mode || lcdc.bg_display {
+                bg_idx
+            } else {
+                ColorIdx::Zero
+            };
+
+            let mut shade = dmg_shade(registers.BGP, bg_idx);
+            let mut color = if cgb_mode {
+                self.bg_palettes
+                    .color(bg_attr & ATTR_PALETTE_MASK, bg_idx.into())
+            } else {
+                self.dmg_palettes[0][shade as usize]
+            };
+
+            if let Some((obj_idx, entry)) = self.object_pixel(x, ly, lcdc.obj_size, &objects) {
+                let bg_over_obj = bg_idx != ColorIdx::Zero
+                    && if cgb_mode {
+                        lcdc.bg_display && (entry.priority || bg_attr & ATTR_PRIORITY > 0)
+                    } else {
+                        entry.priority
+                    };
+
+                if !bg_over_obj {
+                    color = if cgb_mode {
+                        self.obj_palettes.color(entry.cgb_palette, obj_idx.into())
+                    } else {
+                        let obp = if entry.palette == 0 {
+                            registers.OBP0
+                        } else {
+                            registers.OBP1
+                        };
+
+                        shade = dmg_shade(obp, obj_idx);
+                        self.dmg_palettes[1 + entry.palette as usize][shade as usize]
+                    };
+                }
+            }
+
+            self.screen_buffer[ly as usize][x] = color;
+            self.shade_buffer[ly as usize][x] = shade;
+        }
+
+        if window_visible {
+            self.window_line = self.window_line.wrapping_add(1);
         }
     }
 
-    fn get_bg_pixel(&self, y: u8, x: u8, memory: &mut MemoryBus) -> ColorIdx {
-        let bg_x = x + memory.registers.SCX;
-        let bg_y = y + memory.registers.SCY;
+    // colour index and CGB attributes of the BG at screen position (x, y)
+    fn bg_pixel(
+        &self,
+        x: usize,
+        y: usize,
+        registers: &HardwareRegisters,
+        cgb_mode: bool,
+    ) -> (ColorIdx, u8) {
+        let bg_x = (x + registers.SCX as usize) % 256;
+        let bg_y = (y + registers.SCY as usize) % 256;
+
+        self.tile_map_pixel(
+            registers.LCDC.bg_tile_map_display_selct,
+            bg_x,
+            bg_y,
+            registers,
+            cgb_mode,
+        )
+    }
 
-        let bg_x_idx = bg_x / 8;
-        let bg_y_idx = bg_y / 8;
+    fn window_pixel(
+        &self,
+        window_x: usize,
+        registers: &HardwareRegisters,
+        cgb_mode: bool,
+    ) -> (ColorIdx, u8) {
+        self.tile_map_pixel(
+            registers.LCDC.window_tile_map_display_select,
+            window_x,
+            self.window_line as usize,
+            registers,
+            cgb_mode,
+        )
+    }
 
-        let bg_tile_idx: u8 = self.tile_map_upper[bg_x_idx as usize * 32 + bg_y_idx as usize];
+    fn tile_map_pixel(
+        &self,
+        upper_map: bool,
+        map_x: usize,
+        map_y: usize,
+        registers: &HardwareRegisters,
+        cgb_mode: bool,
+    ) -> (ColorIdx, u8) {
+        let map_idx = (map_y / 8) * 32 + map_x / 8;
+
+        let (tile_num, attr) = if upper_map {
+            (self.tile_map_upper[map_idx], self.attr_map_upper[map_idx])
+        } else {
+            (self.tile_map_lower[map_idx], self.attr_map_lower[map_idx])
+        };
+        let attr = if cgb_mode { attr } else { 0 };
 
-        let bg_tile: Sprite = self.sprites[bg_tile_idx as usize];
+        // 0x8000 addressing uses the tile number as an index from 0x8000,
+        // 0x8800 addressing treats it as signed and indexes from 0x9000
+        let tile_idx = if registers.LCDC.bg_window_tile_data_select {
+            tile_num as usize
+        } else {
+            (256 + tile_num as i8 as isize) as usize
+        };
+
+        let mut px = (map_x % 8) as u8;
+        let mut py = (map_y % 8) as u8;
+
+        if attr & ATTR_X_FLIP > 0 {
+            px = 7 - px;
+        }
+        if attr & ATTR_Y_FLIP > 0 {
+            py = 7 - py;
+        }
+
+        let bank = (attr & ATTR_BANK > 0) as usize;
 
-        bg_tile.pixel_at(bg_x % 8, bg_y % 8)
+        (self.sprites[bank][tile_idx].pixel_at(px, py), attr)
+    }
+
+    // Select the (up to 10) objects on this line, in drawing priority order.
+    // On CGB the earliest OAM entry wins, on DMG the leftmost object wins
+    // and OAM order breaks ties.
+    fn scan_oam(&self, ly: u8, tall_objects: bool, cgb_mode: bool) -> Vec<OAMEntry> {
+        let height = if tall_objects { 16 } else { 8 };
+
+        let mut objects: Vec<OAMEntry> = self
+            .oam_entries
+            .iter()
+            .filter(|entry| {
+                let top = entry.y as i16 - 16;
+                (top..top + height).contains(&(ly as i16))
+            })
+            .take(MAX_SPRITES_PER_LINE)
+            .copied()
+            .collect();
+
+        if !cgb_mode {
+            // stable sort keeps OAM order for objects with the same x
+            objects.sort_by_key(|entry| entry.x);
+        }
+
+        objects
+    }
+
+    fn object_pixel(
+        &self,
+        x: usize,
+        ly: u8,
+        tall_objects: bool,
+        objects: &[OAMEntry],
+    ) -> Option<(ColorIdx, OAMEntry)> {
+        let height = if tall_objects { 16 } else { 8 };
+
+        objects.iter().find_map(|entry| {
+            let left = entry.x as isize - 8;
+            let px = x as isize - left;
+
+            if !(0..8).contains(&px) {
+                return None;
+            }
+
+            let mut px = px as u8;
+            let mut py = ly.wrapping_sub(entry.y.wrapping_sub(16));
+
+            if entry.x_flip {
+                px = 7 - px;
+            }
+            if entry.y_flip {
+                py = height - 1 - py;
+            }
+
+            let tile_idx = if tall_objects {
+                (entry.tile_idx & 0xFE) + py / 8
+            } else {
+                entry.tile_idx
+            };
+
+            let idx = self.sprites[entry.bank as usize][tile_idx as usize].pixel_at(px, py % 8);
+
+            // colour 0 is transparent for objects
+            (idx != ColorIdx::Zero).then_some((idx, *entry))
+        })
     }
-    fn update_scan_registers(&mut self, memory: &mut MemoryBus) -> bool {
-        let (ly, render_line) = if self.lx >= 456 {
-            self.lx = 0;
 
-            (
-                memory.wrapping_inc_byte(RegisterAddresses::LY.address(), 153),
-                true,
-            )
+    // Advance one dot. Returns true when the PPU has just finished drawing
+    // a line, which is when we render it in one go.
+    fn update_scan_registers(&mut self, registers: &mut HardwareRegisters) -> bool {
+        if self.lx >= 455 {
+            self.lx = 0;
+            registers.LY = (registers.LY + 1) % 154;
         } else {
             self.lx += 1;
+        }
 
-            (memory.read_byte(RegisterAddresses::LY.address()), false)
-        };
+        let previous_mode = self.mode;
+        self.update_mode(registers.LY);
 
-        self.update_mode(ly);
-        memory.update_ppu_lock(self.mode);
-
-        render_line
+        previous_mode == PPUMode::Mode3DrawingPixels && self.mode == PPUMode::Mode0HorizontalBlank
     }
 
     fn update_mode(&mut self, ly: u8) {
@@ -227,7 +574,79 @@ impl PPU {
         }
     }
 
-    pub fn get_screen_buffer(&self) -> [[DrawColor; SCREEN_WIDTH_PIXELS]; SCREEN_HEIGHT_PIXELS] {
-        self.screen_buffer
+    pub fn get_screen_buffer(&self) -> &[[Rgb; SCREEN_WIDTH_PIXELS]; SCREEN_HEIGHT_PIXELS] {
+        &self.screen_buffer
+    }
+
+    pub fn get_shade_buffer(&self) -> &[[u8; SCREEN_WIDTH_PIXELS]; SCREEN_HEIGHT_PIXELS] {
+        &self.shade_buffer
+    }
+}
+
+// map a colour index through a DMG palette register to a shade
+fn dmg_shade(palette_register: u8, idx: ColorIdx) -> u8 {
+    (palette_register >> (u8::from(idx) * 2)) & 0b11
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::MemoryBus;
+
+    fn setup() -> MemoryBus {
+        let mut bus = MemoryBus::new_and_empty(None);
+
+        // tile 0 solid colour 3, tile 1 solid colour 1
+        for row in 0..8 {
+            bus.write_byte(0x8000 + row * 2, 0xFF);
+            bus.write_byte(0x8000 + row * 2 + 1, 0xFF);
+            bus.write_byte(0x8010 + row * 2, 0xFF);
+        }
+
+        // LCD on, BG on, objects on, 0x8000 tile data
+        bus.write_byte(0xFF40, 0b1001_0011);
+
+        bus
+    }
+
+    #[test]
+    fn test_dmg_bg_and_object() {
+        let mut bus = setup();
+        bus.write_byte(0xFF47, 0b1110_0100);
+        bus.write_byte(0xFF48, 0b0000_0100);
+
+        // object 0 at the top left corner using tile 1
+        bus.write_byte(0xFE00, 16);
+        bus.write_byte(0xFE01, 8);
+        bus.write_byte(0xFE02, 1);
+
+        let (registers, cgb_mode) = (bus.registers.clone(), bus.cgb_mode());
+        bus.ppu_mut().render_line(&registers, cgb_mode);
+
+        let line = &bus.ppu().get_screen_buffer()[0];
+        assert_eq!(line[0], 0xb0b0b0);
+        assert_eq!(line[8], 0x000000);
+    }
+
+    #[test]
+    fn test_cgb_bg_attributes() {
+        let mut bus = setup();
+        bus.set_cgb_mode(true);
+
+        // palette 2 colour 3 = pure green
+        bus.write_byte(0xFF68, 0x80 | (2 * 8 + 3 * 2));
+        bus.write_byte(0xFF69, 0xE0);
+        bus.write_byte(0xFF69, 0x03);
+
+        // tile map entry 0 uses palette 2
+        bus.write_byte(0xFF4F, 1);
+        bus.write_byte(0x9800, 2);
+        bus.write_byte(0xFF4F, 0);
+
+        let (registers, cgb_mode) = (bus.registers.clone(), bus.cgb_mode());
+        bus.ppu_mut().render_line(&registers, cgb_mode);
+
+        let line = &bus.ppu().get_screen_buffer()[0];
+        assert_eq!(line[0], 0x00FF00);
+        assert_eq!(line[8], 0xFFFFFF);
     }
 }
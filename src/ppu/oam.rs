@@ -5,23 +5,30 @@ pub struct OAMEntry {
     pub tile_idx: u8,
 
     // flags
-    pub priority: bool,
+    pub priority: bool, // BG and window colours 1-3 are drawn over the object
     pub y_flip: bool,
     pub x_flip: bool,
-    pub palette: u8, // can only be 0 or 1
+    pub palette: u8, // DMG palette, can only be 0 or 1
+    pub bank: u8,    // CGB VRAM bank, can only be 0 or 1
+    pub cgb_palette: u8,
 }
 
 impl From<[u8; 4]> for OAMEntry {
     fn from(value: [u8; 4]) -> Self {
-        Self {
+        let mut entry = Self {
             y: value[0],
             x: value[1],
             tile_idx: value[2],
-            priority: value[3] & 0x7F == 1,
-            y_flip: value[3] & 0x3F == 1,
-            x_flip: value[3] & 0x1F == 1,
-            palette: value[3] & 0xF,
-        }
+            priority: false,
+            y_flip: false,
+            x_flip: false,
+            palette: 0,
+            bank: 0,
+            cgb_palette: 0,
+        };
+        entry.write_byte(3, value[3]);
+
+        entry
     }
 }
 
@@ -35,12 +42,12 @@ impl OAMEntry {
             1 => self.x,
             2 => self.tile_idx,
             3 => {
-                ((self.priority as u8) << 6)
-                    | ((self.y_flip as u8) << 5)
-                    | ((self.x_flip as u8) << 4)
-                    | ((self.palette as u8) << 3)
-
-                //TODO: any weirdness from not capturing and then returning the unused bits??
+                ((self.priority as u8) << 7)
+                    | ((self.y_flip as u8) << 6)
+                    | ((self.x_flip as u8) << 5)
+                    | (self.palette << 4)
+                    | (self.bank << 3)
+                    | self.cgb_palette
             }
             _ => panic!("Invalid index into OAMEntry: {offset}"),
         }
@@ -52,10 +59,12 @@ impl OAMEntry {
             1 => self.x = val,
             2 => self.tile_idx = val,
             3 => {
-                self.priority = val & 0x7F == 1;
-                self.y_flip = val & 0x3F == 1;
-                self.x_flip = val & 0x1F == 1;
-                self.palette = val & 0xF;
+                self.priority = val & 0x80 > 0;
+                self.y_flip = val & 0x40 > 0;
+                self.x_flip = val & 0x20 > 0;
+                self.palette = (val >> 4) & 1;
+                self.bank = (val >> 3) & 1;
+                self.cgb_palette = val & 0b111;
             }
             _ => panic!("Invalid index into OAMEntry: {offset}"),
         }
@@ -0,0 +1,122 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// CGB colour palette RAM. Eight palettes of four colours, each colour a
+// little endian 15 bit RGB value (5 bits per channel, red lowest). The
+// CPU accesses it through a specification register (BCPS/OCPS) holding
+// the byte index and an auto-increment flag, and a data register
+// (BCPD/OCPD) reading or writing the byte at that index.
+#[derive(Debug, Clone)]
+pub struct ColorPalettes {
+    data: [u8; 64],
+    index: u8,
+    auto_increment: bool,
+}
+
+impl ColorPalettes {
+    pub fn new() -> Self {
+        Self {
+            data: [0xFF; 64],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.data);
+        w.write_u8(self.index);
+        w.write_bool(self.auto_increment);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_into(&mut self.data)?;
+        self.index = r.read_u8()? & 0x3F;
+        self.auto_increment = r.read_bool()?;
+
+        Ok(())
+    }
+
+    // the raw palette RAM, as the CPU sees it through the data register
+    pub fn data(&self) -> &[u8; 64] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8; 64] {
+        &mut self.data
+    }
+
+    pub fn read_spec(&self) -> u8 {
+        ((self.auto_increment as u8) << 7) | 0x40 | self.index
+    }
+
+    pub fn write_spec(&mut self, val: u8) {
+        self.index = val & 0x3F;
+        self.auto_increment = val & 0x80 > 0;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    pub fn write_data(&mut self, val: u8) {
+        self.data[self.index as usize] = val;
+
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    pub fn raw_color(&self, palette: u8, color: u8) -> u16 {
+        let offset = (palette as usize & 0b111) * 8 + (color as usize & 0b11) * 2;
+
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+
+    // 24 bit 0x00RRGGBB colour for the given palette and colour index
+    pub fn color(&self, palette: u8, color: u8) -> u32 {
+        rgb555_to_rgb888(self.raw_color(palette, color))
+    }
+}
+
+impl Default for ColorPalettes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Scale each 5 bit channel up to 8 bits, repeating the top bits in the
+// bottom so 0x1F maps to 0xFF.
+pub fn rgb555_to_rgb888(color: u16) -> u32 {
+    let expand = |channel: u16| {
+        let c = (channel & 0x1F) as u32;
+        (c << 3) | (c >> 2)
+    };
+
+    (expand(color) << 16) | (expand(color >> 5) << 8) | expand(color >> 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rgb555_to_rgb888, ColorPalettes};
+
+    #[test]
+    fn test_auto_increment_write() {
+        let mut palettes = ColorPalettes::new();
+        palettes.write_spec(0x80 | 0x08);
+
+        // palette 1, colour 0 = pure red, colour 1 = pure blue
+        for byte in [0x1F, 0x00, 0x00, 0x7C] {
+            palettes.write_data(byte);
+        }
+
+        assert_eq!(palettes.read_spec(), 0x80 | 0x40 | 0x0C);
+        assert_eq!(palettes.color(1, 0), 0xFF0000);
+        assert_eq!(palettes.color(1, 1), 0x0000FF);
+    }
+
+    #[test]
+    fn test_rgb555_conversion() {
+        assert_eq!(rgb555_to_rgb888(0x7FFF), 0xFFFFFF);
+        assert_eq!(rgb555_to_rgb888(0x1BEF), 0x7BFF31);
+        assert_eq!(rgb555_to_rgb888(0x0000), 0x000000);
+    }
+}
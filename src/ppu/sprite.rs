@@ -51,6 +51,6 @@ impl Sprite {
     }
 
     pub fn pixel_at(&self, x: u8, y: u8) -> ColorIdx {
-        self.colour[x as usize][y as usize]
+        self.colour[y as usize][x as usize]
     }
 }
@@ -0,0 +1,436 @@
+use std::{
+    fmt::Debug,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+
+use crate::{image::Image, ppu::Rgb, serial::SerialDevice};
+
+// Game Boy Printer, on the other end of the link cable.
+//
+// The Game Boy clocks packets to it: the magic bytes 88 33, a command, a
+// compression flag, a little endian data length, the data, a little
+// endian checksum of everything after the magic, then two bytes on which
+// the printer answers 0x81 (it's there) and its status. Every other byte
+// is answered with 0.
+const MAGIC: [u8; 2] = [0x88, 0x33];
+const ALIVE: u8 = 0x81;
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+const STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_BUSY: u8 = 0x02;
+const STATUS_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+pub const PAPER_WIDTH: usize = 160;
+// 20 tiles across, 16 bytes a tile
+const TILE_ROW_BYTES: usize = PAPER_WIDTH / 8 * 16;
+// the printer holds 9 DATA packets of two tile rows each
+const BUFFER_SIZE: usize = TILE_ROW_BYTES * 2 * 9;
+// rows of paper fed for each unit of margin
+const MARGIN_ROWS: usize = 8;
+// STATUS packets answered busy after a PRINT, for games that wait until
+// the printing has finished
+const BUSY_POLLS: usize = 4;
+// the default palette, 0 white to 3 black
+const DEFAULT_PALETTE: u8 = 0xE4;
+const DEFAULT_EXPOSURE: u8 = 0x40;
+const WHITE: Rgb = 0xFFFFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Receiving {
+    Magic(usize),
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+pub struct Printer {
+    // where each finished strip of paper is written
+    dir: PathBuf,
+
+    receiving: Receiving,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    data: Vec<u8>,
+    checksum: u16,
+    sum: u16,
+
+    // tile data waiting to be printed
+    buffer: Vec<u8>,
+    status: u8,
+    busy_polls: usize,
+    // the paper printed since it was last cut
+    strip: Vec<Rgb>,
+    printed: Vec<PathBuf>,
+}
+
+impl Printer {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            receiving: Receiving::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            sum: 0,
+            buffer: Vec::new(),
+            status: 0,
+            busy_polls: 0,
+            strip: Vec::new(),
+            printed: Vec::new(),
+        }
+    }
+
+    // the PNGs written so far
+    pub fn printed(&self) -> &[PathBuf] {
+        &self.printed
+    }
+
+    // Write out the paper printed since the last strip, if any. Strips
+    // finish by themselves when a print ends with a margin, as games feed
+    // the paper out to tear it off.
+    pub fn finish_strip(&mut self) -> io::Result<Option<PathBuf>> {
+        if self.strip.is_empty() {
+            return Ok(None);
+        }
+
+        let pixels = std::mem::take(&mut self.strip);
+        let image = Image::new(PAPER_WIDTH, pixels.len() / PAPER_WIDTH, pixels);
+
+        fs::create_dir_all(&self.dir)?;
+        let path = self.strip_path();
+        image.save_png(&path)?;
+        info!("Printed to {:?}", path);
+        self.printed.push(path.clone());
+
+        Ok(Some(path))
+    }
+
+    // gbars-print-<time>.png, numbered if several come in the same second
+    fn strip_path(&self) -> PathBuf {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut path = self.dir.join(format!("gbars-print-{secs}.png"));
+        let mut n = 1;
+        while path.exists() {
+            n += 1;
+            path = self.dir.join(format!("gbars-print-{secs}-{n}.png"));
+        }
+
+        path
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = self.status;
+        if self.busy_polls > 0 {
+            status |= STATUS_BUSY | STATUS_FULL;
+        } else if !self.buffer.is_empty() {
+            status |= STATUS_UNPROCESSED;
+        }
+
+        status
+    }
+
+    // the byte received is part of the checksum
+    fn summed(&mut self, byte: u8) {
+        self.sum = self.sum.wrapping_add(byte as u16);
+    }
+
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut reply = 0;
+
+        self.receiving = match self.receiving {
+            Receiving::Magic(i) if byte == MAGIC[i] => {
+                if i + 1 < MAGIC.len() {
+                    Receiving::Magic(i + 1)
+                } else {
+                    self.sum = 0;
+                    Receiving::Command
+                }
+            }
+            // out of sync, wait for the start of a packet
+            Receiving::Magic(_) => Receiving::Magic((byte == MAGIC[0]) as usize),
+            Receiving::Command => {
+                self.summed(byte);
+                self.command = byte;
+                Receiving::Compression
+            }
+            Receiving::Compression => {
+                self.summed(byte);
+                self.compressed = byte & 1 != 0;
+                Receiving::LengthLow
+            }
+            Receiving::LengthLow => {
+                self.summed(byte);
+                self.length = byte as usize;
+                Receiving::LengthHigh
+            }
+            Receiving::LengthHigh => {
+                self.summed(byte);
+                self.length |= (byte as usize) << 8;
+                self.data.clear();
+
+                if self.length > 0 {
+                    Receiving::Data
+                } else {
+                    Receiving::ChecksumLow
+                }
+            }
+            Receiving::Data => {
+                self.summed(byte);
+                self.data.push(byte);
+
+                if self.data.len() < self.length {
+                    Receiving::Data
+                } else {
+                    Receiving::ChecksumLow
+                }
+            }
+            Receiving::ChecksumLow => {
+                self.checksum = byte as u16;
+                Receiving::ChecksumHigh
+            }
+            Receiving::ChecksumHigh => {
+                self.checksum |= (byte as u16) << 8;
+                self.run_command();
+                Receiving::Alive
+            }
+            Receiving::Alive => {
+                reply = ALIVE;
+                Receiving::Status
+            }
+            Receiving::Status => {
+                reply = self.status();
+                if self.command == STATUS {
+                    self.busy_polls = self.busy_polls.saturating_sub(1);
+                }
+                Receiving::Magic(0)
+            }
+        };
+
+        reply
+    }
+
+    fn run_command(&mut self) {
+        if self.checksum != self.sum {
+            warn!("Printer packet checksum mismatch");
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            INIT => {
+                self.buffer.clear();
+                self.busy_polls = 0;
+            }
+            DATA => {
+                let data = if self.compressed {
+                    decompress(&self.data)
+                } else {
+                    std::mem::take(&mut self.data)
+                };
+                let space = BUFFER_SIZE.saturating_sub(self.buffer.len());
+                self.buffer.extend(data.into_iter().take(space));
+            }
+            PRINT if self.data.len() >= 4 => {
+                let [sheets, margins, palette, exposure] = self.data[..4].try_into().unwrap();
+                self.print(sheets, margins, palette, exposure);
+            }
+            STATUS => {}
+            command => warn!("Unknown printer command {:02x}", command),
+        }
+    }
+
+    // Print the buffer sheets times, between the margins in the high
+    // (before) and low (after) nibbles. A margin after cuts the strip.
+    fn print(&mut self, sheets: u8, margins: u8, palette: u8, exposure: u8) {
+        self.feed((margins >> 4) as usize);
+
+        let shades = shades(palette, exposure);
+        let rows = self.buffer.len() / TILE_ROW_BYTES * 8;
+        for _ in 0..sheets {
+            for y in 0..rows {
+                let tile_row = &self.buffer[y / 8 * TILE_ROW_BYTES..][..TILE_ROW_BYTES];
+                for x in 0..PAPER_WIDTH {
+                    let tile = &tile_row[x / 8 * 16..][..16];
+                    let (low, high) = (tile[y % 8 * 2], tile[y % 8 * 2 + 1]);
+                    let bit = 7 - x % 8;
+                    let colour = ((high >> bit) & 1) << 1 | ((low >> bit) & 1);
+                    self.strip.push(shades[colour as usize]);
+                }
+            }
+        }
+
+        self.buffer.clear();
+        self.busy_polls = BUSY_POLLS;
+
+        let after = (margins & 0x0F) as usize;
+        if after > 0 {
+            self.feed(after);
+            if let Err(e) = self.finish_strip() {
+                warn!("Failed to write printout: {}", e);
+            }
+        }
+    }
+
+    fn feed(&mut self, margin: usize) {
+        let rows = margin * MARGIN_ROWS;
+        self.strip
+            .extend(std::iter::repeat_n(WHITE, rows * PAPER_WIDTH));
+    }
+}
+
+// RLE: a control byte with bit 7 set repeats the next byte (control &
+// 0x7F) + 2 times, otherwise the next control + 1 bytes are copied
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+
+        if control & 0x80 != 0 {
+            let Some(&byte) = data.get(i) else { break };
+            out.extend(std::iter::repeat_n(byte, (control & 0x7F) as usize + 2));
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+
+    out
+}
+
+// The grey each colour number prints as. The palette maps colours to
+// shades like BGP, and exposure 0 to 0x7F goes from 25% lighter to 25%
+// darker than 0x40.
+fn shades(palette: u8, exposure: u8) -> [Rgb; 4] {
+    let palette = if palette == 0 {
+        DEFAULT_PALETTE
+    } else {
+        palette
+    };
+    let darkness = 0.75 + (exposure & 0x7F) as f32 / DEFAULT_EXPOSURE as f32 * 0.25;
+
+    [0, 1, 2, 3].map(|colour| {
+        let shade = (palette >> (colour * 2)) & 0b11;
+        let ink = (shade as f32 / 3.0 * darkness).min(1.0);
+        let grey = (255.0 * (1.0 - ink)).round() as u32;
+
+        grey << 16 | grey << 8 | grey
+    })
+}
+
+impl Debug for Printer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Printer")
+            .field("dir", &self.dir)
+            .field("receiving", &self.receiving)
+            .field("status", &self.status())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, byte: u8) -> Option<u8> {
+        Some(self.receive(byte))
+    }
+}
+
+impl Drop for Printer {
+    // paper still in the printer comes out when it's unplugged
+    fn drop(&mut self) {
+        if let Err(e) = self.finish_strip() {
+            warn!("Failed to write printout: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use crate::image::Image;
+
+    use super::{Printer, PAPER_WIDTH};
+
+    // send a packet, returning the alive and status bytes
+    fn packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        let mut body = vec![command, compressed as u8];
+        body.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        body.extend_from_slice(data);
+        let checksum = body.iter().map(|&b| b as u16).sum::<u16>();
+
+        for byte in [0x88, 0x33]
+            .iter()
+            .chain(&body)
+            .chain(&checksum.to_le_bytes())
+        {
+            assert_eq!(printer.receive(*byte), 0);
+        }
+        (printer.receive(0), printer.receive(0))
+    }
+
+    #[test]
+    fn test_print() {
+        let dir = env::temp_dir().join(format!("gbars-printer-{}", std::process::id()));
+        let mut printer = Printer::new(&dir);
+
+        assert_eq!(packet(&mut printer, 0x01, false, &[]), (0x81, 0));
+        assert_eq!(packet(&mut printer, 0x0F, false, &[]), (0x81, 0));
+
+        // one tile row of 0x7F bytes: colour 0 in the left column of each
+        // tile and 3 elsewhere, compressed as runs of 129, 129 and 62
+        let compressed = [0xFF, 0x7F, 0xFF, 0x7F, 0xBC, 0x7F];
+        assert_eq!(packet(&mut printer, 0x04, true, &compressed), (0x81, 0x08));
+        assert_eq!(packet(&mut printer, 0x04, false, &[]), (0x81, 0x08));
+
+        // a bad checksum is reported and the packet dropped
+        for byte in [0x88, 0x33, 0x0F, 0, 0, 0, 0x12, 0x34] {
+            printer.receive(byte);
+        }
+        assert_eq!((printer.receive(0), printer.receive(0)), (0x81, 0x09));
+
+        // one sheet, no margin before, one after, default palette
+        assert_eq!(
+            packet(&mut printer, 0x02, false, &[1, 0x01, 0xE4, 0x40]),
+            (0x81, 0x06)
+        );
+        assert_eq!(printer.printed().len(), 1);
+
+        let image = Image::load_png(&printer.printed()[0]).unwrap();
+        assert_eq!((image.width, image.height), (PAPER_WIDTH, 16));
+        assert_eq!(image.pixels[0], 0xFFFFFF);
+        assert_eq!(image.pixels[1], 0);
+        assert_eq!(image.pixels[8], 0xFFFFFF);
+        assert!(image.pixels[8 * PAPER_WIDTH..]
+            .iter()
+            .all(|&p| p == 0xFFFFFF));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::VecDeque;
+
+// runs of unchanged bytes shorter than this are kept in the changed bytes
+// around them rather than ending the run, as each run costs a few bytes
+const MIN_UNCHANGED_RUN: usize = 8;
+
+pub const DEFAULT_BUDGET: usize = 64 << 20;
+
+// History of save states for rewinding. A snapshot is taken every
+// `interval` frames. Only the newest is kept whole; each older one is
+// stored as its difference from the one after it: the XOR of the two,
+// with runs of unchanged (zero) bytes stored as their length. Most of the
+// machine doesn't change from one frame to the next, so this keeps a
+// minute of history to a few tens of MB. The oldest snapshots are dropped
+// to keep it within a byte budget all the same.
+#[derive(Debug)]
+pub struct Rewind {
+    interval: usize,
+    capacity: usize,
+    budget: usize,
+    frames_since_snapshot: usize,
+    // bytes in latest and deltas
+    memory_usage: usize,
+
+    latest: Option<Vec<u8>>,
+    // back is the delta between the latest and the one before it
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    // keep `seconds` of history at 60 frames a second, in at most
+    // `budget` bytes
+    pub fn new(seconds: usize, interval: usize, budget: usize) -> Self {
+        let interval = interval.max(1);
+
+        Self {
+            interval,
+            capacity: (seconds * 60).div_ceil(interval),
+            budget,
+            frames_since_snapshot: 0,
+            memory_usage: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    // count a finished frame, returning true if it is time for a snapshot
+    pub fn frame_finished(&mut self) -> bool {
+        self.frames_since_snapshot += 1;
+        self.frames_since_snapshot >= self.interval || self.latest.is_none()
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        self.frames_since_snapshot = 0;
+
+        if let Some(latest) = self.latest.take() {
+            self.memory_usage -= latest.len();
+            if latest.len() == state.len() {
+                let delta = encode_delta(&latest, &state);
+                self.memory_usage += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                // the machine changed shape, so the history doesn't apply
+                self.deltas.clear();
+                self.memory_usage = 0;
+            }
+        }
+        self.memory_usage += state.len();
+        self.latest = Some(state);
+
+        while self.deltas.len() > self.capacity
+            || (self.memory_usage > self.budget && !self.deltas.is_empty())
+        {
+            self.pop_oldest();
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some(delta) = self.deltas.pop_front() {
+            self.memory_usage -= delta.len();
+        }
+    }
+
+    // The newest snapshot at least `frames` frames old, or the oldest one
+    // if the history doesn't go back that far, with how many frames back
+    // it is. Snapshots newer than it are dropped, so it stays the latest.
+    pub fn rewind(&mut self, frames: usize) -> Option<(&[u8], usize)> {
+        let latest = self.latest.as_mut()?;
+        let mut rewound = self.frames_since_snapshot;
+
+        while rewound < frames {
+            let Some(delta) = self.deltas.pop_back() else {
+                break;
+            };
+            self.memory_usage -= delta.len();
+            apply_delta(latest, &delta);
+            rewound += self.interval;
+        }
+        self.frames_since_snapshot = 0;
+
+        Some((latest, rewound))
+    }
+
+    // frames of history available
+    pub fn len(&self) -> usize {
+        match self.latest {
+            Some(_) => self.deltas.len() * self.interval + self.frames_since_snapshot,
+            None => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    // bytes used by the history
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage
+    }
+
+    pub fn clear(&mut self) {
+        self.frames_since_snapshot = 0;
+        self.memory_usage = 0;
+        self.latest = None;
+        self.deltas.clear();
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        out.push(val as u8 | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        val |= (byte as usize & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return val;
+        }
+        shift += 7;
+    }
+}
+
+// A list of (unchanged length, changed length, changed bytes XORed with
+// the old ones) covering the data. Both snapshots are the same size.
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+
+    while pos < old.len() {
+        let unchanged_start = pos;
+        while pos < old.len() && old[pos] == new[pos] {
+            pos += 1;
+        }
+        if pos == old.len() {
+            break;
+        }
+
+        let changed_start = pos;
+        let mut unchanged_run = 0;
+        while pos < old.len() && unchanged_run < MIN_UNCHANGED_RUN {
+            if old[pos] == new[pos] {
+                unchanged_run += 1;
+            } else {
+                unchanged_run = 0;
+            }
+            pos += 1;
+        }
+        // leave the trailing unchanged bytes to the next run
+        let changed_end = pos - unchanged_run;
+        pos = changed_end;
+
+        write_varint(&mut out, changed_start - unchanged_start);
+        write_varint(&mut out, changed_end - changed_start);
+        out.extend(
+            old[changed_start..changed_end]
+                .iter()
+                .zip(&new[changed_start..changed_end])
+                .map(|(o, n)| o ^ n),
+        );
+    }
+
+    out
+}
+
+// turns either snapshot of encode_delta into the other
+fn apply_delta(data: &mut [u8], delta: &[u8]) {
+    let mut pos = 0;
+    let mut offset = 0;
+
+    while pos < delta.len() {
+        offset += read_varint(delta, &mut pos);
+        let changed = read_varint(delta, &mut pos);
+
+        for (byte, xor) in data[offset..offset + changed]
+            .iter_mut()
+            .zip(&delta[pos..pos + changed])
+        {
+            *byte ^= xor;
+        }
+        offset += changed;
+        pos += changed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_delta, encode_delta, Rewind};
+
+    #[test]
+    fn test_delta_round_trip() {
+        let old: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let mut new = old.clone();
+        new[0] = 0xFF;
+        new[500..504].fill(0);
+        new[510] ^= 1;
+        new[999] = 0;
+
+        let delta = encode_delta(&old, &new);
+        assert!(delta.len() < 40);
+
+        let mut data = old.clone();
+        apply_delta(&mut data, &delta);
+        assert_eq!(data, new);
+        apply_delta(&mut data, &delta);
+        assert_eq!(data, old);
+    }
+
+    #[test]
+    fn test_rewind_history() {
+        let mut rewind = Rewind::new(1, 2, usize::MAX);
+        assert!(rewind.rewind(1).is_none());
+
+        for frame in 0..200u8 {
+            if rewind.frame_finished() {
+                rewind.push(vec![frame; 16]);
+            }
+        }
+
+        // a snapshot every other frame, 60 frames of history
+        assert_eq!(rewind.len(), 61);
+        assert_eq!(rewind.rewind(4).unwrap(), (&[194; 16][..], 5));
+        assert_eq!(rewind.rewind(1000).unwrap(), (&[138; 16][..], 56));
+        assert_eq!(rewind.len(), 0);
+    }
+
+    #[test]
+    fn test_rewind_budget() {
+        const BUDGET: usize = 1 << 20;
+        let mut rewind = Rewind::new(60, 1, BUDGET);
+
+        // a minute of snapshots that change completely every frame
+        let mut state = vec![0u8; 16 * 1024];
+        for frame in 0..3600usize {
+            for (i, byte) in state.iter_mut().enumerate() {
+                *byte = (frame * 7 + i) as u8;
+            }
+            assert!(rewind.frame_finished());
+            rewind.push(state.clone());
+            assert!(rewind.memory_usage() <= BUDGET);
+        }
+
+        // the oldest were dropped, the rest still rewind
+        let frames = rewind.len();
+        assert!(frames > 30 && frames < 3600);
+        assert_eq!(rewind.memory_usage(), {
+            let deltas: usize = rewind.deltas.iter().map(Vec::len).sum();
+            deltas + state.len()
+        });
+        let (oldest, rewound) = rewind.rewind(3600).unwrap();
+        assert_eq!(rewound, frames);
+        assert_eq!(oldest[0], ((3599 - frames) * 7) as u8);
+        assert_eq!(rewind.memory_usage(), state.len());
+    }
+}
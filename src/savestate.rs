@@ -0,0 +1,327 @@
+use std::fmt::Display;
+
+// Save state layout (all numbers little endian):
+//
+// magic "GBARSST\0", u32 version, then a list of sections, each a 4 byte
+// tag, a u32 length and that many bytes, ending with an "END " section.
+//
+// Readers skip sections they don't know and ignore bytes past the end of
+// the fields they read from a section, so a newer state can add sections
+// or append fields to one without breaking older builds. Anything else
+// (changing or removing fields) needs a version bump and a migration,
+// which rewrites the sections of older states into the current layout
+// before anything is loaded from them.
+pub const STATE_MAGIC: &[u8; 8] = b"GBARSST\0";
+pub const STATE_VERSION: u32 = 3;
+const END_TAG: &[u8; 4] = b"END ";
+
+type Migration = fn(&mut SaveState) -> Result<(), StateError>;
+
+// MIGRATIONS[n] upgrades a version n + 1 state to version n + 2
+const MIGRATIONS: [Migration; STATE_VERSION as usize - 1] = [add_serial, add_rtc_register];
+
+// Version 2 gave the serial port its own state at the end of BUS, where
+// version 1 kept SB and SC in the bus's memory and finished transfers
+// straight away
+fn add_serial(state: &mut SaveState) -> Result<(), StateError> {
+    let (_, bus) = state
+        .sections
+        .iter_mut()
+        .find(|(tag, _)| tag == b"BUS ")
+        .ok_or(StateError::MissingSection(*b"BUS "))?;
+    let (sb, sc) = match bus.get(0xFF01..=0xFF02) {
+        Some(&[sb, sc]) => (sb, sc),
+        _ => return Err(StateError::Truncated),
+    };
+
+    let mut w = StateWriter { data: Vec::new() };
+    w.write_u8(sb);
+    w.write_u8(sc);
+    w.write_u16(0);
+    w.write_u8(0);
+    bus.extend_from_slice(&w.data);
+
+    Ok(())
+}
+
+// Version 3 appended the selected RTC register to MBC3 cartridges' CART
+// section. Before that the seconds register was always assumed, and other
+// cartridges ignore the extra byte.
+fn add_rtc_register(state: &mut SaveState) -> Result<(), StateError> {
+    if let Some((_, cart)) = state.sections.iter_mut().find(|(tag, _)| tag == b"CART") {
+        cart.push(0x08);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    MissingSection([u8; 4]),
+    WrongRom,
+    Invalid(&'static str),
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a gbars save state"),
+            StateError::UnsupportedVersion(v) => write!(
+                f,
+                "save state version {v} is newer than this build supports ({STATE_VERSION})"
+            ),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::MissingSection(tag) => write!(
+                f,
+                "save state has no {} section",
+                String::from_utf8_lossy(tag)
+            ),
+            StateError::WrongRom => write!(f, "save state was made with a different ROM"),
+            StateError::Invalid(what) => write!(f, "invalid save state: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut data = STATE_MAGIC.to_vec();
+        data.extend_from_slice(&STATE_VERSION.to_le_bytes());
+
+        Self { data }
+    }
+
+    // write a section, with its length filled in afterwards
+    pub fn section(&mut self, tag: &[u8; 4], write: impl FnOnce(&mut Self)) {
+        self.data.extend_from_slice(tag);
+        let length_at = self.data.len();
+        self.write_u32(0);
+
+        write(self);
+
+        let length = (self.data.len() - length_at - 4) as u32;
+        self.data[length_at..length_at + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.section(END_TAG, |_| {});
+        self.data
+    }
+
+    pub fn write_u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.data.push(val as u8);
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, val: u64) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    // fixed size data, the reader has to know the length
+    pub fn write_bytes(&mut self, val: &[u8]) {
+        self.data.extend_from_slice(val);
+    }
+
+    // variable size data, prefixed with its length
+    pub fn write_blob(&mut self, val: &[u8]) {
+        self.write_u32(val.len() as u32);
+        self.write_bytes(val);
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(StateError::Truncated)?;
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    pub fn read_into(&mut self, dest: &mut [u8]) -> Result<(), StateError> {
+        dest.copy_from_slice(self.read_bytes(dest.len())?);
+        Ok(())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_blob(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.read_u32()? as usize;
+        self.read_bytes(len)
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+// A parsed save state, migrated to the current version
+#[derive(Debug)]
+pub struct SaveState {
+    pub version: u32,
+    pub sections: Vec<([u8; 4], Vec<u8>)>,
+}
+
+impl SaveState {
+    // Parse a state from the start of data, which may carry more after the
+    // END section. Returns the state and the number of bytes it took up.
+    pub fn parse(data: &[u8]) -> Result<(Self, usize), StateError> {
+        let mut reader = StateReader::new(data);
+
+        if reader.read_bytes(STATE_MAGIC.len()).ok() != Some(&STATE_MAGIC[..]) {
+            return Err(StateError::BadMagic);
+        }
+
+        let version = reader.read_u32()?;
+        if version == 0 || version > STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let mut sections = Vec::new();
+        loop {
+            let tag: [u8; 4] = reader.read_bytes(4)?.try_into().unwrap();
+            let len = reader.read_u32()? as usize;
+            let body = reader.read_bytes(len)?;
+
+            if &tag == END_TAG {
+                break;
+            }
+            sections.push((tag, body.to_vec()));
+        }
+
+        let mut state = Self { version, sections };
+        state.migrate()?;
+
+        Ok((state, reader.position()))
+    }
+
+    fn migrate(&mut self) -> Result<(), StateError> {
+        while self.version < STATE_VERSION {
+            MIGRATIONS[self.version as usize - 1](self)?;
+            self.version += 1;
+        }
+
+        Ok(())
+    }
+
+    pub fn section(&self, tag: &[u8; 4]) -> Result<StateReader<'_>, StateError> {
+        self.find_section(tag)
+            .ok_or(StateError::MissingSection(*tag))
+    }
+
+    pub fn find_section(&self, tag: &[u8; 4]) -> Option<StateReader<'_>> {
+        self.sections
+            .iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, body)| StateReader::new(body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SaveState, StateError, StateWriter, END_TAG, STATE_MAGIC, STATE_VERSION};
+
+    #[test]
+    fn test_sections_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.section(b"TEST", |w| {
+            w.write_u8(0x12);
+            w.write_u16(0x3456);
+            w.write_blob(&[1, 2, 3]);
+        });
+        writer.section(b"NEW ", |w| w.write_u32(7));
+        let mut data = writer.finish();
+        let len = data.len();
+        data.extend_from_slice(b"trailing data");
+
+        let (state, consumed) = SaveState::parse(&data).unwrap();
+        assert_eq!(consumed, len);
+
+        let mut test = state.section(b"TEST").unwrap();
+        assert_eq!(test.read_u8(), Ok(0x12));
+        assert_eq!(test.read_u16(), Ok(0x3456));
+        assert_eq!(test.read_blob(), Ok(&[1, 2, 3][..]));
+        assert_eq!(test.read_u8(), Err(StateError::Truncated));
+
+        assert!(state.section(b"CPU ").is_err());
+        assert_eq!(
+            SaveState::parse(b"GBARSST\0\x09\0\0\0").unwrap_err(),
+            StateError::UnsupportedVersion(9)
+        );
+    }
+
+    #[test]
+    fn test_migrate_v1() {
+        let mut bus = vec![0; 0x10000];
+        bus[0xFF01] = 0x42;
+        bus[0xFF02] = 0x80;
+
+        let mut data = STATE_MAGIC.to_vec();
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(b"BUS ");
+        data.extend_from_slice(&(bus.len() as u32).to_le_bytes());
+        data.extend_from_slice(&bus);
+        data.extend_from_slice(END_TAG);
+        data.extend_from_slice(&0u32.to_le_bytes());
+
+        let (state, _) = SaveState::parse(&data).unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        let mut r = state.section(b"BUS ").unwrap();
+        r.read_bytes(0x10000).unwrap();
+        assert_eq!(r.read_bytes(5), Ok(&[0x42, 0x80, 0, 0, 0][..]));
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// CPU clocks per bit on the internal clock: 8192 Hz normally, and 32 times
+// that with the CGB's fast clock (SC bit 1). Counting CPU clocks means
+// double speed mode doubles both, as on hardware.
+const CLOCKS_PER_BIT: u16 = 512;
+const FAST_CLOCKS_PER_BIT: u16 = 16;
+
+// What's plugged into the other end of the link port
+pub trait SerialDevice: Debug + Send {
+    // This Game Boy has clocked out a whole byte. Returns the byte the
+    // device shifted back in at the same time, or None to hold the
+    // transfer (SC bit 7 stays set) until it has one, in which case it's
+    // asked again every bit period.
+    fn transfer(&mut self, byte: u8) -> Option<u8>;
+
+    // This Game Boy is waiting on the external clock with byte in SB, and
+    // asks every bit period whether the device has clocked a byte through.
+    // Returns the byte shifted in if it has.
+    fn poll_external(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+
+    // This Game Boy has stopped waiting on the external clock before a
+    // byte came through, by writing SC
+    fn cancel_external(&mut self) {}
+
+    // This Game Boy has stopped a transfer the device was holding, by
+    // writing SC
+    fn cancel_transfer(&mut self) {}
+}
+
+// Nothing plugged in: the data line floats high, and nobody drives the
+// clock
+#[derive(Debug, Default)]
+pub struct NullDevice;
+
+impl SerialDevice for NullDevice {
+    fn transfer(&mut self, _byte: u8) -> Option<u8> {
+        Some(0xFF)
+    }
+}
+
+// Writes every byte the Game Boy sends somewhere, like test ROMs printing
+// their results, and otherwise acts like nothing is plugged in
+pub struct SerialLogger {
+    out: Box<dyn Write + Send>,
+}
+
+impl SerialLogger {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self { out }
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(Box::new(io::stdout()))
+    }
+
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(Box::new(File::create(path)?)))
+    }
+}
+
+impl Debug for SerialLogger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SerialLogger").finish_non_exhaustive()
+    }
+}
+
+impl SerialDevice for SerialLogger {
+    fn transfer(&mut self, byte: u8) -> Option<u8> {
+        if let Err(e) = self.out.write_all(&[byte]).and_then(|_| self.out.flush()) {
+            log::warn!("Failed to log serial output: {e}");
+        }
+
+        Some(0xFF)
+    }
+}
+
+#[derive(Debug, Default)]
+struct CableState {
+    // SB of each side while it waits on the external clock
+    waiting: [Option<u8>; 2],
+    // a byte clocked into each side that it hasn't picked up yet
+    delivered: [Option<u8>; 2],
+}
+
+// One end of a link cable between two emulators in the same process
+#[derive(Debug)]
+pub struct LinkPort {
+    cable: Arc<Mutex<CableState>>,
+    side: usize,
+}
+
+// A cable with both its ends. Whichever side transfers on its internal
+// clock swaps bytes with the other, if that side is waiting on the
+// external clock by then. Otherwise it reads 0xFF, as with nothing
+// plugged in.
+pub fn link_cable() -> (LinkPort, LinkPort) {
+    let cable = Arc::new(Mutex::new(CableState::default()));
+
+    (
+        LinkPort {
+            cable: cable.clone(),
+            side: 0,
+        },
+        LinkPort { cable, side: 1 },
+    )
+}
+
+impl SerialDevice for LinkPort {
+    fn transfer(&mut self, byte: u8) -> Option<u8> {
+        let other = 1 - self.side;
+        let mut cable = self.cable.lock().unwrap();
+
+        match cable.waiting[other].take() {
+            Some(theirs) => {
+                cable.delivered[other] = Some(byte);
+                Some(theirs)
+            }
+            None => Some(0xFF),
+        }
+    }
+
+    fn poll_external(&mut self, byte: u8) -> Option<u8> {
+        let mut cable = self.cable.lock().unwrap();
+
+        let incoming = cable.delivered[self.side].take();
+        cable.waiting[self.side] = incoming.is_none().then_some(byte);
+
+        incoming
+    }
+
+    fn cancel_external(&mut self) {
+        let mut cable = self.cable.lock().unwrap();
+
+        cable.waiting[self.side] = None;
+    }
+}
+
+// The serial controller, SB and SC. A transfer started with SC bit 7 set
+// shifts SB out and the other side's byte in over 8 bits, on this side's
+// clock (SC bit 0) or the device's, then requests the serial interrupt.
+// The bytes are swapped whole when the transfer finishes, so SB doesn't
+// show the partly shifted value mid transfer.
+#[derive(Debug)]
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    // CPU clocks into the current bit
+    clocks: u16,
+    // bits left to shift on the internal clock
+    bits_left: u8,
+    // a transfer SC cut short had already been clocked through, and the
+    // next tick requests its interrupt
+    finished: bool,
+    device: Box<dyn SerialDevice>,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            sb: 0,
+            sc: 0,
+            clocks: 0,
+            bits_left: 0,
+            finished: false,
+            device: Box::new(NullDevice),
+        }
+    }
+
+    // plug a device in, returning the one that was there
+    pub fn set_device(&mut self, device: Box<dyn SerialDevice>) -> Box<dyn SerialDevice> {
+        std::mem::replace(&mut self.device, device)
+    }
+
+    pub fn read_sb(&self) -> u8 {
+        self.sb
+    }
+
+    pub fn write_sb(&mut self, val: u8) {
+        self.sb = val;
+    }
+
+    pub fn read_sc(&self, cgb_mode: bool) -> u8 {
+        if cgb_mode {
+            self.sc | 0x7C
+        } else {
+            self.sc | 0x7E
+        }
+    }
+
+    // setting bit 7 (re)starts a transfer
+    pub fn write_sc(&mut self, val: u8) {
+        let waiting = self.transferring() && !self.internal_clock();
+        if waiting && val & 0x81 != 0x80 {
+            // the other side may have clocked the byte through since this
+            // side last polled, in which case the transfer is done
+            match self.device.poll_external(self.sb) {
+                Some(byte) => {
+                    self.sb = byte;
+                    self.finished = true;
+                }
+                None => self.device.cancel_external(),
+            }
+        } else if self.transferring() && self.internal_clock() && self.bits_left == 0 {
+            self.device.cancel_transfer();
+        }
+
+        self.sc = val;
+        self.clocks = 0;
+        self.bits_left = if self.transferring() && self.internal_clock() {
+            8
+        } else {
+            0
+        };
+    }
+
+    fn transferring(&self) -> bool {
+        self.sc & 0x80 != 0
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.sc & 0x01 != 0
+    }
+
+    // advance by a single CPU clock. Returns true if a transfer finished
+    // and the serial interrupt should be requested
+    pub fn tick(&mut self, cgb_mode: bool) -> bool {
+        if std::mem::take(&mut self.finished) {
+            return true;
+        }
+        if !self.transferring() {
+            return false;
+        }
+
+        let clocks_per_bit = if cgb_mode && self.sc & 0x02 != 0 && self.internal_clock() {
+            FAST_CLOCKS_PER_BIT
+        } else {
+            CLOCKS_PER_BIT
+        };
+
+        self.clocks += 1;
+        if self.clocks < clocks_per_bit {
+            return false;
+        }
+        self.clocks = 0;
+
+        if self.internal_clock() {
+            // bits_left stays at 0 while the device holds the transfer
+            self.bits_left = self.bits_left.saturating_sub(1);
+            if self.bits_left > 0 {
+                return false;
+            }
+            match self.device.transfer(self.sb) {
+                Some(byte) => self.sb = byte,
+                None => return false,
+            }
+        } else {
+            match self.device.poll_external(self.sb) {
+                Some(byte) => self.sb = byte,
+                None => return false,
+            }
+        }
+
+        self.sc &= 0x7F;
+        true
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.sb);
+        w.write_u8(self.sc);
+        w.write_u16(self.clocks);
+        w.write_u8(self.bits_left);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.sb = r.read_u8()?;
+        self.sc = r.read_u8()?;
+        self.clocks = r.read_u16()?;
+        self.bits_left = r.read_u8()?;
+
+        Ok(())
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{link_cable, Serial};
+
+    // run both sides until neither has a transfer going, at most a frame
+    fn run(a: &mut Serial, b: &mut Serial) -> (bool, bool) {
+        let (mut a_done, mut b_done) = (false, false);
+        for _ in 0..70224 {
+            a_done |= a.tick(false);
+            b_done |= b.tick(false);
+        }
+
+        (a_done, b_done)
+    }
+
+    #[test]
+    fn test_transfers() {
+        let mut serial = Serial::new();
+
+        // 8 bits at 8192 Hz, shifting in 1s with nothing plugged in
+        serial.write_sb(0x42);
+        serial.write_sc(0x81);
+        assert!((1..8 * 512).all(|_| !serial.tick(false)));
+        assert!(serial.tick(false));
+        assert_eq!(serial.read_sb(), 0xFF);
+        assert_eq!(serial.read_sc(false), 0x7F);
+
+        // the CGB's fast clock is 32 times quicker
+        serial.write_sc(0x83);
+        assert!((1..8 * 16).all(|_| !serial.tick(true)));
+        assert!(serial.tick(true));
+
+        // and the external clock never comes
+        serial.write_sc(0x80);
+        assert!((0..70224).all(|_| !serial.tick(false)));
+        assert_eq!(serial.read_sc(false), 0xFE);
+
+        let (a_port, b_port) = link_cable();
+        let mut a = Serial::new();
+        let mut b = Serial::new();
+        a.set_device(Box::new(a_port));
+        b.set_device(Box::new(b_port));
+
+        // a clocks, b waits
+        a.write_sb(0x12);
+        b.write_sb(0x34);
+        b.write_sc(0x80);
+        a.write_sc(0x81);
+        assert_eq!(run(&mut a, &mut b), (true, true));
+        assert_eq!((a.read_sb(), b.read_sb()), (0x34, 0x12));
+
+        // b clocks with a not listening
+        b.write_sc(0x81);
+        assert_eq!(run(&mut a, &mut b), (false, true));
+        assert_eq!((a.read_sb(), b.read_sb()), (0x34, 0xFF));
+    }
+
+    #[test]
+    fn test_cancelled_wait() {
+        let (a_port, b_port) = link_cable();
+        let mut a = Serial::new();
+        let mut b = Serial::new();
+        a.set_device(Box::new(a_port));
+        b.set_device(Box::new(b_port));
+
+        // b waits for a while, then gives up before a clocks
+        b.write_sb(0x34);
+        b.write_sc(0x80);
+        run(&mut a, &mut b);
+        b.write_sc(0x00);
+
+        a.write_sb(0x12);
+        a.write_sc(0x81);
+        assert_eq!(run(&mut a, &mut b), (true, false));
+        assert_eq!((a.read_sb(), b.read_sb()), (0xFF, 0x34));
+
+        // b's next wait doesn't finish on the byte it missed
+        b.write_sb(0x56);
+        b.write_sc(0x80);
+        assert_eq!(run(&mut a, &mut b), (false, false));
+
+        a.write_sb(0x78);
+        a.write_sc(0x81);
+        assert_eq!(run(&mut a, &mut b), (true, true));
+        assert_eq!((a.read_sb(), b.read_sb()), (0x56, 0x78));
+
+        // rewriting SC with the wait still on doesn't cancel it
+        b.write_sb(0x9A);
+        b.write_sc(0x80);
+        run(&mut a, &mut b);
+        b.write_sc(0x80);
+        a.write_sc(0x81);
+        assert_eq!(run(&mut a, &mut b), (true, true));
+        assert_eq!((a.read_sb(), b.read_sb()), (0x9A, 0x56));
+
+        // a byte clocked in before b polled again still finishes b's
+        // transfer when it gives up
+        b.write_sb(0xBC);
+        b.write_sc(0x80);
+        run(&mut a, &mut b);
+        a.write_sb(0xDE);
+        a.write_sc(0x81);
+        assert!((0..8 * 512).any(|_| a.tick(false)));
+        b.write_sc(0x00);
+        assert!(b.tick(false));
+        assert_eq!((a.read_sb(), b.read_sb()), (0xBC, 0xDE));
+    }
+}
@@ -0,0 +1,688 @@
+use log::debug;
+
+use crate::{
+    display::{SCREEN_HEIGHT_PIXELS, SCREEN_WIDTH_PIXELS},
+    ppu::{palette::rgb555_to_rgb888, Rgb},
+    savestate::{StateError, StateReader, StateWriter},
+};
+
+// The SGB picture is a 256x224 SNES frame with the Game Boy screen in the
+// middle and the border around it.
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+const GB_SCREEN_X: usize = 48;
+const GB_SCREEN_Y: usize = 40;
+
+const PACKET_BYTES: usize = 16;
+const PACKET_BITS: usize = PACKET_BYTES * 8;
+
+// the screen is coloured in 8x8 cells, each using one of four palettes
+const ATTR_WIDTH: usize = SCREEN_WIDTH_PIXELS / 8;
+const ATTR_HEIGHT: usize = SCREEN_HEIGHT_PIXELS / 8;
+const ATTR_FILE_BYTES: usize = ATTR_WIDTH * ATTR_HEIGHT / 4;
+const ATTR_FILES: usize = 45;
+
+pub const VRAM_TRANSFER_BYTES: usize = 0x1000;
+const SYSTEM_PALETTES: usize = 512;
+const BORDER_TILE_BYTES: usize = 32;
+const BORDER_TILES: usize = 256;
+const BORDER_MAP_WIDTH: usize = 32;
+const BORDER_MAP_HEIGHT: usize = 28;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskMode {
+    Cancel,
+    Freeze,
+    Black,
+    Color0,
+}
+
+impl From<u8> for MaskMode {
+    fn from(value: u8) -> Self {
+        match value & 0b11 {
+            0 => MaskMode::Cancel,
+            1 => MaskMode::Freeze,
+            2 => MaskMode::Black,
+            _ => MaskMode::Color0,
+        }
+    }
+}
+
+// What to do with the 4KiB of VRAM shown on the next frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VramTransfer {
+    SystemPalettes,
+    BorderTiles(usize),
+    BorderMap,
+    AttributeFiles,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Pal01,
+    Pal23,
+    Pal03,
+    Pal12,
+    AttrBlk,
+    AttrLin,
+    AttrDiv,
+    AttrChr,
+    PalSet,
+    PalTrn,
+    MltReq,
+    ChrTrn,
+    PctTrn,
+    AttrTrn,
+    AttrSet,
+    MaskEn,
+}
+
+impl Command {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte >> 3 {
+            0x00 => Some(Command::Pal01),
+            0x01 => Some(Command::Pal23),
+            0x02 => Some(Command::Pal03),
+            0x03 => Some(Command::Pal12),
+            0x04 => Some(Command::AttrBlk),
+            0x05 => Some(Command::AttrLin),
+            0x06 => Some(Command::AttrDiv),
+            0x07 => Some(Command::AttrChr),
+            0x0A => Some(Command::PalSet),
+            0x0B => Some(Command::PalTrn),
+            0x11 => Some(Command::MltReq),
+            0x13 => Some(Command::ChrTrn),
+            0x14 => Some(Command::PctTrn),
+            0x15 => Some(Command::AttrTrn),
+            0x16 => Some(Command::AttrSet),
+            0x17 => Some(Command::MaskEn),
+            _ => None,
+        }
+    }
+}
+
+// Super Game Boy. Decodes the command packets a cartridge sends by pulsing
+// the P1 select lines, and composes the coloured screen and border.
+#[derive(Debug)]
+pub struct Sgb {
+    // packet reception
+    packet: [u8; PACKET_BYTES],
+    packet_bit: usize,
+    receiving: bool,
+    previous_p1: u8,
+    command_data: Vec<u8>,
+
+    // multiplayer (MLT_REQ)
+    players: u8,
+    current_player: u8,
+
+    // SGB palettes 0-3, as 15 bit colours. Colour 0 is shared by all four
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    attributes: [u8; ATTR_WIDTH * ATTR_HEIGHT],
+    attribute_files: Vec<[u8; ATTR_FILE_BYTES]>,
+    mask: MaskMode,
+
+    // border tiles (SNES 4bpp), tile map and palettes 4-7
+    border_tiles: Vec<u8>,
+    border_map: Vec<u8>,
+    border_palettes: [[u16; 16]; 4],
+
+    pending_transfer: Option<VramTransfer>,
+
+    // the coloured Game Boy screen as of the last frame
+    screen: Vec<Rgb>,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Self {
+            packet: [0; PACKET_BYTES],
+            packet_bit: 0,
+            receiving: false,
+            previous_p1: 0x30,
+            command_data: Vec::new(),
+            players: 1,
+            current_player: 0,
+            palettes: [[0x7FFF, 0x56B5, 0x294A, 0x0000]; 4],
+            system_palettes: vec![[0; 4]; SYSTEM_PALETTES],
+            attributes: [0; ATTR_WIDTH * ATTR_HEIGHT],
+            attribute_files: vec![[0; ATTR_FILE_BYTES]; ATTR_FILES],
+            mask: MaskMode::Cancel,
+            border_tiles: vec![0; BORDER_TILES * BORDER_TILE_BYTES],
+            border_map: vec![0; BORDER_MAP_WIDTH * BORDER_MAP_HEIGHT * 2],
+            border_palettes: [[0; 16]; 4],
+            pending_transfer: None,
+            screen: vec![0; SCREEN_WIDTH_PIXELS * SCREEN_HEIGHT_PIXELS],
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.packet);
+        w.write_u8(self.packet_bit as u8);
+        w.write_bool(self.receiving);
+        w.write_u8(self.previous_p1);
+        w.write_blob(&self.command_data);
+        w.write_u8(self.players);
+        w.write_u8(self.current_player);
+
+        for color in self.palettes.as_flattened() {
+            w.write_u16(*color);
+        }
+        for color in self.system_palettes.as_flattened() {
+            w.write_u16(*color);
+        }
+        w.write_bytes(&self.attributes);
+        w.write_bytes(self.attribute_files.as_flattened());
+        w.write_u8(self.mask as u8);
+
+        w.write_bytes(&self.border_tiles);
+        w.write_bytes(&self.border_map);
+        for color in self.border_palettes.as_flattened() {
+            w.write_u16(*color);
+        }
+
+        w.write_u8(match self.pending_transfer {
+            None => 0,
+            Some(VramTransfer::SystemPalettes) => 1,
+            Some(VramTransfer::BorderTiles(half)) => 2 + half as u8,
+            Some(VramTransfer::BorderMap) => 4,
+            Some(VramTransfer::AttributeFiles) => 5,
+        });
+    }
+
+    pub fn save_screen(&self, w: &mut StateWriter) {
+        for color in &self.screen {
+            w.write_u32(*color);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_into(&mut self.packet)?;
+        self.packet_bit = r.read_u8()? as usize;
+        if self.packet_bit > PACKET_BITS {
+            return Err(StateError::Invalid("SGB packet bit"));
+        }
+        self.receiving = r.read_bool()?;
+        self.previous_p1 = r.read_u8()?;
+        self.command_data = r.read_blob()?.to_vec();
+        self.players = r.read_u8()?;
+        self.current_player = r.read_u8()?;
+
+        for color in self.palettes.as_flattened_mut() {
+            *color = r.read_u16()?;
+        }
+        for color in self.system_palettes.as_flattened_mut() {
+            *color = r.read_u16()?;
+        }
+        r.read_into(&mut self.attributes)?;
+        r.read_into(self.attribute_files.as_flattened_mut())?;
+        self.mask = MaskMode::from(r.read_u8()?);
+
+        r.read_into(&mut self.border_tiles)?;
+        r.read_into(&mut self.border_map)?;
+        for color in self.border_palettes.as_flattened_mut() {
+            *color = r.read_u16()?;
+        }
+
+        self.pending_transfer = match r.read_u8()? {
+            0 => None,
+            1 => Some(VramTransfer::SystemPalettes),
+            n @ (2 | 3) => Some(VramTransfer::BorderTiles(n as usize - 2)),
+            4 => Some(VramTransfer::BorderMap),
+            5 => Some(VramTransfer::AttributeFiles),
+            _ => return Err(StateError::Invalid("SGB VRAM transfer")),
+        };
+
+        Ok(())
+    }
+
+    pub fn load_screen(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for color in self.screen.iter_mut() {
+            *color = r.read_u32()?;
+        }
+
+        Ok(())
+    }
+
+    // Watch writes to P1 for packet bits. Pulling both lines low resets
+    // the receiver for a new packet, then each pulse of P14 low sends a 0
+    // bit and each pulse of P15 low sends a 1 bit, LSB first.
+    pub fn write_p1(&mut self, val: u8) {
+        let select = val & 0x30;
+        let previous = self.previous_p1;
+        self.previous_p1 = select;
+
+        // the multiplayer adapter moves to the next controller when P15
+        // is released
+        if self.players > 1 && previous & 0x20 == 0 && select & 0x20 > 0 {
+            self.current_player = (self.current_player + 1) % self.players;
+        }
+
+        if select == 0x00 {
+            self.receiving = true;
+            self.packet = [0; PACKET_BYTES];
+            self.packet_bit = 0;
+            return;
+        }
+
+        if !self.receiving || previous != 0x30 || select == 0x30 {
+            return;
+        }
+
+        if self.packet_bit == PACKET_BITS {
+            // the stop bit after a full packet
+            self.receiving = false;
+            self.packet_received();
+            return;
+        }
+
+        if select == 0x10 {
+            self.packet[self.packet_bit / 8] |= 1 << (self.packet_bit % 8);
+        }
+        self.packet_bit += 1;
+    }
+
+    // The lower nibble of P1 while multiplayer is active: the current
+    // controller id when no line is selected, nothing pressed for the
+    // controllers we don't emulate.
+    pub fn read_p1_override(&self, select: u8) -> Option<u8> {
+        if self.players == 1 {
+            None
+        } else if select == 0x30 {
+            Some(0x0F - self.current_player)
+        } else if self.current_player != 0 {
+            Some(0x0F)
+        } else {
+            None
+        }
+    }
+
+    pub fn pending_vram_transfer(&self) -> bool {
+        self.pending_transfer.is_some()
+    }
+
+    // Complete a *_TRN command with the 4KiB shown on screen
+    pub fn vram_transfer(&mut self, data: &[u8]) {
+        let Some(transfer) = self.pending_transfer.take() else {
+            return;
+        };
+
+        match transfer {
+            VramTransfer::SystemPalettes => {
+                for (i, palette) in self.system_palettes.iter_mut().enumerate() {
+                    for (j, color) in palette.iter_mut().enumerate() {
+                        let offset = i * 8 + j * 2;
+                        *color = u16::from_le_bytes([data[offset], data[offset + 1]]);
+                    }
+                }
+            }
+            VramTransfer::BorderTiles(half) => {
+                let offset = half * VRAM_TRANSFER_BYTES;
+                self.border_tiles[offset..offset + VRAM_TRANSFER_BYTES].copy_from_slice(data);
+            }
+            VramTransfer::BorderMap => {
+                let map_len = self.border_map.len();
+                self.border_map.copy_from_slice(&data[..map_len]);
+
+                for (i, palette) in self.border_palettes.iter_mut().enumerate() {
+                    for (j, color) in palette.iter_mut().enumerate() {
+                        let offset = 0x800 + i * 32 + j * 2;
+                        *color = u16::from_le_bytes([data[offset], data[offset + 1]]);
+                    }
+                }
+            }
+            VramTransfer::AttributeFiles => {
+                for (i, file) in self.attribute_files.iter_mut().enumerate() {
+                    let offset = i * ATTR_FILE_BYTES;
+                    file.copy_from_slice(&data[offset..offset + ATTR_FILE_BYTES]);
+                }
+            }
+        }
+    }
+
+    // Colour a finished Game Boy frame, given as DMG shades
+    pub fn update_screen(&mut self, shades: &[[u8; SCREEN_WIDTH_PIXELS]; SCREEN_HEIGHT_PIXELS]) {
+        for (y, row) in shades.iter().enumerate() {
+            for (x, shade) in row.iter().enumerate() {
+                let color = match self.mask {
+                    MaskMode::Freeze => continue,
+                    MaskMode::Black => 0x0000,
+                    MaskMode::Color0 => self.palettes[0][0],
+                    MaskMode::Cancel => {
+                        let palette = self.attributes[(y / 8) * ATTR_WIDTH + x / 8];
+                        self.palettes[palette as usize][*shade as usize]
+                    }
+                };
+
+                self.screen[y * SCREEN_WIDTH_PIXELS + x] = rgb555_to_rgb888(color);
+            }
+        }
+    }
+
+    // The full 256x224 SGB picture: backdrop, Game Boy screen, then border
+    pub fn frame(&self) -> Vec<Rgb> {
+        let backdrop = rgb555_to_rgb888(self.palettes[0][0]);
+        let mut frame = vec![backdrop; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT];
+
+        for (y, row) in self.screen.chunks(SCREEN_WIDTH_PIXELS).enumerate() {
+            let start = (GB_SCREEN_Y + y) * SGB_SCREEN_WIDTH + GB_SCREEN_X;
+            frame[start..start + SCREEN_WIDTH_PIXELS].copy_from_slice(row);
+        }
+
+        for y in 0..SGB_SCREEN_HEIGHT {
+            for x in 0..SGB_SCREEN_WIDTH {
+                if let Some(color) = self.border_pixel(x, y) {
+                    frame[y * SGB_SCREEN_WIDTH + x] = color;
+                }
+            }
+        }
+
+        frame
+    }
+
+    fn border_pixel(&self, x: usize, y: usize) -> Option<Rgb> {
+        let map_offset = ((y / 8) * BORDER_MAP_WIDTH + x / 8) * 2;
+        let entry =
+            u16::from_le_bytes([self.border_map[map_offset], self.border_map[map_offset + 1]]);
+
+        let tile = (entry & 0xFF) as usize;
+        let palette = ((entry >> 10) & 0b111) as usize;
+
+        let mut px = x % 8;
+        let mut py = y % 8;
+        if entry & 0x4000 > 0 {
+            px = 7 - px;
+        }
+        if entry & 0x8000 > 0 {
+            py = 7 - py;
+        }
+
+        // SNES 4bpp: bitplanes 0/1 interleaved in the first 16 bytes,
+        // bitplanes 2/3 in the second
+        let tile_data = &self.border_tiles[tile * BORDER_TILE_BYTES..];
+        let bit = 7 - px;
+        let color_idx = (0..4).fold(0, |acc, plane| {
+            let byte = tile_data[(plane / 2) * 16 + py * 2 + plane % 2];
+            acc | (((byte >> bit) & 1) as usize) << plane
+        });
+
+        // colour 0 is transparent, and only palettes 4-7 belong to the border
+        if color_idx == 0 || !(4..8).contains(&palette) {
+            return None;
+        }
+
+        Some(rgb555_to_rgb888(
+            self.border_palettes[palette - 4][color_idx],
+        ))
+    }
+
+    fn packet_received(&mut self) {
+        self.command_data.extend_from_slice(&self.packet);
+
+        let packets = (self.command_data[0] & 0b111).max(1) as usize;
+        if self.command_data.len() >= packets * PACKET_BYTES {
+            let data = std::mem::take(&mut self.command_data);
+            self.execute(&data);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        let Some(command) = Command::from_byte(data[0]) else {
+            debug!("Ignoring SGB command 0x{:x}", data[0] >> 3);
+            return;
+        };
+
+        debug!("SGB command {:?}", command);
+
+        match command {
+            Command::Pal01 => self.set_palette_pair(0, 1, data),
+            Command::Pal23 => self.set_palette_pair(2, 3, data),
+            Command::Pal03 => self.set_palette_pair(0, 3, data),
+            Command::Pal12 => self.set_palette_pair(1, 2, data),
+            Command::AttrBlk => self.attr_blk(data),
+            Command::AttrLin => self.attr_lin(data),
+            Command::AttrDiv => self.attr_div(data),
+            Command::AttrChr => self.attr_chr(data),
+            Command::PalSet => {
+                for (i, palette) in self.palettes.iter_mut().enumerate() {
+                    let id = u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) as usize;
+                    *palette = self.system_palettes[id % SYSTEM_PALETTES];
+                }
+                // every palette shares colour 0 of palette 0
+                let color0 = self.palettes[0][0];
+                self.palettes.iter_mut().for_each(|p| p[0] = color0);
+
+                if data[9] & 0x80 > 0 {
+                    self.apply_attribute_file(data[9] & 0x3F);
+                }
+                if data[9] & 0x40 > 0 {
+                    self.mask = MaskMode::Cancel;
+                }
+            }
+            Command::PalTrn => self.pending_transfer = Some(VramTransfer::SystemPalettes),
+            Command::MltReq => {
+                self.players = match data[1] & 0b11 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.current_player = 0;
+            }
+            Command::ChrTrn => {
+                self.pending_transfer = Some(VramTransfer::BorderTiles((data[1] & 1) as usize))
+            }
+            Command::PctTrn => self.pending_transfer = Some(VramTransfer::BorderMap),
+            Command::AttrTrn => self.pending_transfer = Some(VramTransfer::AttributeFiles),
+            Command::AttrSet => {
+                self.apply_attribute_file(data[1] & 0x3F);
+                if data[1] & 0x40 > 0 {
+                    self.mask = MaskMode::Cancel;
+                }
+            }
+            Command::MaskEn => self.mask = MaskMode::from(data[1]),
+        }
+    }
+
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |i: usize| u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]);
+
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    fn set_attribute(&mut self, x: usize, y: usize, palette: u8) {
+        if x < ATTR_WIDTH && y < ATTR_HEIGHT {
+            self.attributes[y * ATTR_WIDTH + x] = palette & 0b11;
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let sets = (data[1] as usize).min((data.len() - 2) / 6);
+
+        for set in data[2..2 + sets * 6].chunks(6) {
+            let control = set[0] & 0b111;
+            let inside = set[1] & 0b11;
+            let border = (set[1] >> 2) & 0b11;
+            let outside = (set[1] >> 4) & 0b11;
+            let (x1, y1, x2, y2) = (
+                set[2] as usize,
+                set[3] as usize,
+                set[4] as usize,
+                set[5] as usize,
+            );
+
+            // with only one of inside/outside chosen, the border line goes
+            // with it
+            let border = match control {
+                0b001 => Some(inside),
+                0b100 => Some(outside),
+                _ if control & 0b010 > 0 => Some(border),
+                _ => None,
+            };
+
+            for y in 0..ATTR_HEIGHT {
+                for x in 0..ATTR_WIDTH {
+                    let inside_box = x > x1 && x < x2 && y > y1 && y < y2;
+                    let on_border = !inside_box && (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+
+                    if inside_box && control & 0b001 > 0 {
+                        self.set_attribute(x, y, inside);
+                    } else if on_border {
+                        if let Some(border) = border {
+                            self.set_attribute(x, y, border);
+                        }
+                    } else if !inside_box && !on_border && control & 0b100 > 0 {
+                        self.set_attribute(x, y, outside);
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let lines = (data[1] as usize).min(data.len() - 2);
+
+        for line in &data[2..2 + lines] {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0b11;
+
+            if line & 0x80 > 0 {
+                (0..ATTR_WIDTH).for_each(|x| self.set_attribute(x, number, palette));
+            } else {
+                (0..ATTR_HEIGHT).for_each(|y| self.set_attribute(number, y, palette));
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0b11;
+        let before = (data[1] >> 2) & 0b11;
+        let on_line = (data[1] >> 4) & 0b11;
+        let horizontal = data[1] & 0x40 > 0;
+        let line = data[2] as usize;
+
+        for y in 0..ATTR_HEIGHT {
+            for x in 0..ATTR_WIDTH {
+                let position = if horizontal { y } else { x };
+
+                let palette = match position.cmp(&line) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+                self.set_attribute(x, y, palette);
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let mut x = data[1] as usize;
+        let mut y = data[2] as usize;
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(ATTR_WIDTH * ATTR_HEIGHT);
+        let vertical = data[5] & 1 == 1;
+
+        for i in 0..count {
+            let Some(byte) = data.get(6 + i / 4) else {
+                break;
+            };
+            let palette = (byte >> (6 - (i % 4) * 2)) & 0b11;
+            self.set_attribute(x, y, palette);
+
+            if vertical {
+                y += 1;
+                if y == ATTR_HEIGHT {
+                    y = 0;
+                    x = (x + 1) % ATTR_WIDTH;
+                }
+            } else {
+                x += 1;
+                if x == ATTR_WIDTH {
+                    x = 0;
+                    y = (y + 1) % ATTR_HEIGHT;
+                }
+            }
+        }
+    }
+
+    fn apply_attribute_file(&mut self, file: u8) {
+        let Some(file) = self.attribute_files.get(file as usize) else {
+            return;
+        };
+
+        for i in 0..ATTR_WIDTH * ATTR_HEIGHT {
+            self.attributes[i] = (file[i / 4] >> (6 - (i % 4) * 2)) & 0b11;
+        }
+    }
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sgb, PACKET_BYTES};
+
+    fn send_packet(sgb: &mut Sgb, packet: &[u8; PACKET_BYTES]) {
+        sgb.write_p1(0x00);
+        sgb.write_p1(0x30);
+
+        for byte in packet {
+            for bit in 0..8 {
+                sgb.write_p1(if (byte >> bit) & 1 == 1 { 0x10 } else { 0x20 });
+                sgb.write_p1(0x30);
+            }
+        }
+
+        // stop bit
+        sgb.write_p1(0x20);
+        sgb.write_p1(0x30);
+    }
+
+    #[test]
+    fn test_pal01_packet() {
+        let mut sgb = Sgb::new();
+
+        let mut packet = [0; PACKET_BYTES];
+        packet[0] = 0x01;
+        packet[1..3].copy_from_slice(&0x001Fu16.to_le_bytes());
+        packet[7..9].copy_from_slice(&0x7C00u16.to_le_bytes());
+        send_packet(&mut sgb, &packet);
+
+        assert_eq!(sgb.palettes[0][0], 0x001F);
+        assert_eq!(sgb.palettes[3][0], 0x001F);
+        assert_eq!(sgb.palettes[0][3], 0x7C00);
+    }
+
+    #[test]
+    fn test_mlt_req_and_attr_div() {
+        let mut sgb = Sgb::new();
+
+        let mut packet = [0; PACKET_BYTES];
+        packet[0] = (0x11 << 3) | 1;
+        packet[1] = 1;
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.read_p1_override(0x30), Some(0x0F));
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.read_p1_override(0x30), Some(0x0E));
+
+        // split the screen at column 10: palette 1 left, 2 on the line, 3 right
+        let mut packet = [0; PACKET_BYTES];
+        packet[0] = (0x06 << 3) | 1;
+        packet[1] = 0b10_01_11;
+        packet[2] = 10;
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.attributes[0], 1);
+        assert_eq!(sgb.attributes[10], 2);
+        assert_eq!(sgb.attributes[19], 3);
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::serial::SerialDevice;
+
+// Link cable between two gbars processes over TCP.
+//
+// Both ends send the greeting, then two byte messages. A side waiting on
+// the external clock sends READY with its SB, and CANCEL if it stops
+// waiting. A side transferring on its internal clock holds the transfer
+// until the other's READY arrives (up to the timeout, to ride out network
+// latency), takes that as the byte shifted in and sends DATA with its own
+// byte, which the waiting side picks up the next time it polls. With no
+// READY in time the transfer reads 0xFF, as if the other Game Boy wasn't
+// listening.
+const GREETING: &[u8; 8] = b"GBARSLK1";
+const READY: u8 = 0x01;
+const DATA: u8 = 0x02;
+const CANCEL: u8 = 0x03;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct TcpLink {
+    stream: TcpStream,
+    // messages from the reader thread, which owns the read half
+    messages: Receiver<[u8; 2]>,
+    // the other side's SB, if it's waiting for us to clock a byte
+    peer_ready: Option<u8>,
+    // bytes the other side clocked into us that we haven't taken yet
+    incoming: VecDeque<u8>,
+    // the SB we last said we were waiting with
+    sent_ready: Option<u8>,
+    // when the transfer we're holding for the other side's READY gives up
+    deadline: Option<Instant>,
+    timeout: Duration,
+    connected: bool,
+}
+
+impl TcpLink {
+    // listen on addr and wait for the other player to connect
+    pub fn host(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Waiting for the link partner on {}", listener.local_addr()?);
+        Self::accept(&listener)
+    }
+
+    pub fn accept(listener: &TcpListener) -> io::Result<Self> {
+        let (stream, peer) = listener.accept()?;
+        info!("Link partner connected from {}", peer);
+        Self::new(stream)
+    }
+
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::new(TcpStream::connect(addr)?)
+    }
+
+    fn new(mut stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        stream.write_all(GREETING)?;
+
+        let mut greeting = [0; GREETING.len()];
+        stream.read_exact(&mut greeting)?;
+        if &greeting != GREETING {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the other end isn't a gbars link",
+            ));
+        }
+
+        let (sender, messages) = mpsc::channel();
+        let mut reader = stream.try_clone()?;
+        thread::spawn(move || {
+            let mut message = [0; 2];
+            while reader.read_exact(&mut message).is_ok() {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            stream,
+            messages,
+            peer_ready: None,
+            incoming: VecDeque::new(),
+            sent_ready: None,
+            deadline: None,
+            timeout: DEFAULT_TIMEOUT,
+            connected: true,
+        })
+    }
+
+    // how long a transfer waits for the other side to be ready
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn send(&mut self, kind: u8, byte: u8) {
+        if self.connected && self.stream.write_all(&[kind, byte]).is_err() {
+            self.disconnected();
+        }
+    }
+
+    fn handle(&mut self, [kind, byte]: [u8; 2]) {
+        match kind {
+            READY => self.peer_ready = Some(byte),
+            CANCEL => self.peer_ready = None,
+            // a byte that comes after we stopped waiting is missed
+            DATA if self.sent_ready.is_some() => self.incoming.push_back(byte),
+            DATA => {}
+            _ => warn!("Unknown link message {:02x}", kind),
+        }
+    }
+
+    // take everything that's arrived so far
+    fn receive(&mut self) {
+        loop {
+            match self.messages.try_recv() {
+                Ok(message) => self.handle(message),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnected();
+                    break;
+                }
+            }
+        }
+    }
+
+    fn disconnected(&mut self) {
+        if self.connected {
+            warn!("Link partner disconnected");
+            self.connected = false;
+        }
+    }
+}
+
+impl Debug for TcpLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TcpLink")
+            .field("peer", &self.stream.peer_addr().ok())
+            .field("connected", &self.connected)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SerialDevice for TcpLink {
+    fn transfer(&mut self, byte: u8) -> Option<u8> {
+        self.receive();
+
+        if let Some(theirs) = self.peer_ready.take() {
+            self.deadline = None;
+            self.send(DATA, byte);
+            return Some(theirs);
+        }
+
+        let deadline = *self
+            .deadline
+            .get_or_insert_with(|| Instant::now() + self.timeout);
+        if !self.connected || Instant::now() >= deadline {
+            self.deadline = None;
+            return Some(0xFF);
+        }
+
+        None
+    }
+
+    fn poll_external(&mut self, byte: u8) -> Option<u8> {
+        self.receive();
+
+        if let Some(incoming) = self.incoming.pop_front() {
+            self.sent_ready = None;
+            return Some(incoming);
+        }
+
+        if self.sent_ready != Some(byte) {
+            self.send(READY, byte);
+            self.sent_ready = Some(byte);
+        }
+
+        None
+    }
+
+    fn cancel_external(&mut self) {
+        if self.sent_ready.take().is_some() {
+            self.send(CANCEL, 0);
+        }
+    }
+
+    fn cancel_transfer(&mut self) {
+        self.deadline = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread, time::Duration};
+
+    use crate::serial::SerialDevice;
+
+    use super::TcpLink;
+
+    // keep asking until the link stops holding the transfer, as Serial
+    // does every bit period
+    fn transfer(link: &mut TcpLink, byte: u8) -> u8 {
+        loop {
+            if let Some(received) = link.transfer(byte) {
+                return received;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_tcp_transfer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || TcpLink::connect(addr).unwrap());
+        let mut host = TcpLink::accept(&listener).unwrap();
+        let mut client = client.join().unwrap();
+
+        // nobody waiting on the other end
+        host.set_timeout(Duration::from_millis(10));
+        assert_eq!(transfer(&mut host, 0x12), 0xFF);
+
+        // the transfer is held rather than blocking, until the client
+        // waits. Then the host gets its byte and the client the host's,
+        // once it arrives.
+        host.set_timeout(Duration::from_secs(5));
+        assert_eq!(host.transfer(0x12), None);
+        assert_eq!(client.poll_external(0x34), None);
+        assert_eq!(transfer(&mut host, 0x12), 0x34);
+
+        let mut received = None;
+        for _ in 0..500 {
+            received = client.poll_external(0x34);
+            if received.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(received, Some(0x12));
+
+        // a wait the client gave up on isn't clocked
+        assert_eq!(client.poll_external(0x56), None);
+        client.cancel_external();
+        thread::sleep(Duration::from_millis(50));
+        host.set_timeout(Duration::from_millis(10));
+        assert_eq!(transfer(&mut host, 0x78), 0xFF);
+
+        drop(host);
+        client.set_timeout(Duration::from_millis(10));
+        assert_eq!(transfer(&mut client, 0x56), 0xFF);
+    }
+}
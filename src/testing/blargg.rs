@@ -0,0 +1,210 @@
+use std::{
+    fmt::Display,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::{gameboy::Gameboy, model::Model, serial::SerialDevice};
+
+use super::{catch_crash, test_gameboy};
+
+// Blargg's test ROMs print their results over the serial port, and some
+// also write them to cartridge RAM: a status byte at 0xA000 (0x80 while
+// running, then 0 for a pass or an error code), the signature DE B0 61
+// and a zero terminated string.
+const STATUS_ADDRESS: u16 = 0xA000;
+const SIGNATURE_ADDRESS: u16 = 0xA001;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEXT_ADDRESS: u16 = 0xA004;
+const STATUS_RUNNING: u8 = 0x80;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlarggResult {
+    Passed,
+    Failed,
+    // neither output said the ROM was done before the frame limit
+    Timeout,
+    // the emulator panicked, with this message
+    Crashed(String),
+    // the ROM isn't there (test ROMs aren't checked in)
+    Skipped,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlarggReport {
+    pub name: String,
+    pub result: BlarggResult,
+    pub frames: usize,
+    // what the ROM printed, from cartridge RAM if it wrote there and
+    // serial otherwise
+    pub output: String,
+}
+
+impl Display for BlarggReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {:?} after {} frames",
+            self.name, self.result, self.frames
+        )?;
+        if !self.output.trim().is_empty() {
+            write!(f, "\n{}", self.output.trim_end())?;
+        }
+
+        Ok(())
+    }
+}
+
+// Run a Blargg test ROM until it says it passed or failed, or for at most
+// max_frames
+pub fn run(rom: &Path, model: Model, max_frames: usize) -> BlarggReport {
+    let name = rom
+        .file_name()
+        .map_or_else(String::new, |n| n.to_string_lossy().into_owned());
+
+    if !rom.exists() {
+        return BlarggReport {
+            name,
+            result: BlarggResult::Skipped,
+            frames: 0,
+            output: String::new(),
+        };
+    }
+
+    let serial = SerialCapture::default();
+    let mut frames = 0;
+    let outcome = catch_crash(|| {
+        let mut gb = test_gameboy(rom, model);
+        gb.set_serial_device(Box::new(serial.clone()));
+
+        while frames < max_frames {
+            frames += 1;
+            let running = gb.run_frame();
+
+            if let Some(done) = check(&gb, &serial) {
+                return done;
+            }
+            if !running {
+                break;
+            }
+        }
+
+        (
+            BlarggResult::Timeout,
+            memory_text(&gb).unwrap_or_else(|| serial.text()),
+        )
+    });
+
+    let (result, output) =
+        outcome.unwrap_or_else(|message| (BlarggResult::Crashed(message), serial.text()));
+
+    BlarggReport {
+        name,
+        result,
+        frames,
+        output,
+    }
+}
+
+// the result and output, once the ROM has finished
+fn check(gb: &Gameboy, serial: &SerialCapture) -> Option<(BlarggResult, String)> {
+    if let Some(text) = memory_text(gb) {
+        return match gb.peek(STATUS_ADDRESS) {
+            STATUS_RUNNING => None,
+            0 => Some((BlarggResult::Passed, text)),
+            _ => Some((BlarggResult::Failed, text)),
+        };
+    }
+
+    let text = serial.text();
+    if text.contains("Passed") {
+        Some((BlarggResult::Passed, text))
+    } else if text.contains("Failed") {
+        Some((BlarggResult::Failed, text))
+    } else {
+        None
+    }
+}
+
+// keeps what the ROM sends over the serial port, shared with the runner
+#[derive(Debug, Default, Clone)]
+struct SerialCapture(Arc<Mutex<Vec<u8>>>);
+
+impl SerialCapture {
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl SerialDevice for SerialCapture {
+    fn transfer(&mut self, byte: u8) -> Option<u8> {
+        self.0.lock().unwrap().push(byte);
+        Some(0xFF)
+    }
+}
+
+// the text in cartridge RAM, if the signature is there
+fn memory_text(gb: &Gameboy) -> Option<String> {
+    let signature = [0, 1, 2].map(|i| gb.peek(SIGNATURE_ADDRESS + i));
+    if signature != SIGNATURE {
+        return None;
+    }
+
+    let text: Vec<u8> = (TEXT_ADDRESS..0xC000)
+        .map(|address| gb.peek(address))
+        .take_while(|&byte| byte != 0)
+        .collect();
+
+    Some(String::from_utf8_lossy(&text).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::{model::Model, testing::write_test_rom};
+
+    use super::{run, BlarggResult};
+
+    // ROM, model and frame limit. The ROMs go in resources/blargg/, but
+    // aren't checked in, so missing ones are skipped with a note.
+    const SUITE: [(&str, Model, usize); 4] = [
+        ("cpu_instrs.gb", Model::Dmg, 4000),
+        ("instr_timing.gb", Model::Dmg, 300),
+        ("mem_timing.gb", Model::Dmg, 300),
+        ("halt_bug.gb", Model::Dmg, 300),
+    ];
+
+    #[test]
+    fn test_blargg_suite() {
+        let mut failures = Vec::new();
+        for (rom, model, max_frames) in SUITE {
+            let report = run(&Path::new("resources/blargg").join(rom), model, max_frames);
+            eprintln!("{report}");
+
+            match report.result {
+                BlarggResult::Passed => {}
+                BlarggResult::Skipped => {
+                    eprintln!("note: {} not found in resources/blargg, skipped", rom)
+                }
+                _ => failures.push(report.name),
+            }
+        }
+
+        assert!(failures.is_empty(), "failed: {}", failures.join(", "));
+    }
+
+    #[test]
+    fn test_crash_report() {
+        // 0xD3 isn't an instruction, which the CPU panics on
+        let rom = write_test_rom("blargg-crash", &[0xD3]);
+        let report = run(&rom, Model::Dmg, 10);
+        fs::remove_file(&rom).unwrap();
+
+        assert_eq!(report.frames, 1);
+        assert!(
+            matches!(&report.result, BlarggResult::Crashed(message) if message.contains("UNKNOWN INSTRUCTION")),
+            "{report}"
+        );
+    }
+}
@@ -0,0 +1,313 @@
+use std::{env, fmt::Display, fs, io, path::PathBuf};
+
+use crate::{image::Image, model::Model, ppu::Rgb};
+
+use super::{run_to_breakpoint, test_gameboy};
+
+// set to anything but 0 to write the current screens as the goldens
+pub const BLESS_VAR: &str = "GBARS_BLESS";
+
+const MISMATCH_COLOUR: Rgb = 0xFF0000;
+
+// A ROM to run and the golden image its screen should match
+pub struct GoldenTest {
+    // the golden is <name>.png
+    pub name: &'static str,
+    pub rom: PathBuf,
+    pub model: Model,
+    // frames to run, or the most to wait for the breakpoint
+    pub frames: usize,
+    // stop at the first LD B,B instead of running all the frames
+    pub until_breakpoint: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum GoldenOutcome {
+    Matched,
+    // the screen was written as the new golden
+    Blessed,
+    // the ROM isn't there (test ROMs aren't checked in)
+    Skipped,
+}
+
+#[derive(Debug)]
+pub enum GoldenError {
+    // the ROM didn't reach its breakpoint in time
+    Timeout,
+    MissingGolden(PathBuf),
+    Mismatch {
+        differing_pixels: usize,
+        diff_path: PathBuf,
+    },
+    BadGolden(String),
+    Io(io::Error),
+}
+
+impl Display for GoldenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GoldenError::Timeout => write!(f, "didn't reach the LD B,B breakpoint in time"),
+            GoldenError::MissingGolden(path) => write!(
+                f,
+                "no golden image at {:?}, run with {}=1 to write it",
+                path, BLESS_VAR
+            ),
+            GoldenError::Mismatch {
+                differing_pixels,
+                diff_path,
+            } => write!(
+                f,
+                "{} pixels differ from the golden, see {:?}",
+                differing_pixels, diff_path
+            ),
+            GoldenError::BadGolden(e) => write!(f, "failed to read the golden image: {e}"),
+            GoldenError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for GoldenError {}
+
+impl From<io::Error> for GoldenError {
+    fn from(e: io::Error) -> Self {
+        GoldenError::Io(e)
+    }
+}
+
+pub struct GoldenRunner {
+    pub golden_dir: PathBuf,
+    // where expected | actual | difference images go on a mismatch
+    pub diff_dir: PathBuf,
+    pub bless: bool,
+}
+
+impl GoldenRunner {
+    // goldens under resources/golden, diffs under target/golden-diffs,
+    // blessing if GBARS_BLESS is set
+    pub fn from_env() -> Self {
+        Self {
+            golden_dir: PathBuf::from("resources/golden"),
+            diff_dir: PathBuf::from("target/golden-diffs"),
+            bless: env::var(BLESS_VAR).is_ok_and(|v| v != "0"),
+        }
+    }
+
+    pub fn run(&self, test: &GoldenTest) -> Result<GoldenOutcome, GoldenError> {
+        if !test.rom.exists() {
+            return Ok(GoldenOutcome::Skipped);
+        }
+
+        let mut gb = test_gameboy(&test.rom, test.model);
+        if test.until_breakpoint {
+            if !run_to_breakpoint(&mut gb, test.frames) {
+                return Err(GoldenError::Timeout);
+            }
+        } else {
+            for _ in 0..test.frames {
+                gb.run_frame();
+            }
+        }
+
+        self.check(test.name, &gb.screenshot())
+    }
+
+    // compare a screen with the golden of that name, or bless it
+    pub fn check(&self, name: &str, actual: &Image) -> Result<GoldenOutcome, GoldenError> {
+        let golden_path = self.golden_dir.join(name).with_extension("png");
+
+        if self.bless {
+            fs::create_dir_all(&self.golden_dir)?;
+            actual.save_png(&golden_path)?;
+            return Ok(GoldenOutcome::Blessed);
+        }
+
+        if !golden_path.exists() {
+            return Err(GoldenError::MissingGolden(golden_path));
+        }
+        let expected = Image::load_png(&golden_path).map_err(GoldenError::BadGolden)?;
+
+        match diff_image(&expected, actual) {
+            None => Ok(GoldenOutcome::Matched),
+            Some((differing_pixels, diff)) => {
+                fs::create_dir_all(&self.diff_dir)?;
+                let diff_path = self.diff_dir.join(name).with_extension("png");
+                diff.save_png(&diff_path)?;
+
+                Err(GoldenError::Mismatch {
+                    differing_pixels,
+                    diff_path,
+                })
+            }
+        }
+    }
+}
+
+// None if the images match, or the number of differing pixels and an
+// image of the expected, actual and differences side by side. The
+// difference shows matching pixels faded and the rest in red.
+pub fn diff_image(expected: &Image, actual: &Image) -> Option<(usize, Image)> {
+    if expected == actual {
+        return None;
+    }
+
+    let width = expected.width.max(actual.width);
+    let height = expected.height.max(actual.height);
+    let pixel = |image: &Image, x: usize, y: usize| {
+        (x < image.width && y < image.height).then(|| image.pixels[y * image.width + x])
+    };
+
+    let mut differing_pixels = 0;
+    let mut pixels = Vec::with_capacity(width * 3 * height);
+    for y in 0..height {
+        let expected_row = (0..width).map(|x| pixel(expected, x, y));
+        let actual_row = (0..width).map(|x| pixel(actual, x, y));
+        let diff_row: Vec<Rgb> = expected_row
+            .clone()
+            .zip(actual_row.clone())
+            .map(|(e, a)| match (e, a) {
+                (Some(e), Some(a)) if e == a => fade(e),
+                _ => {
+                    differing_pixels += 1;
+                    MISMATCH_COLOUR
+                }
+            })
+            .collect();
+
+        pixels.extend(expected_row.map(Option::unwrap_or_default));
+        pixels.extend(actual_row.map(Option::unwrap_or_default));
+        pixels.extend(diff_row);
+    }
+
+    Some((differing_pixels, Image::new(width * 3, height, pixels)))
+}
+
+// grey, lightened towards white
+fn fade(pixel: Rgb) -> Rgb {
+    let [_, r, g, b] = pixel.to_be_bytes();
+    let grey = (r as u32 + g as u32 + b as u32) / 3;
+    let light = 0xC0 + grey / 4;
+
+    light << 16 | light << 8 | light
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use crate::{image::Image, model::Model, testing::write_test_rom};
+
+    use super::{diff_image, GoldenOutcome, GoldenRunner, GoldenTest};
+
+    // the acid2 ROMs go in resources/, but aren't checked in
+    fn rom(name: &str) -> PathBuf {
+        Path::new("resources").join(name)
+    }
+
+    // Turns the LCD off, fills tile 1 with stripes of all four shades,
+    // lays tiles 0 and 1 out as a checkerboard and turns the LCD back on
+    const CHECKERBOARD: &[u8] = &[
+        0xF3, // DI
+        0xAF, // XOR A
+        0xE0, 0x40, // LDH (LCDC),A
+        0x21, 0x10, 0x80, // LD HL,0x8010
+        0x0E, 0x08, // LD C,8
+        0x3E, 0xF0, // LD A,0xF0
+        0x22, // LD (HL+),A
+        0x3E, 0xCC, // LD A,0xCC
+        0x22, // LD (HL+),A
+        0x0D, // DEC C
+        0x20, 0xF7, // JR NZ,-9
+        0x21, 0x00, 0x98, // LD HL,0x9800
+        0x06, 0x04, // LD B,4
+        0x0E, 0x00, // LD C,0
+        0x7D, // LD A,L
+        0xCB, 0x37, // SWAP A
+        0x1F, // RRA
+        0x85, // ADD A,L
+        0xE6, 0x01, // AND 1
+        0x22, // LD (HL+),A
+        0x0D, // DEC C
+        0x20, 0xF5, // JR NZ,-11
+        0x05, // DEC B
+        0x20, 0xF0, // JR NZ,-16
+        0x3E, 0xE4, // LD A,0xE4
+        0xE0, 0x47, // LDH (BGP),A
+        0x3E, 0x91, // LD A,0x91
+        0xE0, 0x40, // LDH (LCDC),A
+        0x18, 0xFE, // JR -2
+    ];
+
+    #[test]
+    fn test_diff_image() {
+        let expected = Image::new(2, 2, vec![0, 0xFFFFFF, 0, 0xFFFFFF]);
+        let mut actual = expected.clone();
+        assert!(diff_image(&expected, &actual).is_none());
+
+        actual.pixels[1] = 0x123456;
+        let (differing, diff) = diff_image(&expected, &actual).unwrap();
+        assert_eq!(differing, 1);
+        assert_eq!((diff.width, diff.height), (6, 2));
+        assert_eq!(
+            &diff.pixels[..6],
+            [0, 0xFFFFFF, 0, 0x123456, 0xC0C0C0, 0xFF0000]
+        );
+    }
+
+    #[test]
+    fn test_checkerboard_golden() {
+        let test = GoldenTest {
+            name: "checkerboard",
+            rom: write_test_rom("golden-checkerboard", CHECKERBOARD),
+            model: Model::Dmg,
+            frames: 10,
+            until_breakpoint: false,
+        };
+
+        let outcome = GoldenRunner::from_env().run(&test);
+        fs::remove_file(&test.rom).unwrap();
+        assert!(
+            matches!(outcome, Ok(GoldenOutcome::Matched | GoldenOutcome::Blessed)),
+            "{:?}",
+            outcome
+        );
+    }
+
+    // the acid2 ROMs aren't checked in, so missing ones are skipped with a
+    // note
+    #[test]
+    fn test_acid2_goldens() {
+        let suite = [
+            GoldenTest {
+                name: "dmg-acid2",
+                rom: rom("dmg-acid2.gb"),
+                model: Model::Dmg,
+                frames: 600,
+                until_breakpoint: true,
+            },
+            GoldenTest {
+                name: "cgb-acid2",
+                rom: rom("cgb-acid2.gbc"),
+                model: Model::Cgb,
+                frames: 600,
+                until_breakpoint: true,
+            },
+        ];
+
+        let runner = GoldenRunner::from_env();
+        let mut failures = Vec::new();
+        for test in &suite {
+            match runner.run(test) {
+                Ok(GoldenOutcome::Skipped) => {
+                    eprintln!("note: {:?} not found, skipped {}", test.rom, test.name)
+                }
+                Ok(outcome) => eprintln!("{}: {:?}", test.name, outcome),
+                Err(e) => failures.push(format!("{}: {}", test.name, e)),
+            }
+        }
+
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+}
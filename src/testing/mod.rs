@@ -0,0 +1,87 @@
+use std::{
+    any::Any,
+    env, fs,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    gameboy::{Gameboy, GbOptions, Speed},
+    model::Model,
+};
+
+pub mod blargg;
+pub mod golden;
+pub mod mooneye;
+
+// Running test ROMs headless and checking what they did: golden image
+// comparisons and runners for the common test ROM suites
+
+// options for running a test ROM headless, flat out and without a boot
+// ROM
+pub fn test_options(model: Model) -> GbOptions {
+    GbOptions {
+        speed: Speed::Uncapped,
+        render: false,
+        skip_boot: true,
+        model,
+        ..Default::default()
+    }
+}
+
+// a Gameboy for running a test ROM with test_options
+pub fn test_gameboy(rom: &Path, model: Model) -> Gameboy {
+    Gameboy::new(false, Some(rom), Some(test_options(model)))
+}
+
+// Write a 32 KiB cartridge with no mapper that jumps to code at 0x0150
+// to a temporary file, for tests that need a small program of their own
+pub fn write_test_rom(name: &str, code: &[u8]) -> PathBuf {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x150..0x150 + code.len()].copy_from_slice(code);
+
+    let path = env::temp_dir().join(format!("gbars-{}-{}.gb", name, std::process::id()));
+    fs::write(&path, rom).unwrap_or_else(|e| panic!("Failed to write {:?}: {}", path, e));
+
+    path
+}
+
+// Run f, turning a panic into its message, so that one ROM the emulator
+// can't handle yet doesn't take a whole suite down with it
+pub fn catch_crash<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(panic_message)
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload.downcast_ref::<&str>().map_or_else(
+            || "unknown panic".to_string(),
+            |message| message.to_string(),
+        ),
+    }
+}
+
+// Run whole frames until the ROM runs LD B,B, which many test ROMs do to
+// say they've finished, for at most max_frames. Returns whether it got
+// there. Uses the breakpoint hook.
+pub fn run_to_breakpoint(gb: &mut Gameboy, max_frames: usize) -> bool {
+    let hit = Arc::new(AtomicBool::new(false));
+    let hook_hit = hit.clone();
+    gb.hooks_mut().on_breakpoint(move |_| {
+        hook_hit.store(true, Ordering::Relaxed);
+    });
+
+    for _ in 0..max_frames {
+        if !gb.run_frame() || hit.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+
+    hit.load(Ordering::Relaxed)
+}
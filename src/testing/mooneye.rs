@@ -0,0 +1,218 @@
+use std::{
+    fmt::Write,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::model::Model;
+
+use super::{catch_crash, run_to_breakpoint, test_gameboy};
+
+// Mooneye test ROMs finish with LD B,B, having loaded B, C, D, E, H and L
+// with the Fibonacci numbers below if they passed (and 0x42 each if they
+// failed). They then loop in place, so the registers can be read at the
+// end of the frame.
+const PASS_REGISTERS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+// the tests take a few seconds at most
+const MAX_FRAMES: usize = 60 * 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MooneyeResult {
+    Passed,
+    // with B, C, D, E, H and L at the breakpoint
+    Failed([u8; 6]),
+    // never reached the breakpoint
+    Timeout,
+    // the emulator panicked, with this message
+    Crashed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct MooneyeReport {
+    // path under the suite directory
+    pub name: String,
+    pub model: Model,
+    pub result: MooneyeResult,
+}
+
+// The model a ROM is meant for, from the hardware its name says it
+// passes on: -dmg0, -mgb, -sgb, -sgb2, -cgb... or the letters G (DMG), S
+// (SGB) and C (CGB). DMG when it doesn't say.
+pub fn model_for(name: &str) -> Model {
+    let stem = name.trim_end_matches(".gbc").trim_end_matches(".gb");
+    let Some((_, hardware)) = stem.rsplit_once('-') else {
+        return Model::Dmg;
+    };
+
+    if hardware.starts_with("dmg0") {
+        Model::Dmg0
+    } else if hardware.starts_with("dmg") || hardware.contains('G') {
+        Model::Dmg
+    } else if hardware.starts_with("mgb") {
+        Model::Mgb
+    } else if hardware.starts_with("sgb2") {
+        Model::Sgb2
+    } else if hardware.starts_with("sgb") || hardware.contains('S') {
+        Model::Sgb
+    } else if hardware.starts_with("cgb") || hardware.contains('C') {
+        Model::Cgb
+    } else {
+        Model::Dmg
+    }
+}
+
+pub fn run(rom: &Path, model: Model) -> MooneyeResult {
+    catch_crash(|| {
+        let mut gb = test_gameboy(rom, model);
+        if !run_to_breakpoint(&mut gb, MAX_FRAMES) {
+            return MooneyeResult::Timeout;
+        }
+
+        let r = gb.cpu().registers();
+        let registers = [r.b, r.c, r.d, r.e, r.h, r.l];
+        if registers == PASS_REGISTERS {
+            MooneyeResult::Passed
+        } else {
+            MooneyeResult::Failed(registers)
+        }
+    })
+    .unwrap_or_else(MooneyeResult::Crashed)
+}
+
+// Run every .gb and .gbc file under dir, in name order, each on the model
+// its name asks for
+pub fn run_dir(dir: &Path) -> io::Result<Vec<MooneyeReport>> {
+    let mut roms = Vec::new();
+    find_roms(dir, &mut roms)?;
+    roms.sort();
+
+    Ok(roms
+        .into_iter()
+        .map(|rom| {
+            let name = rom
+                .strip_prefix(dir)
+                .unwrap_or(&rom)
+                .to_string_lossy()
+                .into_owned();
+            let model = model_for(&name);
+
+            MooneyeReport {
+                result: run(&rom, model),
+                name,
+                model,
+            }
+        })
+        .collect())
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_roms(&path, roms)?;
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension == "gb" || extension == "gbc")
+        {
+            roms.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+// A table of results, one ROM a line, with the pass rate at the bottom
+pub fn summary_table(reports: &[MooneyeReport]) -> String {
+    let width = reports
+        .iter()
+        .map(|report| report.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("ROM".len());
+    let mut table = String::new();
+
+    writeln!(table, "{:width$}  {:5}  Result", "ROM", "Model").unwrap();
+    for report in reports {
+        let result = match &report.result {
+            MooneyeResult::Passed => "pass".to_string(),
+            MooneyeResult::Failed(registers) => format!("FAIL {:02x?}", registers),
+            MooneyeResult::Timeout => "TIMEOUT".to_string(),
+            // the first line is enough to tell crashes apart
+            MooneyeResult::Crashed(message) => format!(
+                "CRASH {}",
+                message.trim().lines().next().unwrap_or_default()
+            ),
+        };
+        writeln!(
+            table,
+            "{:width$}  {:5}  {}",
+            report.name,
+            report.model.to_string(),
+            result
+        )
+        .unwrap();
+    }
+
+    let passed = reports
+        .iter()
+        .filter(|report| report.result == MooneyeResult::Passed)
+        .count();
+    let percentage = if reports.is_empty() {
+        0.0
+    } else {
+        passed as f32 * 100.0 / reports.len() as f32
+    };
+    writeln!(
+        table,
+        "\n{passed}/{} passed ({percentage:.1}%)",
+        reports.len()
+    )
+    .unwrap();
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::Model;
+
+    use super::{model_for, summary_table, MooneyeReport, MooneyeResult};
+
+    #[test]
+    fn test_model_and_summary() {
+        assert_eq!(model_for("acceptance/boot_regs-dmg0.gb"), Model::Dmg0);
+        assert_eq!(model_for("acceptance/boot_hwio-dmgABCmgb.gb"), Model::Dmg);
+        assert_eq!(model_for("acceptance/boot_regs-sgb2.gb"), Model::Sgb2);
+        assert_eq!(model_for("acceptance/boot_hwio-S.gb"), Model::Sgb);
+        assert_eq!(model_for("acceptance/boot_div-cgbABCDE.gb"), Model::Cgb);
+        assert_eq!(model_for("acceptance/di_timing-GS.gb"), Model::Dmg);
+        assert_eq!(model_for("acceptance/ei_sequence.gb"), Model::Dmg);
+
+        let reports = [
+            MooneyeReport {
+                name: "a.gb".to_string(),
+                model: Model::Dmg,
+                result: MooneyeResult::Passed,
+            },
+            MooneyeReport {
+                name: "longer_name.gb".to_string(),
+                model: Model::Cgb,
+                result: MooneyeResult::Failed([0x42; 6]),
+            },
+            MooneyeReport {
+                name: "c.gb".to_string(),
+                model: Model::Dmg,
+                result: MooneyeResult::Crashed("\n\nUNKNOWN INSTRUCTION\nat 0x0150".to_string()),
+            },
+        ];
+        assert_eq!(
+            summary_table(&reports),
+            "ROM             Model  Result\n\
+             a.gb            dmg    pass\n\
+             longer_name.gb  cgb    FAIL [42, 42, 42, 42, 42, 42]\n\
+             c.gb            dmg    CRASH UNKNOWN INSTRUCTION\n\
+             \n\
+             1/3 passed (33.3%)\n"
+        );
+    }
+}
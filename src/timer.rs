@@ -0,0 +1,126 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// DIV/TIMA timer. DIV is the upper byte of a 16 bit counter that
+// increments every CPU clock, and TIMA increments on the falling edge of
+// the counter bit selected by TAC. Because the timer is driven by the CPU
+// clock, it runs twice as fast relative to the PPU in CGB double speed mode.
+#[derive(Debug, Default)]
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // advance the timer by a single CPU clock. Returns true if TIMA
+    // overflowed and the timer interrupt should be requested
+    pub fn tick(&mut self) -> bool {
+        let old_counter = self.counter;
+        self.counter = self.counter.wrapping_add(1);
+
+        self.falling_edge(old_counter) && self.increment_tima()
+    }
+
+    pub fn read_div(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+
+    // any write to DIV resets the whole internal counter, which can itself
+    // cause a falling edge on the selected bit
+    pub fn write_div(&mut self) -> bool {
+        let old_counter = self.counter;
+        self.counter = 0;
+
+        self.falling_edge(old_counter) && self.increment_tima()
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.counter);
+        w.write_u8(self.tima);
+        w.write_u8(self.tma);
+        w.write_u8(self.tac);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.counter = r.read_u16()?;
+        self.tima = r.read_u8()?;
+        self.tma = r.read_u8()?;
+        self.tac = r.read_u8()?;
+
+        Ok(())
+    }
+
+    pub fn set_counter(&mut self, counter: u16) {
+        self.counter = counter;
+    }
+
+    pub fn read_tima(&self) -> u8 {
+        self.tima
+    }
+
+    pub fn write_tima(&mut self, val: u8) {
+        self.tima = val;
+    }
+
+    pub fn read_tma(&self) -> u8 {
+        self.tma
+    }
+
+    pub fn write_tma(&mut self, val: u8) {
+        self.tma = val;
+    }
+
+    pub fn read_tac(&self) -> u8 {
+        self.tac | 0xF8
+    }
+
+    pub fn write_tac(&mut self, val: u8) {
+        self.tac = val & 0b111;
+    }
+
+    fn selected_bit(&self, counter: u16) -> bool {
+        let bit = match self.tac & 0b11 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+
+        self.tac & 0b100 > 0 && (counter >> bit) & 1 == 1
+    }
+
+    fn falling_edge(&self, old_counter: u16) -> bool {
+        self.selected_bit(old_counter) && !self.selected_bit(self.counter)
+    }
+
+    fn increment_tima(&mut self) -> bool {
+        let (new_tima, overflow) = self.tima.overflowing_add(1);
+
+        self.tima = if overflow { self.tma } else { new_tima };
+
+        overflow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timer;
+
+    #[test]
+    fn test_tima_overflow_reloads_tma() {
+        let mut timer = Timer::new();
+        timer.write_tac(0b101); // enabled, every 16 clocks
+        timer.write_tma(0x42);
+        timer.write_tima(0xFF);
+
+        let interrupts = (0..16).filter(|_| timer.tick()).count();
+
+        assert_eq!(interrupts, 1);
+        assert_eq!(timer.read_tima(), 0x42);
+    }
+}